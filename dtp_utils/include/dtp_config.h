@@ -26,8 +26,4 @@ struct dtp_config {
 
 typedef struct dtp_config dtp_config;
 
-// return: config array, you have to release it 
-// number: return the number of parsed dtp_config (MAX=10).
-struct dtp_config* parse_dtp_config(const char *filename, int *number);
-
 #endif // CONFIG_PD_H
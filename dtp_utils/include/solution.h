@@ -0,0 +1,55 @@
+/// The interface of the scheduler plugins loaded by the server with
+/// `--scheduler plugin --plugin <path to the shared object>`
+///
+/// It follows the AItrans solution format: the plugin exports
+/// `SolutionSelectBlock`, and optionally `SolutionInit` and `SolutionCcTrigger`.
+/// The times given to the plugin are those of the scheduler, the virtual time
+/// of the simulation included.
+/// Every connection of the server uses the same loaded library, so the
+/// static variables of the plugin are shared by all of them.
+/// Build it with `cc -shared -fPIC -I dtp_utils/include solution.c -o libsolution.so`
+/// All the times are in microseconds.
+
+#ifndef SOLUTION_H
+#define SOLUTION_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/// The same layout as `BlockInfo` in the server
+struct BlockInfo {
+    size_t id;
+    size_t size;       // bytes
+    size_t priority;   // 1, 2, 3, higher the number is, higher the priority is
+    size_t deadline;   // ms
+};
+
+struct SolutionBlock {
+    struct BlockInfo info;
+    uint64_t create_time;
+    uint64_t remaining_size; // bytes not sent yet
+};
+
+struct SolutionCcInfo {
+    uint64_t event_time;
+    uint64_t rtt;
+    uint64_t bandwidth;        // estimated delivery rate, bytes per second
+    uint64_t congestion_window; // bytes
+};
+
+/// Called every time a scheduler loads the plugin: once for every connection
+/// of the server, and once more when the server checks its options at startup.
+/// The congestion window and pacing rate are only informational, quiche
+/// keeps running its own congestion control.
+void SolutionInit(uint64_t *init_congestion_window, uint64_t *init_pacing_rate);
+
+/// Return the index in `blocks` of the block to send now.
+/// `blocks` lists the pending blocks in generation order.
+uint64_t SolutionSelectBlock(const struct SolutionBlock *blocks, uint64_t block_num, uint64_t current_time);
+
+/// Called with the new network estimates of the connection before the next
+/// `SolutionSelectBlock`, `event_time` is its `current_time`.
+/// The outputs are only informational, like in `SolutionInit`.
+void SolutionCcTrigger(const struct SolutionCcInfo *cc_info, uint64_t *congestion_window, uint64_t *pacing_rate);
+
+#endif // SOLUTION_H
@@ -1,4 +1,3 @@
-use cc;
 fn main() {
     let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     println!("manifest_dir: {}", manifest_dir);
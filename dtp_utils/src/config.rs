@@ -0,0 +1,204 @@
+use std::{fmt, fs, path::Path};
+
+use crate::dtp_config;
+
+/// Priorities accepted in a trace, higher the number is, higher the priority is
+pub const PRIORITIES: std::ops::RangeInclusive<i32> = 1..=3;
+
+/// The reasons a trace file can be rejected
+#[derive(Debug)]
+pub enum ConfigError {
+  /// The file could not be read
+  Io {
+    path: String,
+    source: std::io::Error,
+  },
+  /// A line could not be parsed or holds an invalid value
+  Parse {
+    line: usize,
+    column: usize,
+    msg: String,
+  },
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigError::Io { path, source } => write!(f, "failed to read {}: {}", path, source),
+      ConfigError::Parse { line, column, msg } => write!(f, "{}:{}: {}", line, column, msg),
+    }
+  }
+}
+
+impl std::error::Error for ConfigError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ConfigError::Io { source, .. } => Some(source),
+      ConfigError::Parse { .. } => None,
+    }
+  }
+}
+
+/// Read and parse a trace file, see `parse_dtp_config_str` for the format
+pub fn parse_dtp_config<P: AsRef<Path>>(path: P) -> Result<Vec<dtp_config>, ConfigError> {
+  let path = path.as_ref();
+  let content = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+    path: path.display().to_string(),
+    source,
+  })?;
+  parse_dtp_config_str(&content)
+}
+
+/// Parse a trace, one block per line:
+///
+/// `send_time_gap (s) deadline (ms) block_size (B) priority`
+///
+/// Fields are separated by whitespace. Everything after a `#` is a comment,
+/// blank lines are skipped.
+pub fn parse_dtp_config_str(content: &str) -> Result<Vec<dtp_config>, ConfigError> {
+  let mut cfgs = Vec::new();
+  for (index, raw_line) in content.lines().enumerate() {
+    let line = index + 1;
+    let code = match raw_line.find('#') {
+      Some(pos) => &raw_line[..pos],
+      None => raw_line,
+    };
+    let fields = split_fields(code);
+    if fields.is_empty() {
+      continue;
+    }
+    let err = |column: usize, msg: String| ConfigError::Parse { line, column, msg };
+    let end_column = code.trim_end().chars().count() + 1;
+    if fields.len() < 4 {
+      return Err(err(end_column, format!("expected 4 fields, found {}", fields.len())));
+    }
+    if let Some(&(column, extra)) = fields.get(4) {
+      return Err(err(column, format!("unexpected field '{}'", extra)));
+    }
+
+    let (column, token) = fields[0];
+    let send_time_gap: f32 = token
+      .parse()
+      .map_err(|_| err(column, format!("invalid send time gap '{}'", token)))?;
+    if !send_time_gap.is_finite() || send_time_gap < 0.0 {
+      return Err(err(column, format!("send time gap must be non-negative, found {}", token)));
+    }
+
+    let (column, token) = fields[1];
+    let deadline: i32 = token
+      .parse()
+      .map_err(|_| err(column, format!("invalid deadline '{}'", token)))?;
+    if deadline <= 0 {
+      return Err(err(column, format!("deadline must be positive, found {}", deadline)));
+    }
+
+    let (column, token) = fields[2];
+    let block_size: i32 = token
+      .parse()
+      .map_err(|_| err(column, format!("invalid block size '{}'", token)))?;
+    if block_size <= 0 {
+      return Err(err(column, format!("block size must be positive, found {}", block_size)));
+    }
+
+    let (column, token) = fields[3];
+    let priority: i32 = token
+      .parse()
+      .map_err(|_| err(column, format!("invalid priority '{}'", token)))?;
+    if !PRIORITIES.contains(&priority) {
+      return Err(err(
+        column,
+        format!("unknown priority {}, expected {} to {}", priority, PRIORITIES.start(), PRIORITIES.end()),
+      ));
+    }
+
+    cfgs.push(dtp_config {
+      deadline,
+      priority,
+      block_size,
+      send_time_gap,
+    });
+  }
+  Ok(cfgs)
+}
+
+/// Split a line into whitespace separated fields with their 1-based column
+fn split_fields(line: &str) -> Vec<(usize, &str)> {
+  let mut fields = Vec::new();
+  let mut start = None;
+  let mut column = 0;
+  for (col, (pos, c)) in line.char_indices().enumerate() {
+    match (c.is_whitespace(), start) {
+      (false, None) => {
+        start = Some(pos);
+        column = col + 1;
+      }
+      (true, Some(begin)) => {
+        fields.push((column, &line[begin..pos]));
+        start = None;
+      }
+      _ => {}
+    }
+  }
+  if let Some(begin) = start {
+    fields.push((column, &line[begin..]));
+  }
+  fields
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse_err(content: &str) -> (usize, usize, String) {
+    match parse_dtp_config_str(content) {
+      Err(ConfigError::Parse { line, column, msg }) => (line, column, msg),
+      other => panic!("expected a parse error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn comments_and_blank_lines() {
+    let cfgs = parse_dtp_config_str(
+      "# gap deadline size priority\n\n0.5   200 1235 1\n  0.0 100 20 3 # burst\n",
+    )
+    .unwrap();
+    assert_eq!(cfgs.len(), 2);
+    assert_eq!(cfgs[0].send_time_gap, 0.5);
+    assert_eq!(cfgs[0].deadline, 200);
+    assert_eq!(cfgs[0].block_size, 1235);
+    assert_eq!(cfgs[0].priority, 1);
+    assert_eq!(cfgs[1].priority, 3);
+  }
+
+  #[test]
+  fn error_positions() {
+    assert_eq!(parse_err("0.1 200 10 1\n0.1 2x0 10 1\n").0, 2);
+    assert_eq!(parse_err("0.1 2x0 10 1\n").1, 5);
+    assert_eq!(parse_err("0.1 200 10\n").1, 11);
+    assert_eq!(parse_err("0.1 200 10 1 7\n").1, 14);
+    assert_eq!(parse_err("-0.1 200 10 1\n").1, 1);
+    assert_eq!(parse_err("0.1 200 0 1\n").1, 9);
+    assert_eq!(parse_err("0.1 200 10 4\n").1, 12);
+    assert_eq!(parse_err("0.1 -5 10 1\n").1, 5);
+  }
+
+  #[test]
+  fn no_entry_cap() {
+    let content = "0.001 200 10 2\n".repeat(20000);
+    assert_eq!(parse_dtp_config_str(&content).unwrap().len(), 20000);
+  }
+
+  #[test]
+  fn missing_file() {
+    assert!(matches!(
+      parse_dtp_config("/nonexistent/aitrans_block.txt"),
+      Err(ConfigError::Io { .. })
+    ));
+  }
+
+  #[test]
+  fn bundled_trace() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../aitrans_block.txt");
+    assert_eq!(parse_dtp_config(path).unwrap().len(), 1063);
+  }
+}
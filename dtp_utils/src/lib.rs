@@ -1,6 +1,6 @@
-use std::{ffi::c_void, slice, ffi::CString};
+mod config;
 
-use libc::{free};
+pub use config::{ConfigError, PRIORITIES, parse_dtp_config, parse_dtp_config_str};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -13,30 +13,14 @@ pub struct dtp_config {
 
 extern "C" {
   fn getCurrentUsec() -> u64;
-  fn parse_dtp_config(filename: *const libc::c_char, number: *mut i32) -> * const dtp_config;
 }
 
 #[allow(dead_code)]
 /// Get a vector of dtp_configs
 /// 
-/// The function is memory safe
-pub fn get_dtp_config(filename: &str) -> Vec<dtp_config> {
-  unsafe {
-    // get dtp_config raw pointer
-    let mut number = 0;
-    let filename_to_c = CString::new(filename).unwrap();
-    let cfgs_ptr: * const dtp_config = parse_dtp_config(filename_to_c.as_ptr(), &mut number);
-    if !cfgs_ptr.is_null(){
-      // copy and save them in Rust vector
-      let cfgs_slice = slice::from_raw_parts(cfgs_ptr, number as usize);
-      let cfgs_vec = cfgs_slice.to_vec();
-      // free the raw pointer
-      free(cfgs_ptr as *mut c_void);
-      return cfgs_vec;
-    } else {
-      return Vec::new();
-    }
-  }
+/// Return an error with the line and column of the first invalid entry
+pub fn get_dtp_config(filename: &str) -> Result<Vec<dtp_config>, ConfigError> {
+  parse_dtp_config(filename)
 }
 
 #[allow(dead_code)]
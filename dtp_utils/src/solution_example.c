@@ -0,0 +1,32 @@
+// An example scheduler plugin: send the block of the highest priority,
+// the one with the earliest deadline among them.
+#include "../include/solution.h"
+
+void SolutionInit(uint64_t *init_congestion_window, uint64_t *init_pacing_rate)
+{
+    *init_congestion_window = 10 * 1350;
+    *init_pacing_rate = 0;
+}
+
+uint64_t SolutionSelectBlock(const struct SolutionBlock *blocks, uint64_t block_num, uint64_t current_time)
+{
+    uint64_t best = 0;
+    for (uint64_t i = 1; i < block_num; i++) {
+        const struct SolutionBlock *b = &blocks[i];
+        const struct SolutionBlock *cur = &blocks[best];
+        uint64_t b_deadline = b->create_time + b->info.deadline * 1000;
+        uint64_t cur_deadline = cur->create_time + cur->info.deadline * 1000;
+        if (b->info.priority > cur->info.priority ||
+            (b->info.priority == cur->info.priority && b_deadline < cur_deadline)) {
+            best = i;
+        }
+    }
+    (void)current_time;
+    return best;
+}
+
+void SolutionCcTrigger(const struct SolutionCcInfo *cc_info, uint64_t *congestion_window, uint64_t *pacing_rate)
+{
+    *congestion_window = cc_info->congestion_window;
+    *pacing_rate = cc_info->bandwidth;
+}
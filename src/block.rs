@@ -4,5 +4,91 @@ pub struct BlockInfo {
     pub id:       usize,
     pub size:     usize,
     pub priority: usize,
-    pub deadline: usize 
-}
\ No newline at end of file
+    pub deadline: usize
+}
+
+/// Every block is sent on its own server-initiated unidirectional stream,
+/// so the stream id is derived from the block id: 3, 7, 11, ...
+pub fn block_id_to_stream_id(id: usize) -> u64 {
+    (id as u64) * 4 + 3
+}
+
+/// The reverse of `block_id_to_stream_id`.
+/// Return None if the stream is not a server-initiated unidirectional stream.
+pub fn stream_id_to_block_id(stream_id: u64) -> Option<usize> {
+    if stream_id % 4 != 3 {
+        return None;
+    }
+    Some((stream_id / 4) as usize)
+}
+
+/// The server-initiated bidirectional stream carrying control messages as
+/// `StreamFrame`s.
+///
+/// It starts with a `DtpConfig` frame announcing the number of blocks of the
+/// trace followed by the `BlockInfo` of every block (with a zero create
+/// time), then a `BlockDrop` frame for every block dropped by the scheduler.
+pub const CONTROL_STREAM_ID: u64 = 1;
+
+/// The client-initiated unidirectional stream carrying a `BlockAck` frame
+/// for every block the client received completely.
+pub const ACK_STREAM_ID: u64 = 2;
+
+/// The server-initiated bidirectional stream carrying all the blocks as
+/// `StreamFrame`s in the framed mode, see `sender::FramedSender`.
+pub const FRAMED_STREAM_ID: u64 = 5;
+
+/// The application error code of the RESET_STREAM of a block abandoned
+/// because its deadline passed before it was delivered.
+pub const BLOCK_EXPIRED_ERROR: u64 = 0x1;
+
+pub const BLOCK_HEADER_LEN: usize = 40;
+
+/// The header written at the beginning of every block stream
+/// so that the receiver knows what to expect
+///
+/// | id | size | priority | deadline (ms) | create_time (us) |
+///
+/// Every field is a big endian u64.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockHeader {
+    pub info: BlockInfo,
+    /// the time the block was generated, in microseconds since the epoch
+    pub create_time: u64,
+}
+
+impl BlockHeader {
+    pub fn to_bytes(self) -> [u8; BLOCK_HEADER_LEN] {
+        let mut buf = [0u8; BLOCK_HEADER_LEN];
+        let fields = [
+            self.info.id as u64,
+            self.info.size as u64,
+            self.info.priority as u64,
+            self.info.deadline as u64,
+            self.create_time,
+        ];
+        for (chunk, field) in buf.chunks_exact_mut(8).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+        buf
+    }
+    /// Return None if the buffer is shorter than `BLOCK_HEADER_LEN`
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < BLOCK_HEADER_LEN {
+            return None;
+        }
+        let mut fields = buf[..BLOCK_HEADER_LEN]
+            .chunks_exact(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+        Some(BlockHeader {
+            info: BlockInfo {
+                id: next() as usize,
+                size: next() as usize,
+                priority: next() as usize,
+                deadline: next() as usize,
+            },
+            create_time: next(),
+        })
+    }
+}
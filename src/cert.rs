@@ -0,0 +1,110 @@
+//! Generate a self-signed certificate for the server.
+//!
+//! The certificate and its ECDSA P-256 key are built with rcgen. The client
+//! trusts the server by giving the certificate itself as its CA file.
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+
+use crate::error::{Error, Result};
+
+/// A certificate and its private key, in PEM
+pub struct SelfSigned {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+/// A certificate for the DNS names and IP addresses of `names`, the first
+/// one being the common name, valid for `days` days from now
+pub fn generate_self_signed(names: &[String], days: u32) -> Result<SelfSigned> {
+    let common_name = names.first()
+        .ok_or_else(|| Error::Config("the certificate needs at least one name".to_owned()))?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, common_name.as_str());
+    params.subject_alt_names = names
+        .iter()
+        .flat_map(|name| {
+            let dns = SanType::DnsName(name.clone());
+            match name.parse::<IpAddr>() {
+                // also as a DNS name, for the clients checking the server
+                // name they are given as a host name
+                Ok(ip) => vec![dns, SanType::IpAddress(ip)],
+                Err(_) => vec![dns],
+            }
+        })
+        .collect();
+    let now = SystemTime::now();
+    // leave room for the clocks of the peers being a bit off
+    params.not_before = (now - Duration::from_secs(3600)).into();
+    params.not_after = (now + Duration::from_secs(days as u64 * 86400)).into();
+
+    let rcgen_error = |e: rcgen::RcgenError| Error::Config(format!("failed to generate the certificate: {}", e));
+    let cert = Certificate::from_params(params).map_err(rcgen_error)?;
+    Ok(SelfSigned {
+        cert_pem: cert.serialize_pem().map_err(rcgen_error)?,
+        key_pem: cert.serialize_private_key_pem(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use crate::quic::{load_certificate, load_verify_locations, set_quiche_conn_config, QuicOptions};
+    use super::*;
+
+    /// Run a handshake with the generated pair, the client trusting `ca`
+    fn handshake(pair: &SelfSigned, ca: &SelfSigned, server_name: &str) -> bool {
+        let dir = std::env::temp_dir().join(format!("dtp-cert-{}-{}", std::process::id(), server_name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        std::fs::write(path("cert.crt"), &pair.cert_pem).unwrap();
+        std::fs::write(path("cert.key"), &pair.key_pem).unwrap();
+        std::fs::write(path("ca.crt"), &ca.cert_pem).unwrap();
+
+        let server_options = QuicOptions { cert_path: path("cert.crt"), key_path: path("cert.key"), ..Default::default() };
+        let mut server_config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        set_quiche_conn_config(&mut server_config, &server_options).unwrap();
+        load_certificate(&mut server_config, &server_options).unwrap();
+        let client_options = QuicOptions { ca_path: Some(path("ca.crt")), ..Default::default() };
+        let mut client_config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        set_quiche_conn_config(&mut client_config, &client_options).unwrap();
+        load_verify_locations(&mut client_config, &client_options).unwrap();
+
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 1234));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 4321));
+        let scid = quiche::ConnectionId::from_ref(&[0xc1; quiche::MAX_CONN_ID_LEN]);
+        let mut client = quiche::connect(Some(server_name), &scid, client_addr, server_addr, &mut client_config).unwrap();
+        let scid = quiche::ConnectionId::from_ref(&[0x5e; quiche::MAX_CONN_ID_LEN]);
+        let mut server = quiche::accept(&scid, None, server_addr, client_addr, &mut server_config).unwrap();
+        // a failed handshake closes the connection instead of failing recv
+        let deliver = |from: &mut quiche::Connection, to: &mut quiche::Connection| {
+            let mut buf = [0; 1500];
+            while let Ok((len, send_info)) = from.send(&mut buf) {
+                let recv_info = quiche::RecvInfo { from: send_info.from, to: send_info.to };
+                to.recv(&mut buf[..len], recv_info).ok();
+            }
+        };
+        for _ in 0..10 {
+            deliver(&mut client, &mut server);
+            deliver(&mut server, &mut client);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        client.is_established() && server.is_established()
+    }
+
+    #[test]
+    fn self_signed_handshake() {
+        let names = vec!["localhost".to_owned(), "127.0.0.1".to_owned()];
+        let pair = generate_self_signed(&names, 30).unwrap();
+        assert!(pair.cert_pem.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(handshake(&pair, &pair, "localhost"));
+        assert!(handshake(&pair, &pair, "127.0.0.1"));
+        // a name the certificate does not carry
+        assert!(!handshake(&pair, &pair, "example.com"));
+        // the client does not trust another certificate
+        let other = generate_self_signed(&names, 30).unwrap();
+        assert!(!handshake(&pair, &other, "localhost"));
+        assert!(generate_self_signed(&[], 30).is_err());
+    }
+}
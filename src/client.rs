@@ -0,0 +1,587 @@
+//! The QUIC client receiving the blocks, see `DtpClient`
+use std::cell::{Ref, RefCell};
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::time::Duration;
+use calloop::{generic::Generic, timer::{Timer, TimeoutAction}, Dispatcher, Interest, LoopHandle, Mode, PostAction};
+use dtp_utils::get_current_usec;
+use quiche::ConnectionId;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::block::ACK_STREAM_ID;
+use crate::error::{would_block, Error, Result};
+use crate::events::{BlockEvent, ClientEvents, Event};
+use crate::frame::StreamFrame;
+use crate::quic::{close_connection, hex_dump, load_verify_locations, set_quiche_conn_config, set_recv_buffer, DispatcherTimer, LoopTimer, QuicOptions, IDLE_TIMEOUT, MAX_DATAGRAM_SIZE};
+use crate::receiver::BlockReceiver;
+
+
+struct ClientState<'l> {
+    scid: ConnectionId<'static>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    // checked against the certificate of the server
+    server_name: String,
+    config: quiche::Config,
+
+    receiver: BlockReceiver,
+    // waiting for the borrow of the state to end, see `fire_events`
+    events: Vec<Event>,
+    callbacks: Rc<RefCell<ClientEvents<'l>>>,
+    // the acknowledgements waiting for stream capacity
+    ack_buf: Vec<u8>,
+    closed: bool,
+    // the failure that closed the connection
+    error: Option<Error>,
+
+    conn: Option<quiche::Connection>,
+    socket: UdpSocket,
+
+    // set right after the state is shared with the event sources
+    timer: Option<Box<dyn LoopTimer + 'l>>,
+}
+
+/// A QUIC client receiving blocks from a `DtpServer`.
+///
+/// The client runs in the calloop event loop of the application, the
+/// sources it inserts stop once the `DtpClient` is dropped. It closes the
+/// connection once every block announced by the server is accounted for.
+pub struct DtpClient<'l> {
+    state: Rc<RefCell<ClientState<'l>>>,
+}
+
+impl<'l> DtpClient<'l> {
+    pub fn builder(peer_addr: SocketAddr) -> DtpClientBuilder<'l> {
+        DtpClientBuilder::new(peer_addr)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.state.borrow().local_addr
+    }
+
+    /// The outcome of the blocks so far, see `BlockReceiver::write_report`
+    pub fn receiver(&self) -> Ref<'_, BlockReceiver> {
+        Ref::map(self.state.borrow(), |state| &state.receiver)
+    }
+
+    /// Whether the connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.state.borrow().closed
+    }
+
+    /// The failure that closed the connection: of the socket, of QUIC, or
+    /// the error code the server closed the connection with
+    pub fn take_error(&self) -> Option<Error> {
+        self.state.borrow_mut().error.take()
+    }
+}
+
+/// Configure a `DtpClient`, the defaults are those of the command line
+pub struct DtpClientBuilder<'l> {
+    peer_addr: SocketAddr,
+    bind_addr: SocketAddr,
+    connect_delay: Duration,
+    quic_options: QuicOptions,
+    events: ClientEvents<'l>,
+}
+
+impl<'l> DtpClientBuilder<'l> {
+    /// A client connecting to the server at `peer_addr`
+    pub fn new(peer_addr: SocketAddr) -> Self {
+        DtpClientBuilder {
+            peer_addr,
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            connect_delay: Duration::ZERO,
+            quic_options: QuicOptions::default(),
+            events: ClientEvents::default(),
+        }
+    }
+
+    /// The local address of the client, any by default
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Wait before connecting, to give a server started at the same time
+    /// the chance to bind its socket
+    pub fn connect_delay(mut self, delay: Duration) -> Self {
+        self.connect_delay = delay;
+        self
+    }
+
+    pub fn quic_options(mut self, quic_options: QuicOptions) -> Self {
+        self.quic_options = quic_options;
+        self
+    }
+
+    /// Call `callback` with every block that completes and its data
+    pub fn on_block_received<F: FnMut(&BlockEvent, Vec<u8>) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_block_received = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` when a block completes after its deadline, or the
+    /// server drops or abandons it
+    pub fn on_deadline_missed<F: FnMut(&BlockEvent) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_deadline_missed = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` when the connection is closed
+    pub fn on_connection_closed<F: FnMut(SocketAddr, &quiche::Stats) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_connection_closed = Some(Box::new(callback));
+        self
+    }
+
+    /// Bind the socket and insert the client into the event loop of
+    /// `handle`, it connects after the `connect_delay`
+    pub fn build<D: 'l>(self, handle: &LoopHandle<'l, D>) -> Result<DtpClient<'l>> {
+        // init socket
+        let client_socket = UdpSocket::bind(self.bind_addr)?;
+        client_socket.set_nonblocking(true)?;
+        set_recv_buffer(&client_socket)?;
+        let local_addr = client_socket.local_addr()?;
+        // init quiche
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+        set_quiche_conn_config(&mut config, &self.quic_options)?;
+        load_verify_locations(&mut config, &self.quic_options)?;
+        let server_name = self.quic_options.server_name
+            .unwrap_or_else(|| self.peer_addr.ip().to_string());
+
+        // Generate a random source connection ID for the connection.
+        let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+        SystemRandom::new().fill(&mut scid[..])
+            .map_err(|_| std::io::Error::other("failed to generate the connection id"))?;
+
+        let receiver = match self.events.on_block_received {
+            Some(_) => BlockReceiver::with_data(),
+            None => BlockReceiver::default(),
+        };
+        let state = Rc::new(RefCell::new(ClientState {
+            scid: ConnectionId::from_vec(scid.to_vec()),
+            local_addr,
+            peer_addr: self.peer_addr,
+            server_name,
+            config,
+            receiver,
+            events: Vec::new(),
+            callbacks: Rc::new(RefCell::new(self.events)),
+            ack_buf: Vec::new(),
+            closed: false,
+            error: None,
+            conn: None,
+            socket: client_socket.try_clone()?,
+            timer: None,
+        }));
+
+        // connect once the delay is over
+        let weak = Rc::downgrade(&state);
+        handle.insert_source(
+            Timer::from_duration(self.connect_delay),
+            move |_event, _metadata, _data: &mut D| {
+                if let Some(state) = weak.upgrade() {
+                    let mut state_ref = state.borrow_mut();
+                    if let Err(e) = client_connect(&mut state_ref) {
+                        client_fail(&mut state_ref, e);
+                    }
+                    drop(state_ref);
+                    fire_events(&state);
+                }
+                TimeoutAction::Drop
+            },
+        )?;
+        // add recv_cb
+        let weak = Rc::downgrade(&state);
+        handle.insert_source(
+            // wrap your IO object in a Generic, here we register for read readiness
+            // in level-triggering mode
+            Generic::new(client_socket, Interest::READ, Mode::Level),
+            move |readiness, socket, _data: &mut D| match weak.upgrade() {
+                Some(state) => {
+                    let action = client_recv_cb(readiness, socket, &mut state.borrow_mut());
+                    fire_events(&state);
+                    action
+                },
+                None => Ok(PostAction::Remove),
+            },
+        )?;
+        // add timeout cb
+        let weak = Rc::downgrade(&state);
+        let timeout_dispatcher = Dispatcher::new(
+            Timer::from_duration(Duration::from_secs(IDLE_TIMEOUT)),
+            move |_event, _metadata, _data: &mut D| match weak.upgrade() {
+                Some(state) => {
+                    let action = client_timeout_cb(&mut state.borrow_mut());
+                    fire_events(&state);
+                    action
+                },
+                None => TimeoutAction::Drop,
+            },
+        );
+        let timeout_token = handle.register_dispatcher(timeout_dispatcher.clone())?;
+
+        state.borrow_mut().timer = Some(Box::new(DispatcherTimer {
+            handle: handle.clone(),
+            dispatcher: timeout_dispatcher,
+            token: timeout_token,
+        }));
+        Ok(DtpClient { state })
+    }
+}
+
+/// Call the callbacks of the events queued in `state`, once it is not
+/// borrowed anymore. The callbacks may call the client.
+fn fire_events(state: &RefCell<ClientState>) {
+    let callbacks = state.borrow().callbacks.clone();
+    let mut callbacks = match callbacks.try_borrow_mut() {
+        Ok(callbacks) => callbacks,
+        // called back from a callback, whose caller fires the new events
+        Err(_) => return,
+    };
+    loop {
+        let events = std::mem::take(&mut state.borrow_mut().events);
+        if events.is_empty() {
+            return;
+        }
+        for event in events {
+            callbacks.fire(event);
+        }
+    }
+}
+
+/// Create a QUIC connection and initiate handshake.
+fn client_connect(state: &mut ClientState) -> Result<()> {
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+    let peer_addr = state.peer_addr;
+    let mut conn =
+        quiche::connect(Some(&state.server_name), &state.scid, state.local_addr, peer_addr, &mut state.config)?;
+
+    info!(
+        "connecting to {:} from {:} with scid {}",
+        peer_addr,
+        state.local_addr,
+        hex_dump(&state.scid)
+    );
+
+    let (write, send_info) = conn.send(&mut out)?;
+
+    while let Err(e) = state.socket.send_to(&out[..write], send_info.to) {
+        if would_block(&e) {
+            debug!("send() would block");
+            continue;
+        }
+
+        return Err(e.into());
+    }
+
+    debug!("written {}", write);
+
+    state.conn = Some(conn);
+    update_client_timer(state);
+    Ok(())
+}
+
+fn client_recv_cb(
+    _readiness: calloop::Readiness,
+    _io_object: &mut UdpSocket,
+    state: &mut ClientState
+) -> std::io::Result<calloop::PostAction> {
+    match client_recv(state) {
+        Ok(action) => Ok(action),
+
+        Err(e) => {
+            client_fail(state, e);
+            Ok(PostAction::Remove)
+        },
+    }
+}
+
+/// Feed the packets waiting on the socket to the connection, report the
+/// blocks, and send the packets of the connection.
+fn client_recv(state: &mut ClientState) -> Result<PostAction> {
+    let mut buf = [0; 65535];
+    let socket = &mut state.socket;
+    let conn = match state.conn.as_mut() {
+        Some(conn) => conn,
+        // nothing to read before connecting
+        None => return Ok(PostAction::Continue),
+    };
+    // Read incoming UDP packets from the socket and feed them to quiche,
+    // until there are no more packets to read.
+    'read: loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(v) => v,
+
+            Err(e) => {
+                // There are no more UDP packets to read, so end the read
+                // loop.
+                if would_block(&e) {
+                    debug!("client recv() would block");
+                    break 'read;
+                }
+
+                return Err(e.into());
+            },
+        };
+
+        debug!("client got {} bytes", len);
+
+        let recv_info = quiche::RecvInfo {
+            to: state.local_addr,
+            from,
+        };
+
+        // Process potentially coalesced packets.
+        let read = match conn.recv(&mut buf[..len], recv_info) {
+            Ok(v) => v,
+
+            Err(e) => {
+                error!("recv failed: {:?}", e);
+                continue 'read;
+            },
+        };
+
+        debug!("client processed {} bytes", read);
+    }
+
+    debug!("client done reading");
+
+    if conn.is_closed() {
+        info!("client connection closed, {:?}", conn.stats());
+        client_finish(state);
+        return Ok(PostAction::Remove);
+    }
+
+    let mut completed = Vec::new();
+
+    // Process all readable streams.
+    for s in conn.readable() {
+        loop {
+            let (read, fin) = match conn.stream_recv(s, &mut buf) {
+                Ok(v) => v,
+
+                Err(quiche::Error::StreamReset(error_code)) => {
+                    state.receiver.on_stream_reset(s, error_code);
+                    break;
+                },
+
+                Err(_) => break,
+            };
+            debug!("client received {} bytes", read);
+
+            let stream_buf = &buf[..read];
+
+            debug!(
+                "client stream {} has {} bytes (fin? {})",
+                s,
+                stream_buf.len(),
+                fin
+            );
+
+            completed.extend(state.receiver.on_stream_data(s, stream_buf, fin, get_current_usec()));
+        }
+    }
+
+    // Process the pieces of blocks sent in datagrams.
+    loop {
+        let len = match conn.dgram_recv(&mut buf) {
+            Ok(v) => v,
+
+            Err(quiche::Error::Done) => break,
+
+            Err(e) => {
+                error!("client dgram_recv() failed: {:?}", e);
+                break;
+            },
+        };
+        debug!("client received a datagram of {} bytes", len);
+
+        completed.extend(state.receiver.on_datagram(&buf[..len], get_current_usec()));
+    }
+
+    report_blocks(&mut state.receiver, &mut state.events, &mut state.ack_buf, completed);
+    flush_ack_stream(&mut state.ack_buf, conn)?;
+
+    // every announced block is accounted for, no need to wait for the idle timeout
+    if state.receiver.is_finished() && state.ack_buf.is_empty() {
+        // the close stops the stream data, send the last acknowledgements first
+        client_flush_quic_packets(socket, conn)?;
+        if conn.close(true, 0x00, b"done").is_ok() {
+            info!("client received all the blocks, closing...");
+        }
+    }
+
+    client_flush_quic_packets(socket, conn)?;
+
+    if conn.is_closed() {
+        info!("connection closed, {:?}", conn.stats());
+        client_finish(state);
+        return Ok(PostAction::Remove);
+    }
+    update_client_timer(state);
+    Ok(PostAction::Continue)
+}
+
+/// Acknowledge the completed blocks and call the callbacks of the blocks
+/// that completed or that the server gave up on.
+fn report_blocks(receiver: &mut BlockReceiver, events: &mut Vec<Event>, ack_buf: &mut Vec<u8>, completed: Vec<usize>) {
+    let now = get_current_usec();
+    for id in completed {
+        ack_buf.extend_from_slice(&StreamFrame::BlockAck { id }.to_vec());
+
+        let (event, late) = match receiver.get_block(id) {
+            Some(block) => match block.info() {
+                Some(info) => {
+                    let create_time = block.create_time().unwrap_or(now);
+                    (BlockEvent { info: *info, create_time, time: now }, !block.is_deadline_met())
+                },
+                None => continue,
+            },
+            None => continue,
+        };
+        // only kept with a callback
+        if let Some(data) = receiver.take_data(id) {
+            events.push(Event::BlockReceived(event, data));
+        }
+        if late {
+            events.push(Event::DeadlineMissed(event));
+        }
+    }
+
+    for id in receiver.take_given_up() {
+        let block = receiver.get_block(id);
+        if let Some(info) = block.and_then(|b| b.info()).copied() {
+            let create_time = block.and_then(|b| b.create_time()).unwrap_or(now);
+            events.push(Event::DeadlineMissed(BlockEvent { info, create_time, time: now }));
+        }
+    }
+}
+
+/// Writes the pending acknowledgements into the ack stream.
+fn flush_ack_stream(ack_buf: &mut Vec<u8>, conn: &mut quiche::Connection) -> Result<()> {
+    if ack_buf.is_empty() {
+        return Ok(());
+    }
+
+    match conn.stream_send(ACK_STREAM_ID, ack_buf, false) {
+        Ok(written) => {
+            ack_buf.drain(..written);
+        },
+
+        Err(quiche::Error::Done) => {},
+
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// The connection is closed, the application finds the outcome of the
+/// blocks in the receiver.
+fn client_finish(state: &mut ClientState) {
+    if let Some(conn) = state.conn.as_ref() {
+        if let Some(e) = conn.peer_error().filter(|e| e.error_code != 0) {
+            let reason = String::from_utf8_lossy(&e.reason);
+            let e = Error::Protocol(format!("the server closed the connection with error {:#x}: {}", e.error_code, reason));
+            error!("{}", e);
+            state.error.get_or_insert(e);
+        }
+        // the failures of quiche itself, such as a certificate the client rejects
+        if let Some(e) = conn.local_error().filter(|e| e.error_code != 0) {
+            let e = match e.error_code {
+                0x100..=0x1ff => Error::Config(format!(
+                    "the TLS handshake failed with alert {}, check the CA file and the server name",
+                    e.error_code - 0x100
+                )),
+                code => Error::Protocol(format!("the connection failed with error {:#x}", code)),
+            };
+            error!("{}", e);
+            state.error.get_or_insert(e);
+        }
+        if conn.is_timed_out() && !conn.is_established() {
+            let e = std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("the server at {} did not answer", state.peer_addr),
+            );
+            state.error.get_or_insert(e.into());
+        }
+        state.events.push(Event::ConnectionClosed(state.peer_addr, conn.stats()));
+    }
+    state.closed = true;
+}
+
+/// The socket or the connection failed: close the connection and stop the
+/// client, the application gets the error from `DtpClient::take_error`
+fn client_fail(state: &mut ClientState, e: Error) {
+    if let Some(conn) = state.conn.as_mut() {
+        close_connection(conn, &e);
+        // best effort, the socket may be the one that failed
+        client_flush_quic_packets(&mut state.socket, conn).ok();
+    } else {
+        error!("client failed: {}", e);
+    }
+    state.error.get_or_insert(e);
+    state.closed = true;
+}
+
+fn client_flush_quic_packets(socket: &mut UdpSocket, conn: &mut quiche::Connection) -> Result<()> {
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (write, send_info) = match conn.send(&mut out) {
+            Ok(v) => v,
+
+            Err(quiche::Error::Done) => {
+                debug!("client done writing");
+                break;
+            },
+
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Err(e) = socket.send_to(&out[..write], send_info.to) {
+            if would_block(&e) {
+                debug!("send() would block");
+                break;
+            }
+
+            return Err(e.into());
+        }
+
+        debug!("client written {}", write);
+    }
+    Ok(())
+}
+
+/// Fire the timeout timer at the QUIC timeout of the connection
+fn update_client_timer(state: &mut ClientState) {
+    let timeout = state.conn.as_ref()
+        .and_then(|conn| conn.timeout())
+        .unwrap_or(Duration::from_secs(IDLE_TIMEOUT));
+    state.timer.as_mut().unwrap().set_duration(timeout);
+}
+
+fn client_timeout_cb(state: &mut ClientState) -> TimeoutAction {
+    let conn = match state.conn.as_mut() {
+        Some(conn) => conn,
+        None => return TimeoutAction::ToDuration(Duration::from_secs(IDLE_TIMEOUT)),
+    };
+    // handle timeout
+    conn.on_timeout();
+
+    if conn.is_closed() {
+        info!("connection closed, {:?}", conn.stats());
+        client_finish(state);
+        return TimeoutAction::Drop;
+    }
+
+    if let Err(e) = client_flush_quic_packets(&mut state.socket, conn) {
+        client_fail(state, e);
+        return TimeoutAction::Drop;
+    }
+
+    // update timer
+    match conn.timeout() {
+        Some(next_timeout) => TimeoutAction::ToDuration(next_timeout),
+        None => TimeoutAction::ToDuration(Duration::from_secs(IDLE_TIMEOUT)),
+    }
+}
@@ -0,0 +1,131 @@
+//! Blocks chopped into QUIC DATAGRAM frames
+//!
+//! Every datagram carries a piece of one block, or a repair symbol of some
+//! of its pieces (see `crate::fec`):
+//!
+//! | type | id | offset | size | priority | deadline | create_time | [group_size] | data |
+//!
+//! where every header field is a varint, `type` is 0 for a piece of data and
+//! 1 for a repair symbol, `size` is the total size of the block, `priority`
+//! and `deadline` (ms) those of its `BlockInfo`, so that the blocks not
+//! announced on the control stream are complete, and `create_time` the time
+//! it was generated, in microseconds. A repair symbol
+//! also carries the number of pieces it protects, starting at `offset`.
+//! Datagrams are never retransmitted, so a block whose piece is lost and
+//! cannot be repaired stays incomplete.
+use crate::frame::{Error, Result};
+
+const DATA_TYPE: u64 = 0x0;
+const REPAIR_TYPE: u64 = 0x1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatagramHeader {
+    pub id: usize,
+    /// the offset of the data of the datagram in the block
+    pub offset: usize,
+    /// the total size of the block
+    pub size: usize,
+    pub priority: usize,
+    /// ms
+    pub deadline: usize,
+    pub create_time: u64,
+    /// the number of pieces protected by a repair symbol, None for data
+    pub repair: Option<usize>,
+}
+
+impl DatagramHeader {
+    /// Encode the header, return the number of bytes written
+    pub fn to_bytes(self, b: &mut octets::OctetsMut) -> Result<usize> {
+        let before = b.cap();
+        b.put_varint(self.ty())?;
+        b.put_varint(self.id as u64)?;
+        b.put_varint(self.offset as u64)?;
+        b.put_varint(self.size as u64)?;
+        b.put_varint(self.priority as u64)?;
+        b.put_varint(self.deadline as u64)?;
+        b.put_varint(self.create_time)?;
+        if let Some(group_size) = self.repair {
+            b.put_varint(group_size as u64)?;
+        }
+        Ok(before - b.cap())
+    }
+    /// Encode the header followed by `data` into a new datagram
+    pub fn to_vec(self, data: &[u8]) -> Vec<u8> {
+        let header_len = self.wire_len();
+        let mut buf = vec![0; header_len + data.len()];
+        self.to_bytes(&mut octets::OctetsMut::with_slice(&mut buf)).unwrap();
+        buf[header_len..].copy_from_slice(data);
+        buf
+    }
+    /// Decode the header, the data of the datagram is left in `b`
+    pub fn from_bytes(b: &mut octets::Octets) -> Result<Self> {
+        let ty = b.get_varint()?;
+        if ty != DATA_TYPE && ty != REPAIR_TYPE {
+            return Err(Error::UnknownFrame(ty));
+        }
+        Ok(DatagramHeader {
+            id: b.get_varint()? as usize,
+            offset: b.get_varint()? as usize,
+            size: b.get_varint()? as usize,
+            priority: b.get_varint()? as usize,
+            deadline: b.get_varint()? as usize,
+            create_time: b.get_varint()?,
+            repair: match ty {
+                REPAIR_TYPE => Some(b.get_varint()? as usize),
+                _ => None,
+            },
+        })
+    }
+    /// The number of bytes `to_bytes` writes
+    pub fn wire_len(&self) -> usize {
+        octets::varint_len(self.ty())
+            + octets::varint_len(self.id as u64)
+            + octets::varint_len(self.offset as u64)
+            + octets::varint_len(self.size as u64)
+            + octets::varint_len(self.priority as u64)
+            + octets::varint_len(self.deadline as u64)
+            + octets::varint_len(self.create_time)
+            + self.repair.map_or(0, |group_size| octets::varint_len(group_size as u64))
+    }
+    fn ty(&self) -> u64 {
+        match self.repair {
+            Some(_) => REPAIR_TYPE,
+            None => DATA_TYPE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let header = DatagramHeader {
+            id: 42,
+            offset: 288_000,
+            size: 288_555,
+            priority: 2,
+            deadline: 200,
+            create_time: 1_678_400_000_000_000,
+            repair: None,
+        };
+        let repair = DatagramHeader { repair: Some(4), ..header };
+        for header in [header, repair] {
+            let mut buf = [0; 64];
+            let mut b = octets::OctetsMut::with_slice(&mut buf);
+            let written = header.to_bytes(&mut b).unwrap();
+            assert_eq!(written, header.wire_len());
+            buf[written..written + 3].copy_from_slice(&[1, 2, 3]);
+
+            let mut b = octets::Octets::with_slice(&buf[..written + 3]);
+            assert_eq!(DatagramHeader::from_bytes(&mut b), Ok(header));
+            assert_eq!(b.as_ref(), &[1, 2, 3]);
+
+            let mut b = octets::Octets::with_slice(&buf[..written - 1]);
+            assert_eq!(DatagramHeader::from_bytes(&mut b), Err(Error::BufferTooShort));
+        }
+        let mut b = octets::Octets::with_slice(&[0x2, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(DatagramHeader::from_bytes(&mut b), Err(Error::UnknownFrame(0x2)));
+    }
+}
@@ -0,0 +1,584 @@
+//! An emulated network link between the server and the client sockets
+//!
+//! `Link` models one direction of the path: a drop-tail queue in front of a
+//! bottleneck of `bandwidth`, or whose capacity follows a `CapacityTrace`,
+//! followed by a propagation `delay` with `jitter`, random and burst losses
+//! and reordering. `spawn_emulator` relays the UDP datagrams of a client
+//! and a server through two links, so that experiments run on loopback
+//! without tc/netem, until `Emulator::stop`.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use dtp_utils::get_current_usec;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// The impairments of a link, the default is an ideal link
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LinkConfig {
+    /// bits per second, 0 for unlimited
+    pub bandwidth: f64,
+    /// the capacity of the bottleneck over time, instead of `bandwidth`
+    pub capacity: Option<CapacityTrace>,
+    /// one-way delay, in microseconds
+    pub delay: u64,
+    /// the delay varies uniformly by up to this much, in microseconds
+    pub jitter: u64,
+    /// the probability to lose a packet
+    pub loss: f64,
+    /// the Gilbert-Elliott burst loss model: the probability to enter the
+    /// state where every packet is lost, and the probability to leave it
+    pub burst_loss: Option<(f64, f64)>,
+    /// the probability that a packet skips the delay and overtakes the
+    /// packets before it
+    pub reorder: f64,
+    /// the size of the queue in front of the bottleneck, in bytes, 0 for unlimited
+    pub queue_size: usize,
+    /// the seed of the random impairments
+    pub seed: u64,
+}
+
+impl LinkConfig {
+    /// Whether the link forwards the packets as they are
+    pub fn is_ideal(&self) -> bool {
+        *self == LinkConfig { seed: self.seed, ..Default::default() }
+    }
+}
+
+/// The bytes delivered by a delivery opportunity of a `CapacityTrace`
+pub const TRACE_MTU: usize = 1500;
+
+/// The capacity of a link over time as delivery opportunities, as in
+/// Mahimahi: every opportunity delivers up to `TRACE_MTU` bytes, those it
+/// does not use are lost, and the trace repeats every `period`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapacityTrace {
+    /// the time of the opportunities in a period, in microseconds
+    opportunities: Vec<u64>,
+    period: u64,
+}
+
+impl CapacityTrace {
+    /// Load a Mahimahi trace, or a CSV if the file has commas
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read the link trace {}: {}", path, e))?;
+        let trace = if text.contains(',') {
+            Self::from_csv(&text)
+        } else {
+            Self::from_mahimahi(&text)
+        };
+        trace.map_err(|e| anyhow!("invalid link trace {}: {}", path, e))
+    }
+
+    /// A Mahimahi trace has the time of an opportunity in milliseconds on
+    /// every line, in increasing order, and repeats after the last one
+    pub fn from_mahimahi(text: &str) -> Result<Self> {
+        let mut opportunities = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let ms: u64 = line.parse().map_err(|e| anyhow!("invalid time {:?}: {}", line, e))?;
+            if opportunities.last().is_some_and(|last| ms * 1000 < *last) {
+                return Err(anyhow!("the time {} goes backwards", ms));
+            }
+            opportunities.push(ms * 1000);
+        }
+        Self::new(opportunities)
+    }
+
+    /// A CSV trace has `time (ms),bandwidth (Mbit/s)` lines, in increasing
+    /// order of time. The bandwidth of a line holds until the time of the
+    /// next one, the last one lasts as long as the one before it, and the
+    /// trace repeats.
+    pub fn from_csv(text: &str) -> Result<Self> {
+        let mut rows = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let row = line
+                .split_once(',')
+                .and_then(|(t, bw)| Some((t.trim().parse::<u64>().ok()?, bw.trim().parse::<f64>().ok()?)));
+            let (ms, mbps) = row.ok_or_else(|| anyhow!("expected time (ms),bandwidth (Mbit/s), got {:?}", line))?;
+            if rows.last().is_some_and(|(last, _)| ms <= *last) {
+                return Err(anyhow!("the time {} does not increase", ms));
+            }
+            rows.push((ms, mbps));
+        }
+        let last_duration = match rows.as_slice() {
+            [.., (before, _), (last, _)] => last - before,
+            _ => 1,
+        };
+        let end = rows.last().map_or(0, |(last, _)| last + last_duration);
+        // spread the bytes of every millisecond in MTU-sized opportunities
+        let mut opportunities = Vec::new();
+        let mut credit = 0.0;
+        for (i, (start, mbps)) in rows.iter().enumerate() {
+            let next = rows.get(i + 1).map_or(end, |(t, _)| *t);
+            for ms in *start..next {
+                credit += mbps.max(0.0) * 1e6 / 8.0 / 1000.0;
+                while credit >= TRACE_MTU as f64 {
+                    opportunities.push(ms * 1000);
+                    credit -= TRACE_MTU as f64;
+                }
+            }
+        }
+        let mut trace = Self::new(opportunities)?;
+        trace.period = end * 1000;
+        Ok(trace)
+    }
+
+    fn new(opportunities: Vec<u64>) -> Result<Self> {
+        let period = match opportunities.last() {
+            Some(last) if *last > 0 => *last,
+            _ => return Err(anyhow!("the trace must deliver something and last more than 0 ms")),
+        };
+        Ok(CapacityTrace { opportunities, period })
+    }
+
+    /// The time of the opportunity `index`, repeating the trace
+    fn opportunity(&self, index: u64) -> u64 {
+        let n = self.opportunities.len() as u64;
+        self.opportunities[(index % n) as usize] + index / n * self.period
+    }
+}
+
+/// The next opportunity a link uses on its `CapacityTrace`
+#[derive(Debug, Default)]
+struct TraceCursor {
+    /// the time the trace starts, the arrival of the first packet
+    origin: Option<u64>,
+    index: u64,
+    /// the bytes the opportunity `index` can still deliver
+    left: usize,
+}
+
+impl TraceCursor {
+    /// Transmit a packet of `size` bytes from `start`, return the end of
+    /// the transmission
+    fn transmit(&mut self, trace: &CapacityTrace, start: u64, mut size: usize) -> u64 {
+        let origin = *self.origin.get_or_insert_with(|| {
+            self.left = TRACE_MTU;
+            start
+        });
+        let start = start - origin;
+        // skip the periods the link was idle
+        let behind = start.saturating_sub(trace.opportunity(self.index));
+        if behind > trace.period {
+            self.index += behind / trace.period * trace.opportunities.len() as u64;
+            self.left = TRACE_MTU;
+        }
+        loop {
+            let time = trace.opportunity(self.index);
+            // the opportunities before the packet are wasted
+            if time < start {
+                self.index += 1;
+                self.left = TRACE_MTU;
+                continue;
+            }
+            let taken = size.min(self.left);
+            size -= taken;
+            self.left -= taken;
+            if self.left == 0 {
+                self.index += 1;
+                self.left = TRACE_MTU;
+            }
+            if size == 0 {
+                return origin + time;
+            }
+        }
+    }
+}
+
+/// What happened to the packets sent on a link
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent: usize,
+    /// dropped because the queue was full
+    pub queue_drops: usize,
+    /// lost by the random or burst loss
+    pub lost: usize,
+    pub reordered: usize,
+}
+
+/// One direction of an emulated path, driven by the time in microseconds
+#[derive(Debug)]
+pub struct Link {
+    config: LinkConfig,
+    rng: StdRng,
+    /// when the bottleneck finishes transmitting the queued packets
+    busy_until: u64,
+    cursor: TraceCursor,
+    /// the end of transmission and size of the queued packets
+    queue: VecDeque<(u64, usize)>,
+    queued_bytes: usize,
+    /// whether the burst loss model is in the loss state
+    bursting: bool,
+    /// the delivery time of the last packet that was not reordered
+    last_delivery: u64,
+    /// the packets on the way, by delivery time then order of arrival
+    in_flight: BinaryHeap<Reverse<(u64, u64, Vec<u8>)>>,
+    stats: LinkStats,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig) -> Self {
+        Link {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            busy_until: 0,
+            cursor: TraceCursor::default(),
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            bursting: false,
+            last_delivery: 0,
+            in_flight: BinaryHeap::new(),
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// A packet enters the link at `now`.
+    /// Return false if it is dropped or lost.
+    pub fn send(&mut self, now: u64, packet: Vec<u8>) -> bool {
+        let seq = self.stats.sent as u64;
+        self.stats.sent += 1;
+
+        // the packets whose transmission is over left the queue
+        while let Some(&(end, size)) = self.queue.front() {
+            if end > now {
+                break;
+            }
+            self.queue.pop_front();
+            self.queued_bytes -= size;
+        }
+        if self.config.queue_size > 0 && self.queued_bytes + packet.len() > self.config.queue_size {
+            self.stats.queue_drops += 1;
+            return false;
+        }
+        let start = self.busy_until.max(now);
+        let sent = if let Some(trace) = self.config.capacity.as_ref() {
+            self.cursor.transmit(trace, start, packet.len())
+        } else if self.config.bandwidth > 0.0 {
+            let tx_time = packet.len() as f64 * 8.0 / self.config.bandwidth * 1e6;
+            start + tx_time.round() as u64
+        } else {
+            start
+        };
+        if sent > now {
+            self.queue.push_back((sent, packet.len()));
+            self.queued_bytes += packet.len();
+        }
+        self.busy_until = sent;
+
+        // a lost packet still took its share of the bottleneck
+        if self.is_lost() {
+            self.stats.lost += 1;
+            return false;
+        }
+
+        let deliver = if self.config.reorder > 0.0 && self.rng.gen_bool(self.config.reorder.min(1.0)) {
+            self.stats.reordered += 1;
+            sent
+        } else {
+            let jitter = match self.config.jitter {
+                0 => 0,
+                jitter => self.rng.gen_range(-(jitter as i64)..=jitter as i64),
+            };
+            let delay = (self.config.delay as i64 + jitter).max(0) as u64;
+            // jitter alone does not reorder packets
+            let deliver = (sent + delay).max(self.last_delivery);
+            self.last_delivery = deliver;
+            deliver
+        };
+        self.in_flight.push(Reverse((deliver, seq, packet)));
+        true
+    }
+
+    fn is_lost(&mut self) -> bool {
+        if let Some((enter, leave)) = self.config.burst_loss {
+            let p = if self.bursting { leave } else { enter };
+            if self.rng.gen_bool(p.clamp(0.0, 1.0)) {
+                self.bursting = !self.bursting;
+            }
+            if self.bursting {
+                return true;
+            }
+        }
+        self.config.loss > 0.0 && self.rng.gen_bool(self.config.loss.min(1.0))
+    }
+
+    /// Return the next packet delivered by `now`
+    pub fn recv(&mut self, now: u64) -> Option<Vec<u8>> {
+        match self.in_flight.peek() {
+            Some(Reverse((deliver, _, _))) if *deliver <= now => {
+                self.in_flight.pop().map(|Reverse((_, _, packet))| packet)
+            },
+            _ => None,
+        }
+    }
+
+    /// When the next packet is delivered, None if no packet is on the way
+    pub fn next_delivery(&self) -> Option<u64> {
+        self.in_flight.peek().map(|Reverse((deliver, _, _))| *deliver)
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+}
+
+/// Relay the datagrams between the client sending to `listen_addr` and the
+/// server at `server_addr` through the `uplink` from the client and the
+/// `downlink` to the client. The links should draw different random numbers.
+///
+/// The relay threads run until `Emulator::stop` or until the `Emulator` is dropped.
+pub fn spawn_emulator(listen_addr: SocketAddr, server_addr: SocketAddr, uplink: LinkConfig, downlink: LinkConfig) -> Result<Emulator> {
+    // the socket facing the client, and the one facing the server
+    let client_side = UdpSocket::bind(listen_addr)?;
+    let server_side = UdpSocket::bind(SocketAddr::new(listen_addr.ip(), 0))?;
+    let local_addr = client_side.local_addr()?;
+    let client_addr = Arc::new(Mutex::new(None));
+    let stop = Arc::new(AtomicBool::new(false));
+    info!(
+        "emulate the uplink {:?} and the downlink {:?} between {} and {} via {}",
+        uplink,
+        downlink,
+        listen_addr,
+        server_addr,
+        server_side.local_addr()?
+    );
+
+    let uplink = Link::new(uplink);
+    let (ingress, egress) = (client_side.try_clone()?, server_side.try_clone()?);
+    let peer = client_addr.clone();
+    let uplink_stop = stop.clone();
+    let uplink = std::thread::spawn(move || {
+        relay(uplink, ingress, egress, "uplink", &uplink_stop, |from| {
+            *peer.lock().unwrap() = Some(from);
+            Some(server_addr)
+        })
+    });
+
+    let downlink = Link::new(downlink);
+    let downlink_stop = stop.clone();
+    let downlink = std::thread::spawn(move || {
+        relay(downlink, server_side, client_side, "downlink", &downlink_stop, |_| *client_addr.lock().unwrap())
+    });
+    Ok(Emulator { local_addr, stop, threads: vec![uplink, downlink] })
+}
+
+/// The relay threads started by `spawn_emulator`
+pub struct Emulator {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<Result<()>>>,
+}
+
+impl Emulator {
+    /// The address the client sends to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop relaying and wait for the threads, return the error that
+    /// stopped one of them early
+    pub fn stop(mut self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let mut result = Ok(());
+        for thread in std::mem::take(&mut self.threads) {
+            let relay_result = thread.join().map_err(|_| anyhow!("the relay thread has panicked"))?;
+            result = result.and(relay_result);
+        }
+        result
+    }
+}
+
+/// Dropping the emulator stops the threads without waiting for them
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Forward the datagrams read from `ingress` through the link to `egress`
+/// until `stop` is set, `route` returns where the datagram from an address goes
+fn relay<F>(mut link: Link, ingress: UdpSocket, egress: UdpSocket, name: &str, stop: &AtomicBool, mut route: F) -> Result<()>
+where
+    F: FnMut(SocketAddr) -> Option<SocketAddr>,
+{
+    let mut buf = [0; 65535];
+    let mut to = None;
+    let mut last_log = get_current_usec();
+    while !stop.load(Ordering::Relaxed) {
+        let now = get_current_usec();
+        while let Some(packet) = link.recv(now) {
+            if let Some(to) = to {
+                if let Err(e) = egress.send_to(&packet, to) {
+                    error!("{} send_to() failed: {:?}", name, e);
+                }
+            }
+        }
+        if now - last_log > 1_000_000 {
+            debug!("{} {:?}", name, link.stats());
+            last_log = now;
+        }
+
+        // wait for a datagram until the next delivery
+        let timeout = link
+            .next_delivery()
+            .map(|t| t.saturating_sub(now))
+            .unwrap_or(100_000)
+            .max(1);
+        ingress
+            .set_read_timeout(Some(Duration::from_micros(timeout)))
+            .map_err(|e| anyhow!("{} set_read_timeout() failed: {}", name, e))?;
+        match ingress.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                to = route(from);
+                link.send(get_current_usec(), buf[..len].to_vec());
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
+            Err(e) => error!("{} recv_from() failed: {:?}", name, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send `count` packets of `size` bytes every `gap` us, return the
+    /// delivery time of every packet by its index
+    fn run(config: LinkConfig, count: usize, size: usize, gap: u64) -> Vec<(usize, u64)> {
+        let mut link = Link::new(config);
+        let mut delivered = Vec::new();
+        let mut now = 0;
+        for i in 0..count {
+            let mut packet = vec![0; size];
+            packet[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            link.send(now, packet);
+            now += gap;
+        }
+        while let Some(t) = link.next_delivery() {
+            let packet = link.recv(t).unwrap();
+            delivered.push((u64::from_be_bytes(packet[..8].try_into().unwrap()) as usize, t));
+        }
+        delivered
+    }
+
+    #[test]
+    fn ideal_link() {
+        assert!(LinkConfig { seed: 7, ..Default::default() }.is_ideal());
+        let delivered = run(LinkConfig::default(), 3, 100, 10);
+        assert_eq!(delivered, vec![(0, 0), (1, 10), (2, 20)]);
+    }
+
+    #[test]
+    fn bandwidth_delay_and_queue() {
+        // 1250 bytes take 1 ms at 10 Mbit/s, the queue holds 2 packets
+        let config = LinkConfig {
+            bandwidth: 10e6,
+            delay: 20_000,
+            queue_size: 2500,
+            ..Default::default()
+        };
+        let mut link = Link::new(config);
+        for _ in 0..4 {
+            link.send(0, vec![0; 1250]);
+        }
+        assert_eq!(link.stats().queue_drops, 2);
+        assert_eq!(link.next_delivery(), Some(21_000));
+        assert!(link.recv(21_000).is_some());
+        assert_eq!(link.next_delivery(), Some(22_000));
+        // the queue drained meanwhile
+        assert!(link.send(1_500, vec![0; 1250]));
+    }
+
+    #[test]
+    fn jitter_keeps_the_order() {
+        let config = LinkConfig { delay: 10_000, jitter: 5_000, seed: 1, ..Default::default() };
+        let delivered = run(config, 100, 100, 100);
+        let order: Vec<usize> = delivered.iter().map(|(i, _)| *i).collect();
+        assert_eq!(order, (0..100).collect::<Vec<_>>());
+        assert!(delivered.iter().all(|(i, t)| *t >= (*i as u64 * 100 + 5_000)));
+    }
+
+    #[test]
+    fn capacity_traces() {
+        let trace = CapacityTrace::from_mahimahi("1\n1\n3\n\n").unwrap();
+        assert_eq!(trace.opportunities, vec![1_000, 1_000, 3_000]);
+        assert_eq!(trace.opportunity(4), 4_000);
+        assert!(CapacityTrace::from_mahimahi("3\n1\n").is_err());
+        assert!(CapacityTrace::from_mahimahi("0\n").is_err());
+
+        // 12 Mbit/s is one opportunity per ms, the last line lasts 2 ms
+        let trace = CapacityTrace::from_csv("0,12\n2,0\n4,6\n").unwrap();
+        assert_eq!(trace.opportunities, vec![0, 1_000, 5_000]);
+        assert_eq!(trace.period, 6_000);
+        assert!(CapacityTrace::from_csv("0,12\n0,6\n").is_err());
+        assert!(CapacityTrace::from_csv("0;12\n").is_err());
+    }
+
+    #[test]
+    fn trace_driven_link() {
+        // an opportunity every ms, then none for 8 ms
+        let trace = CapacityTrace::from_mahimahi("1\n2\n10\n").unwrap();
+        let mut link = Link::new(LinkConfig { capacity: Some(trace), delay: 500, ..Default::default() });
+        let start = 1_000_000;
+        // 3 packets of 1000 bytes take 2 opportunities
+        for _ in 0..3 {
+            link.send(start, vec![0; 1000]);
+        }
+        // the idle link wastes the opportunities at 10 and 11 ms
+        link.send(start + 12_000, vec![0; 100]);
+        let mut delivered = Vec::new();
+        while let Some(t) = link.next_delivery() {
+            link.recv(t);
+            delivered.push(t - start);
+        }
+        assert_eq!(delivered, vec![1_500, 2_500, 2_500, 12_500]);
+    }
+
+    #[test]
+    fn loss_and_reordering_are_reproducible() {
+        let config = LinkConfig {
+            delay: 10_000,
+            loss: 0.1,
+            burst_loss: Some((0.01, 0.5)),
+            reorder: 0.05,
+            seed: 42,
+            ..Default::default()
+        };
+        let delivered = run(config.clone(), 1000, 100, 100);
+        assert_eq!(delivered, run(config.clone(), 1000, 100, 100));
+        assert!(delivered.len() > 800 && delivered.len() < 950, "{} delivered", delivered.len());
+        assert!(delivered.windows(2).any(|w| w[0].0 > w[1].0));
+
+        let other = run(LinkConfig { seed: 43, ..config }, 1000, 100, 100);
+        assert_ne!(delivered, other);
+    }
+
+    #[test]
+    fn relay_until_stopped() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let link = LinkConfig { delay: 1_000, ..Default::default() };
+        let local = "127.0.0.1:0".parse().unwrap();
+        let emulator = spawn_emulator(local, server.local_addr().unwrap(), link.clone(), link).unwrap();
+
+        let mut buf = [0; 16];
+        client.send_to(b"ping", emulator.local_addr()).unwrap();
+        let (len, relay_addr) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        server.send_to(b"pong", relay_addr).unwrap();
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&b"pong"[..], emulator.local_addr()));
+
+        emulator.stop().unwrap();
+    }
+}
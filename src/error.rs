@@ -0,0 +1,89 @@
+//! The errors of the QUIC server and client
+use std::fmt;
+
+/// QUIC transport error code closing a connection on internal failures
+pub const INTERNAL_ERROR: u64 = 0x1;
+/// QUIC transport error code closing a connection whose peer broke the protocol
+pub const PROTOCOL_VIOLATION: u64 = 0xa;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The socket or the event loop failed
+    Io(std::io::Error),
+    /// quiche rejected a packet, a connection or a stream operation
+    Quic(quiche::Error),
+    /// Invalid options, certificate or block given by the application
+    Config(String),
+    /// The peer sent something this protocol does not allow
+    Protocol(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The QUIC transport error code closing a connection that failed with
+    /// this error
+    pub fn close_code(&self) -> u64 {
+        match self {
+            Error::Protocol(_) => PROTOCOL_VIOLATION,
+            _ => INTERNAL_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Quic(e) => write!(f, "QUIC error: {:?}", e),
+            Error::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+        }
+    }
+}
+
+// the message already includes the wrapped error
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<quiche::Error> for Error {
+    fn from(e: quiche::Error) -> Self {
+        Error::Quic(e)
+    }
+}
+
+impl From<calloop::Error> for Error {
+    fn from(e: calloop::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl<S> From<calloop::InsertError<S>> for Error {
+    fn from(e: calloop::InsertError<S>) -> Self {
+        e.error.into()
+    }
+}
+
+/// Whether the socket has nothing to read or no room to send right now
+pub(crate) fn would_block(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_codes() {
+        assert_eq!(Error::Protocol("bad frame".to_owned()).close_code(), PROTOCOL_VIOLATION);
+        assert_eq!(Error::Quic(quiche::Error::FlowControl).close_code(), INTERNAL_ERROR);
+        let e: Error = std::io::Error::new(std::io::ErrorKind::AddrInUse, "busy").into();
+        assert!(matches!(e, Error::Io(_)));
+        assert_eq!(e.to_string(), "I/O error: busy");
+    }
+}
@@ -0,0 +1,124 @@
+//! The callbacks the application attaches to `DtpServer` and `DtpClient`.
+//!
+//! They are called from the event sources of the server and the client,
+//! inside the dispatch of the calloop event loop, so they must not block.
+//! They may call the `DtpServer` or the `DtpClient` back, for example to
+//! send the next block.
+use std::net::SocketAddr;
+
+use crate::block::BlockInfo;
+
+/// A block and the time something happened to it
+#[derive(Debug, Clone, Copy)]
+pub struct BlockEvent {
+    pub info: BlockInfo,
+    /// the time the block was generated, in microseconds
+    pub create_time: u64,
+    /// the time of the event, in microseconds
+    pub time: u64,
+}
+
+impl BlockEvent {
+    /// Time between the generation of the block and the event, in microseconds
+    pub fn elapsed(&self) -> u64 {
+        self.time.saturating_sub(self.create_time)
+    }
+
+    /// Whether the event happened after the deadline of the block
+    pub fn is_late(&self) -> bool {
+        self.elapsed() > self.info.deadline as u64 * 1000
+    }
+}
+
+pub type BlockEventCallback<'l> = Box<dyn FnMut(&BlockEvent) + 'l>;
+/// Called with a completed block and its data
+pub type BlockDataCallback<'l> = Box<dyn FnMut(&BlockEvent, Vec<u8>) + 'l>;
+/// Called with the address of the peer and the statistics of the connection
+pub type ConnectionCallback<'l> = Box<dyn FnMut(SocketAddr, &quiche::Stats) + 'l>;
+
+/// Something the server or the client reports to the application, queued
+/// while their state is borrowed and fired once it is not
+pub(crate) enum Event {
+    BlockGenerated(BlockEvent),
+    BlockSent(BlockEvent),
+    BlockAcked(BlockEvent),
+    BlockReceived(BlockEvent, Vec<u8>),
+    DeadlineMissed(BlockEvent),
+    ConnectionClosed(SocketAddr, quiche::Stats),
+}
+
+/// The callbacks of a `DtpServer`, see `DtpServerBuilder`
+#[derive(Default)]
+pub(crate) struct ServerEvents<'l> {
+    pub on_block_generated: Option<BlockEventCallback<'l>>,
+    pub on_block_sent: Option<BlockEventCallback<'l>>,
+    pub on_block_acked: Option<BlockEventCallback<'l>>,
+    pub on_deadline_missed: Option<BlockEventCallback<'l>>,
+    pub on_connection_closed: Option<ConnectionCallback<'l>>,
+}
+
+impl<'l> ServerEvents<'l> {
+    pub fn fire(&mut self, event: Event) {
+        match event {
+            Event::BlockGenerated(event) => fire_block(&mut self.on_block_generated, &event),
+            Event::BlockSent(event) => fire_block(&mut self.on_block_sent, &event),
+            Event::BlockAcked(event) => fire_block(&mut self.on_block_acked, &event),
+            Event::DeadlineMissed(event) => fire_block(&mut self.on_deadline_missed, &event),
+            Event::ConnectionClosed(peer, stats) => fire_connection(&mut self.on_connection_closed, peer, &stats),
+            // the server does not receive blocks
+            Event::BlockReceived(..) => {},
+        }
+    }
+}
+
+/// The callbacks of a `DtpClient`, see `DtpClientBuilder`
+#[derive(Default)]
+pub(crate) struct ClientEvents<'l> {
+    pub on_block_received: Option<BlockDataCallback<'l>>,
+    pub on_deadline_missed: Option<BlockEventCallback<'l>>,
+    pub on_connection_closed: Option<ConnectionCallback<'l>>,
+}
+
+impl<'l> ClientEvents<'l> {
+    pub fn fire(&mut self, event: Event) {
+        match event {
+            Event::BlockReceived(event, data) => {
+                if let Some(callback) = self.on_block_received.as_mut() {
+                    callback(&event, data);
+                }
+            },
+            Event::DeadlineMissed(event) => fire_block(&mut self.on_deadline_missed, &event),
+            Event::ConnectionClosed(peer, stats) => fire_connection(&mut self.on_connection_closed, peer, &stats),
+            // the events of the sender
+            Event::BlockGenerated(_) | Event::BlockSent(_) | Event::BlockAcked(_) => {},
+        }
+    }
+}
+
+fn fire_block(callback: &mut Option<BlockEventCallback>, event: &BlockEvent) {
+    if let Some(callback) = callback.as_mut() {
+        callback(event);
+    }
+}
+
+fn fire_connection(callback: &mut Option<ConnectionCallback>, peer: SocketAddr, stats: &quiche::Stats) {
+    if let Some(callback) = callback.as_mut() {
+        callback(peer, stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_events() {
+        let info = BlockInfo { id: 0, size: 10, priority: 1, deadline: 200 };
+        let event = BlockEvent { info, create_time: 1_000_000, time: 1_200_000 };
+        assert_eq!(event.elapsed(), 200_000);
+        assert!(!event.is_late());
+        assert!(BlockEvent { time: 1_200_001, ..event }.is_late());
+        // clocks of different hosts may be off
+        assert_eq!(BlockEvent { time: 0, ..event }.elapsed(), 0);
+    }
+}
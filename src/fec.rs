@@ -0,0 +1,278 @@
+//! XOR parity forward error correction of the datagram mode
+//!
+//! The data of a block is cut in source symbols of `symbol_len` bytes (the
+//! last one may be shorter), sent in one datagram each. Every group of
+//! `group_size` consecutive source symbols is followed by a repair symbol,
+//! the XOR of the symbols of the group padded with zeros, which recovers
+//! one lost source symbol of the group.
+use std::collections::BTreeMap;
+
+/// The largest number of source symbols a repair symbol protects, the
+/// receiver ignores the repair symbols of larger groups
+pub const MAX_GROUP_SIZE: usize = 256;
+
+/// The number of source symbols protected by a repair symbol for a
+/// redundancy ratio (repair symbols per source symbol), None without FEC.
+/// A ratio of 1 or more duplicates every source symbol, a ratio below
+/// 1 / `MAX_GROUP_SIZE` protects `MAX_GROUP_SIZE` symbols.
+pub fn group_size(ratio: f64) -> Option<usize> {
+    if ratio.is_nan() || ratio <= 0.0 {
+        return None;
+    }
+    Some((1.0 / ratio).round().clamp(1.0, MAX_GROUP_SIZE as f64) as usize)
+}
+
+/// The XOR of a group of source symbols
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairSymbol {
+    /// the offset of the first source symbol of the group in the block
+    pub offset: usize,
+    /// the number of source symbols of the group
+    pub group_size: usize,
+    pub data: Vec<u8>,
+}
+
+impl RepairSymbol {
+    /// The offset following the group in the block, None if it overflows
+    fn end(&self) -> Option<usize> {
+        self.group_size.checked_mul(self.data.len()).and_then(|len| self.offset.checked_add(len))
+    }
+}
+
+/// Compute the repair symbols of a block while its source symbols are sent
+#[derive(Debug)]
+pub struct FecEncoder {
+    symbol_len: usize,
+    group_size: usize,
+    group_offset: usize,
+    count: usize,
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new(symbol_len: usize, group_size: usize) -> Self {
+        assert!(symbol_len > 0 && group_size > 0);
+        FecEncoder {
+            symbol_len,
+            group_size,
+            group_offset: 0,
+            count: 0,
+            parity: vec![0; symbol_len],
+        }
+    }
+
+    pub fn symbol_len(&self) -> usize {
+        self.symbol_len
+    }
+
+    /// Add the next source symbol of the block, `last` if it ends the block.
+    ///
+    /// Return the repair symbol of the group once the group is complete.
+    pub fn push(&mut self, offset: usize, data: &[u8], last: bool) -> Option<RepairSymbol> {
+        assert!(data.len() <= self.symbol_len);
+        if self.count == 0 {
+            self.group_offset = offset;
+        }
+        xor_into(&mut self.parity, data);
+        self.count += 1;
+        if self.count < self.group_size && !last {
+            return None;
+        }
+        let repair = RepairSymbol {
+            offset: self.group_offset,
+            group_size: self.count,
+            data: std::mem::replace(&mut self.parity, vec![0; self.symbol_len]),
+        };
+        self.count = 0;
+        Some(repair)
+    }
+}
+
+/// Collect the source and repair symbols of a block and recover the lost
+/// source symbols when possible
+#[derive(Debug, Default)]
+pub struct FecDecoder {
+    /// the source symbols received or recovered, by offset
+    sources: BTreeMap<usize, Vec<u8>>,
+    /// the repair symbols of the groups missing more than one source symbol
+    repairs: BTreeMap<usize, RepairSymbol>,
+}
+
+impl FecDecoder {
+    /// A source symbol of a block of `block_size` bytes arrived.
+    ///
+    /// Return the number of bytes of the block it brings, including the
+    /// source symbol it allows to recover: zero for a duplicate.
+    pub fn on_source(&mut self, offset: usize, data: &[u8], block_size: usize) -> usize {
+        if self.sources.contains_key(&offset) {
+            return 0;
+        }
+        self.sources.insert(offset, data.to_vec());
+        // `Option::is_none_or` needs Rust 1.82
+        #[allow(clippy::unnecessary_map_or)]
+        let group = self
+            .repairs
+            .range(..=offset)
+            .next_back()
+            .filter(|(_, r)| r.end().map_or(true, |end| offset < end))
+            .map(|(group, _)| *group);
+        match group {
+            Some(group) => data.len() + self.try_recover(group, block_size),
+            None => data.len(),
+        }
+    }
+
+    /// A repair symbol of a block of `block_size` bytes arrived.
+    ///
+    /// Return the number of bytes of the source symbol it allows to recover.
+    pub fn on_repair(&mut self, repair: RepairSymbol, block_size: usize) -> usize {
+        // recovers nothing
+        if repair.data.is_empty() || repair.group_size == 0 {
+            return 0;
+        }
+        let group = repair.offset;
+        self.repairs.insert(group, repair);
+        self.try_recover(group, block_size)
+    }
+
+    /// Recover the missing source symbol of a group if it is the only one
+    fn try_recover(&mut self, group: usize, block_size: usize) -> usize {
+        let repair = &self.repairs[&group];
+        let symbol_len = repair.data.len();
+        let offsets = (0..repair.group_size)
+            .map_while(|i| i.checked_mul(symbol_len).and_then(|len| group.checked_add(len)))
+            .take_while(|offset| *offset < block_size);
+        let mut missing = offsets.clone().filter(|offset| !self.sources.contains_key(offset));
+        let lost = match (missing.next(), missing.next()) {
+            (Some(lost), None) => lost,
+            // everything arrived
+            (None, _) => {
+                self.repairs.remove(&group);
+                return 0;
+            },
+            // wait for more source symbols
+            (Some(_), Some(_)) => return 0,
+        };
+        let mut data = repair.data.clone();
+        for offset in offsets.filter(|offset| *offset != lost) {
+            xor_into(&mut data, &self.sources[&offset]);
+        }
+        data.truncate(symbol_len.min(block_size - lost));
+        debug!("recover {} bytes at offset {}", data.len(), lost);
+        let len = data.len();
+        self.sources.insert(lost, data);
+        self.repairs.remove(&group);
+        len
+    }
+
+    /// The data of the block from offset 0 up to the first missing byte
+    pub fn contiguous_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (offset, source) in self.sources.iter() {
+            if *offset != data.len() {
+                break;
+            }
+            data.extend_from_slice(source);
+        }
+        data
+    }
+}
+
+fn xor_into(parity: &mut [u8], data: &[u8]) {
+    for (p, d) in parity.iter_mut().zip(data) {
+        *p ^= d;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cut `data` in symbols of `symbol_len`, return the source symbols
+    /// by offset and the repair symbols
+    fn encode(data: &[u8], symbol_len: usize, group: usize) -> (Vec<(usize, Vec<u8>)>, Vec<RepairSymbol>) {
+        let mut encoder = FecEncoder::new(symbol_len, group);
+        let mut sources = Vec::new();
+        let mut repairs = Vec::new();
+        for (i, chunk) in data.chunks(symbol_len).enumerate() {
+            let offset = i * symbol_len;
+            repairs.extend(encoder.push(offset, chunk, offset + chunk.len() == data.len()));
+            sources.push((offset, chunk.to_vec()));
+        }
+        (sources, repairs)
+    }
+
+    #[test]
+    fn group_sizes() {
+        assert_eq!(group_size(0.0), None);
+        assert_eq!(group_size(0.25), Some(4));
+        assert_eq!(group_size(0.3), Some(3));
+        assert_eq!(group_size(2.0), Some(1));
+        assert_eq!(group_size(1e-9), Some(MAX_GROUP_SIZE));
+    }
+
+    #[test]
+    fn recover_one_loss_per_group() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        // 10 symbols of 100 bytes, the last group has 2
+        let (sources, repairs) = encode(&data, 100, 4);
+        assert_eq!(repairs.len(), 3);
+        assert_eq!(repairs[2].group_size, 2);
+
+        let mut decoder = FecDecoder::default();
+        let mut received = 0;
+        // the repair symbol of the first group comes before its sources
+        received += decoder.on_repair(repairs[0].clone(), data.len());
+        for (offset, source) in sources.iter() {
+            // lose one symbol of each group
+            if [100, 700, 900].contains(offset) {
+                continue;
+            }
+            received += decoder.on_source(*offset, source, data.len());
+        }
+        received += decoder.on_repair(repairs[1].clone(), data.len());
+        received += decoder.on_repair(repairs[2].clone(), data.len());
+        assert_eq!(received, data.len());
+        assert_eq!(decoder.contiguous_data(), data);
+        // a late duplicate brings nothing
+        assert_eq!(decoder.on_source(100, &sources[1].1, data.len()), 0);
+    }
+
+    #[test]
+    fn recover_short_last_symbol() {
+        let data: Vec<u8> = (1..=250).collect();
+        let (sources, repairs) = encode(&data, 100, 3);
+        let mut decoder = FecDecoder::default();
+        decoder.on_source(0, &sources[0].1, data.len());
+        decoder.on_source(100, &sources[1].1, data.len());
+        assert_eq!(decoder.on_repair(repairs[0].clone(), data.len()), 50);
+        assert_eq!(decoder.contiguous_data(), data);
+    }
+
+    #[test]
+    fn malformed_repair_symbols() {
+        let mut decoder = FecDecoder::default();
+        let empty = RepairSymbol { offset: 0, group_size: usize::MAX, data: Vec::new() };
+        assert_eq!(decoder.on_repair(empty, usize::MAX), 0);
+        // a group running past the largest offset, 3 symbols are missing
+        let huge = RepairSymbol { offset: usize::MAX - 300, group_size: usize::MAX, data: vec![1; 100] };
+        assert_eq!(decoder.on_repair(huge, usize::MAX), 0);
+        assert_eq!(decoder.on_source(usize::MAX - 200, &[1; 100], usize::MAX), 100);
+        // a group larger than the block
+        let large = RepairSymbol { offset: 0, group_size: usize::MAX, data: vec![1; 100] };
+        assert_eq!(decoder.on_repair(large, 1000), 0);
+    }
+
+    #[test]
+    fn two_losses_are_not_recovered() {
+        let data = vec![7u8; 400];
+        let (sources, repairs) = encode(&data, 100, 4);
+        let mut decoder = FecDecoder::default();
+        decoder.on_source(0, &sources[0].1, data.len());
+        decoder.on_source(300, &sources[3].1, data.len());
+        assert_eq!(decoder.on_repair(repairs[0].clone(), data.len()), 0);
+        // the group is recovered once one of the lost symbols shows up
+        assert_eq!(decoder.on_source(100, &sources[1].1, data.len()), 200);
+        assert_eq!(decoder.contiguous_data(), data);
+    }
+}
@@ -1,26 +1,60 @@
-#[derive(Debug)]
-pub struct BlockInfo {
-    pub id: usize, // should be identical for each block. We can use the stream_id where the block is sent in Stream mode.
-    pub size: usize,
-    pub priority: usize,
-    pub deadline: usize,
-}
-
-#[derive(Debug)]
-pub struct Block {
-    info: BlockInfo,
-    offset: usize,
-    data: Vec<u8>
-}
+//! Frames multiplexing the blocks on a single stream
+//!
+//! Every frame is encoded as
+//!
+//! | type (varint) | payload length (varint) | payload |
+//!
+//! and the payloads are:
+//!
+//! - DTP_CONFIG: | cfg_len (varint) |
+//! - BLOCK_INFO: | id | size | priority | deadline | create_time | (varints)
+//! - BLOCK_DATA: | id (varint) | data |
+//! - BLOCK_DROP: | id (varint) |
+//! - BLOCK_ACK: | id (varint) |
+use crate::block::BlockInfo;
 
+#[allow(non_camel_case_types, clippy::upper_case_acronyms, dead_code)]
 pub enum StreamFrameType {
     NONE = 0x0,
     DTP_CONFIG = 0x1,
     BLOCK_INFO = 0x2,
     BLOCK_DATA = 0x3,
+    BLOCK_DROP = 0x4,
+    BLOCK_ACK = 0x5,
+}
+
+/// The errors of the frame codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer is too short to read or write the frame
+    BufferTooShort,
+    /// The frame type is unknown
+    UnknownFrame(u64),
+    /// The payload length does not match the content of the frame
+    InvalidFrame,
 }
 
-#[derive(Clone, PartialEq, Eq)]
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BufferTooShort => write!(f, "buffer too short"),
+            Error::UnknownFrame(ty) => write!(f, "unknown frame type {:#x}", ty),
+            Error::InvalidFrame => write!(f, "invalid frame"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<octets::BufferTooShortError> for Error {
+    fn from(_: octets::BufferTooShortError) -> Self {
+        Error::BufferTooShort
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum StreamFrame {
     DtpConfig {
         cfg_len: usize, // the number of config blocks
@@ -30,40 +64,249 @@ pub enum StreamFrame {
         size: usize,
         priority: usize,
         deadline: usize,
+        create_time: u64, // the time the block was generated, in microseconds
     },
     BlockData {
         id: usize,
         data: Vec<u8> // data is always continous, so we can keep it in a simple vector
-    }
+    },
+    BlockDrop {
+        id: usize, // the sender gave up on the block
+    },
+    BlockAck {
+        id: usize, // the receiver got the whole block
+    },
 }
 impl StreamFrame {
+    pub fn block_info(info: &BlockInfo, create_time: u64) -> Self {
+        StreamFrame::BlockInfo {
+            id: info.id,
+            size: info.size,
+            priority: info.priority,
+            deadline: info.deadline,
+            create_time,
+        }
+    }
+    /// Decode the payload of a frame whose type and length were already read
     pub fn from_bytes(
-        frame_type: u64, payload_length: u64, bytes: &[u8],
-    ) -> Result<Frame> {
+        frame_type: u64, payload_length: u64, b: &mut octets::Octets,
+    ) -> Result<StreamFrame> {
+        let mut payload = b.get_bytes(payload_length as usize)?;
+        let b = &mut payload;
         let frame = match frame_type {
-            StreamFrameType::DTP_CONFIG => StreamFrame::DtpConfig {
-                cfg_len: b.get_varint()?
+            t if t == StreamFrameType::DTP_CONFIG as u64 => StreamFrame::DtpConfig {
+                cfg_len: b.get_varint()? as usize,
+            },
+            t if t == StreamFrameType::BLOCK_INFO as u64 => StreamFrame::BlockInfo {
+                id: b.get_varint()? as usize,
+                size: b.get_varint()? as usize,
+                priority: b.get_varint()? as usize,
+                deadline: b.get_varint()? as usize,
+                create_time: b.get_varint()?,
             },
-            StreamFrameType::BLOCK_INFO => StreamFrame::BlockInfo {
-                id: b.get_varint()?,
-                size: b.get_varint()?,
-                priority: b.get_varint()?,
-                deadline: b.get_varint()?,
+            t if t == StreamFrameType::BLOCK_DATA as u64 => {
+                let id = b.get_varint()? as usize;
+                let data = b.get_bytes(b.cap())?.to_vec();
+                StreamFrame::BlockData { id, data }
             },
-            _ => return Err(Error::InvalidFrame)
+            t if t == StreamFrameType::BLOCK_DROP as u64 => StreamFrame::BlockDrop {
+                id: b.get_varint()? as usize,
+            },
+            t if t == StreamFrameType::BLOCK_ACK as u64 => StreamFrame::BlockAck {
+                id: b.get_varint()? as usize,
+            },
+            _ => return Err(Error::UnknownFrame(frame_type)),
         };
-        return Ok(frame);
+        // the whole payload must be consumed
+        if b.cap() != 0 {
+            return Err(Error::InvalidFrame);
+        }
+        Ok(frame)
     }
+    /// Encode the frame, return the number of bytes written
     pub fn to_bytes(&self, b: &mut octets::OctetsMut) -> Result<usize> {
         let before = b.cap();
+        b.put_varint(self.frame_type())?;
+        b.put_varint(self.payload_len() as u64)?;
         match self {
             StreamFrame::DtpConfig { cfg_len } => {
-                b.put_varint(DATA_FRAME_TYPE_ID)?;
-                b.put_varint(payload.len() as u64)?;
-
-                b.put_bytes(payload.as_ref())?;
+                b.put_varint(*cfg_len as u64)?;
+            },
+            StreamFrame::BlockInfo { id, size, priority, deadline, create_time } => {
+                b.put_varint(*id as u64)?;
+                b.put_varint(*size as u64)?;
+                b.put_varint(*priority as u64)?;
+                b.put_varint(*deadline as u64)?;
+                b.put_varint(*create_time)?;
+            },
+            StreamFrame::BlockData { id, data } => {
+                b.put_varint(*id as u64)?;
+                b.put_bytes(data)?;
+            },
+            StreamFrame::BlockDrop { id } | StreamFrame::BlockAck { id } => {
+                b.put_varint(*id as u64)?;
             },
         }
         Ok(before - b.cap())
     }
-}
\ No newline at end of file
+    /// Encode the frame into a new vector
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.wire_len()];
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+        self.to_bytes(&mut b).unwrap();
+        buf
+    }
+    /// The number of bytes `to_bytes` writes
+    pub fn wire_len(&self) -> usize {
+        let payload_len = self.payload_len();
+        octets::varint_len(self.frame_type())
+            + octets::varint_len(payload_len as u64)
+            + payload_len
+    }
+    fn frame_type(&self) -> u64 {
+        let ty = match self {
+            StreamFrame::DtpConfig { .. } => StreamFrameType::DTP_CONFIG,
+            StreamFrame::BlockInfo { .. } => StreamFrameType::BLOCK_INFO,
+            StreamFrame::BlockData { .. } => StreamFrameType::BLOCK_DATA,
+            StreamFrame::BlockDrop { .. } => StreamFrameType::BLOCK_DROP,
+            StreamFrame::BlockAck { .. } => StreamFrameType::BLOCK_ACK,
+        };
+        ty as u64
+    }
+    fn payload_len(&self) -> usize {
+        let len = |v: usize| octets::varint_len(v as u64);
+        match self {
+            StreamFrame::DtpConfig { cfg_len } => len(*cfg_len),
+            StreamFrame::BlockInfo { id, size, priority, deadline, create_time } => {
+                len(*id) + len(*size) + len(*priority) + len(*deadline)
+                    + octets::varint_len(*create_time)
+            },
+            StreamFrame::BlockData { id, data } => len(*id) + data.len(),
+            StreamFrame::BlockDrop { id } | StreamFrame::BlockAck { id } => len(*id),
+        }
+    }
+}
+
+/// Decode frames from a stream whose data comes in arbitrary pieces, as
+/// returned by `stream_recv`
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Append data read from the stream
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Return the next complete frame, None if more data is needed
+    pub fn next_frame(&mut self) -> Result<Option<StreamFrame>> {
+        let mut b = octets::Octets::with_slice(&self.buf);
+        let (frame_type, payload_length) = match (b.get_varint(), b.get_varint()) {
+            (Ok(t), Ok(l)) => (t, l),
+            _ => return Ok(None),
+        };
+        if b.cap() < payload_length as usize {
+            return Ok(None);
+        }
+        let frame = StreamFrame::from_bytes(frame_type, payload_length, &mut b)?;
+        let consumed = b.off();
+        self.buf.drain(..consumed);
+        Ok(Some(frame))
+    }
+
+    /// The number of bytes waiting for the rest of their frame
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> Vec<StreamFrame> {
+        vec![
+            StreamFrame::DtpConfig { cfg_len: 1063 },
+            StreamFrame::BlockInfo {
+                id: 7,
+                size: 288555,
+                priority: 2,
+                deadline: 200,
+                create_time: 1_678_400_000_000_000,
+            },
+            StreamFrame::BlockData { id: 7, data: (0..=255).collect() },
+            StreamFrame::BlockData { id: 8, data: Vec::new() },
+            StreamFrame::BlockDrop { id: 16384 },
+            StreamFrame::BlockAck { id: 7 },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for frame in frames() {
+            let mut buf = [0; 512];
+            let mut b = octets::OctetsMut::with_slice(&mut buf);
+            let written = frame.to_bytes(&mut b).unwrap();
+            assert_eq!(written, frame.wire_len());
+
+            let mut b = octets::Octets::with_slice(&buf[..written]);
+            let frame_type = b.get_varint().unwrap();
+            let payload_length = b.get_varint().unwrap();
+            let decoded = StreamFrame::from_bytes(frame_type, payload_length, &mut b).unwrap();
+            assert_eq!(decoded, frame);
+            assert_eq!(b.cap(), 0);
+        }
+    }
+
+    #[test]
+    fn buffer_too_short() {
+        let frame = StreamFrame::BlockData { id: 1, data: vec![0; 100] };
+        let mut buf = [0; 50];
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+        assert_eq!(frame.to_bytes(&mut b), Err(Error::BufferTooShort));
+
+        let encoded = frame.to_vec();
+        let mut b = octets::Octets::with_slice(&encoded[..60]);
+        let frame_type = b.get_varint().unwrap();
+        let payload_length = b.get_varint().unwrap();
+        assert_eq!(
+            StreamFrame::from_bytes(frame_type, payload_length, &mut b),
+            Err(Error::BufferTooShort)
+        );
+    }
+
+    #[test]
+    fn invalid_frames() {
+        // unknown type
+        let mut b = octets::Octets::with_slice(&[0]);
+        assert_eq!(StreamFrame::from_bytes(0x9, 1, &mut b), Err(Error::UnknownFrame(0x9)));
+        // a DTP_CONFIG frame with a trailing byte
+        let mut b = octets::Octets::with_slice(&[5, 0]);
+        assert_eq!(
+            StreamFrame::from_bytes(StreamFrameType::DTP_CONFIG as u64, 2, &mut b),
+            Err(Error::InvalidFrame)
+        );
+    }
+
+    #[test]
+    fn streaming_decoder() {
+        let mut stream = Vec::new();
+        for frame in frames() {
+            stream.extend_from_slice(&frame.to_vec());
+        }
+
+        // feed the stream a few bytes at a time
+        let mut decoder = FrameDecoder::default();
+        let mut decoded = Vec::new();
+        for piece in stream.chunks(3) {
+            decoder.push(piece);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames());
+        assert_eq!(decoder.pending(), 0);
+    }
+}
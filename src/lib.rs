@@ -0,0 +1,302 @@
+//! Send blocks with priorities and deadlines over QUIC
+//!
+//! `DtpServer` and `DtpClient` run in the calloop event loop of the
+//! application: the server sends the blocks given to `send_block`, or
+//! replays a trace, and the client hands the completed blocks to its
+//! `on_block_received` callback. Both report what happens to the blocks
+//! through the callbacks of `events`. The failures of the socket stop them
+//! with an `Error`, those of one connection only close it. The binary runs
+//! them from the command line.
+#[macro_use]
+extern crate log;
+
+pub mod block;
+pub mod cert;
+pub mod client;
+pub mod datagram;
+pub mod emulator;
+pub mod error;
+pub mod events;
+pub mod fec;
+pub mod frame;
+pub mod quic;
+pub mod receiver;
+pub mod scheduler;
+pub mod sender;
+pub mod server;
+pub mod simulation;
+pub mod tcp;
+pub mod token;
+
+pub use client::{DtpClient, DtpClientBuilder};
+pub use error::Error;
+pub use quic::QuicOptions;
+pub use server::{ConnectionReport, DtpServer, DtpServerBuilder};
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+    use calloop::EventLoop;
+    use crate::block::BlockInfo;
+    use crate::quic::{load_certificate, set_quiche_conn_config, QuicOptions, MAX_DATAGRAM_SIZE};
+    use crate::sender::TransportMode;
+    use super::*;
+
+    #[test]
+    fn embedded_server_and_client() {
+        for mode in [TransportMode::Stream, TransportMode::Framed, TransportMode::Datagram] {
+            let received = RefCell::new(Vec::new());
+            let generated = RefCell::new(Vec::new());
+            let sent = RefCell::new(Vec::new());
+            let acked = RefCell::new(Vec::new());
+            let mut event_loop: EventLoop<()> = EventLoop::try_new().unwrap();
+            let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+            let server = DtpServer::builder(localhost)
+                .transport_mode(mode)
+                .on_block_generated(|event| generated.borrow_mut().push(event.info.id))
+                .on_block_sent(|event| sent.borrow_mut().push(event.info.id))
+                .on_block_acked(|event| {
+                    assert!(event.time >= event.create_time);
+                    acked.borrow_mut().push(event.info.id)
+                })
+                .build(&event_loop.handle())
+                .unwrap();
+            let client = DtpClient::builder(server.local_addr())
+                .bind(localhost)
+                .on_block_received(|event, data| received.borrow_mut().push((event.info.id, data)))
+                .build(&event_loop.handle())
+                .unwrap();
+
+            let blocks: Vec<Vec<u8>> = (0..3).map(|id| vec![id as u8; 5000 * (id + 1)]).collect();
+            for (id, data) in blocks.iter().enumerate() {
+                let info = BlockInfo { id, size: data.len(), priority: 1, deadline: 1000 };
+                server.send_block(info, data.clone()).unwrap();
+            }
+            let bad = BlockInfo { id: 3, size: 10, priority: 1, deadline: 1000 };
+            assert!(server.send_block(bad, vec![0; 9]).is_err());
+
+            let start = Instant::now();
+            while acked.borrow().len() < blocks.len() && start.elapsed() < Duration::from_secs(5) {
+                event_loop.dispatch(Duration::from_millis(10), &mut ()).unwrap();
+            }
+            let mut received = received.take();
+            received.sort();
+            assert_eq!(received, blocks.into_iter().enumerate().collect::<Vec<_>>(), "{:?}", mode);
+            assert!(!client.is_closed());
+            assert_eq!(generated.take(), vec![0, 1, 2]);
+            let mut sent = sent.take();
+            sent.sort();
+            assert_eq!(sent, vec![0, 1, 2], "{:?}", mode);
+            let mut acked = acked.take();
+            acked.sort();
+            assert_eq!(acked, vec![0, 1, 2], "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn large_datagram_blocks() {
+        // larger than the default receive buffer, datagrams are never retransmitted
+        let rmem_max = std::fs::read_to_string("/proc/sys/net/core/rmem_max").unwrap_or_default();
+        if rmem_max.trim().parse::<usize>().map_or(true, |max| max < crate::quic::RECV_BUFFER_SIZE) {
+            eprintln!("net.core.rmem_max {} caps the receive buffer, skip", rmem_max.trim());
+            return;
+        }
+        let received = RefCell::new(Vec::new());
+        let mut event_loop: EventLoop<()> = EventLoop::try_new().unwrap();
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = DtpServer::builder(localhost)
+            .transport_mode(TransportMode::Datagram)
+            .build(&event_loop.handle())
+            .unwrap();
+        let client = DtpClient::builder(server.local_addr())
+            .bind(localhost)
+            .on_block_received(|event, _| received.borrow_mut().push(event.info.id))
+            .build(&event_loop.handle())
+            .unwrap();
+        for id in 0..4 {
+            let info = BlockInfo { id, size: 1_000_000, priority: 1, deadline: 5000 };
+            server.send_block(info, vec![id as u8; info.size]).unwrap();
+        }
+
+        let start = Instant::now();
+        while received.borrow().len() < 4 && start.elapsed() < Duration::from_secs(10) {
+            event_loop.dispatch(Duration::from_millis(10), &mut ()).unwrap();
+        }
+        let mut received = received.take();
+        received.sort();
+        assert_eq!(received, vec![0, 1, 2, 3]);
+        assert_eq!(client.receiver().summary().complete, 4);
+    }
+
+    #[test]
+    fn every_client_gets_the_trace() {
+        let trace: Vec<_> = (0..5)
+            .map(|i| dtp_utils::dtp_config { deadline: 1000, priority: 1, block_size: 3000 * (i + 1), send_time_gap: 0.01 })
+            .collect();
+        let mut event_loop: EventLoop<()> = EventLoop::try_new().unwrap();
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = DtpServer::builder(localhost)
+            .trace(trace)
+            .finish_after(2)
+            .build(&event_loop.handle())
+            .unwrap();
+        let clients: Vec<_> = (0..2)
+            .map(|_| DtpClient::builder(server.local_addr()).bind(localhost).build(&event_loop.handle()).unwrap())
+            .collect();
+
+        let start = Instant::now();
+        let done = || server.reports().len() == clients.len() && clients.iter().all(|c| c.is_closed());
+        while !done() && start.elapsed() < Duration::from_secs(5) {
+            event_loop.dispatch(Duration::from_millis(10), &mut ()).unwrap();
+        }
+        for client in clients.iter() {
+            assert!(client.is_closed());
+            assert_eq!(client.receiver().summary().complete, 5);
+        }
+        let reports = server.reports();
+        assert_eq!(reports.len(), 2);
+        let mut peers: Vec<_> = reports.iter().map(|r| r.peer).collect();
+        peers.sort();
+        let mut addrs: Vec<_> = clients.iter().map(|c| c.local_addr()).collect();
+        addrs.sort();
+        assert_eq!(peers, addrs);
+        for report in reports {
+            assert_eq!((report.generated, report.sent, report.acked), (5, 5, 5), "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn callbacks_call_the_server() {
+        let received = RefCell::new(Vec::new());
+        let mut event_loop: EventLoop<()> = EventLoop::try_new().unwrap();
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        // the server does not exist yet when its callbacks are given
+        let slot: Rc<RefCell<Option<DtpServer>>> = Rc::default();
+        let weak = Rc::downgrade(&slot);
+        let server = DtpServer::builder(localhost)
+            .on_block_acked(move |event| {
+                let slot = weak.upgrade().unwrap();
+                let slot = slot.borrow();
+                let server = slot.as_ref().unwrap();
+                assert!(server.take_error().is_none());
+                // queue the next block once the previous one is acknowledged
+                let id = event.info.id + 1;
+                if id < 3 {
+                    let info = BlockInfo { id, size: 100, priority: 1, deadline: 1000 };
+                    server.send_block(info, vec![id as u8; 100]).unwrap();
+                }
+            })
+            .build(&event_loop.handle())
+            .unwrap();
+        let client = DtpClient::builder(server.local_addr())
+            .bind(localhost)
+            .on_block_received(|event, _data| received.borrow_mut().push(event.info.id))
+            .build(&event_loop.handle())
+            .unwrap();
+        server.send_block(BlockInfo { id: 0, size: 100, priority: 1, deadline: 1000 }, vec![0; 100]).unwrap();
+        *slot.borrow_mut() = Some(server);
+
+        let start = Instant::now();
+        while received.borrow().len() < 3 && start.elapsed() < Duration::from_secs(5) {
+            event_loop.dispatch(Duration::from_millis(10), &mut ()).unwrap();
+        }
+        assert_eq!(received.take(), vec![0, 1, 2]);
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn without_retry() {
+        let received = RefCell::new(Vec::new());
+        let mut event_loop: EventLoop<()> = EventLoop::try_new().unwrap();
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = DtpServer::builder(localhost).retry(false).build(&event_loop.handle()).unwrap();
+        let _client = DtpClient::builder(server.local_addr())
+            .bind(localhost)
+            .on_block_received(|event, data| received.borrow_mut().push((event.info.id, data)))
+            .build(&event_loop.handle())
+            .unwrap();
+        let info = BlockInfo { id: 0, size: 3, priority: 1, deadline: 1000 };
+        server.send_block(info, vec![1, 2, 3]).unwrap();
+
+        let start = Instant::now();
+        while received.borrow().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            event_loop.dispatch(Duration::from_millis(10), &mut ()).unwrap();
+        }
+        assert_eq!(received.take(), vec![(0, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn unreachable_server() {
+        let mut event_loop: EventLoop<()> = EventLoop::try_new().unwrap();
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        // a bound socket nobody reads from
+        let silent = std::net::UdpSocket::bind(localhost).unwrap();
+        let client = DtpClient::builder(silent.local_addr().unwrap())
+            .bind(localhost)
+            .quic_options(QuicOptions { idle_timeout: 200, ..Default::default() })
+            .build(&event_loop.handle())
+            .unwrap();
+
+        let start = Instant::now();
+        while !client.is_closed() && start.elapsed() < Duration::from_secs(5) {
+            event_loop.dispatch(Duration::from_millis(10), &mut ()).unwrap();
+        }
+        match client.take_error() {
+            Some(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    /// A client and a server connection that completed the handshake in memory
+    pub fn connected_pair() -> (quiche::Connection, quiche::Connection) {
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 1234));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 4321));
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+        let options = QuicOptions::default();
+        set_quiche_conn_config(&mut config, &options).unwrap();
+        load_certificate(&mut config, &options).unwrap();
+        config.verify_peer(false);
+
+        let client_scid = [0xc1; quiche::MAX_CONN_ID_LEN];
+        let server_scid = [0x5e; quiche::MAX_CONN_ID_LEN];
+        let mut client = quiche::connect(
+            Some("quic.test"),
+            &quiche::ConnectionId::from_ref(&client_scid),
+            client_addr,
+            server_addr,
+            &mut config,
+        ).unwrap();
+        let mut server = quiche::accept(
+            &quiche::ConnectionId::from_ref(&server_scid),
+            None,
+            server_addr,
+            client_addr,
+            &mut config,
+        ).unwrap();
+        while !client.is_established() || !server.is_established() {
+            exchange(&mut client, &mut server);
+        }
+        (client, server)
+    }
+
+    /// Deliver the packets of both connections to each other until neither
+    /// has anything left to send
+    pub fn exchange(a: &mut quiche::Connection, b: &mut quiche::Connection) {
+        while deliver(a, b) + deliver(b, a) > 0 {}
+    }
+
+    /// Deliver the packets of `from` to `to`, return the number of packets
+    fn deliver(from: &mut quiche::Connection, to: &mut quiche::Connection) -> usize {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let mut packets = 0;
+        while let Ok((len, send_info)) = from.send(&mut buf) {
+            let recv_info = quiche::RecvInfo { from: send_info.from, to: send_info.to };
+            to.recv(&mut buf[..len], recv_info).unwrap();
+            packets += 1;
+        }
+        packets
+    }
+}
@@ -1,865 +1,376 @@
 #[macro_use]
 extern crate log;
-use std::{error::Error, net::UdpSocket};
-use calloop::{timer::{Timer, TimeoutAction}, EventLoop, LoopSignal, LoopHandle, RegistrationToken};
-use calloop::{generic::Generic, Interest, Mode, PostAction, Dispatcher};
-use std::{collections::{VecDeque, HashMap}, hash::Hash, net::TcpStream, net::TcpListener, io::Write};
-use anyhow::{anyhow, Result};
-use rand::Rng;
-use std::time::{Instant, Duration};
 use std::net::SocketAddr;
-use quiche::*;
-use ring::rand::*;
-use std::net;
-use crate::sender::{SenderDeque, BlockGenerator};
-
-struct PartialResponse {
-    body: Vec<u8>,
-
-    written: usize,
-}
-
-struct Client {
-    conn: quiche::Connection,
-
-    partial_responses: HashMap<u64, PartialResponse>,
-}
-
-type ClientMap = HashMap<quiche::ConnectionId<'static>, Client>;
-
-const MAX_DATAGRAM_SIZE: usize = 1350;
-const HTTP_REQ_STREAM_ID: u64 = 64;
-const IDLE_TIMEOUT: u64 = 5;
-
-fn generate_cb(event: Instant, _metadata: &mut (), shared_data: &mut ServerGlobalData) -> TimeoutAction {
-    // This callback is given 3 values:
-    // - the event generated by the source (in our case, timer events are the Instant
-    //   representing the deadline for which it has fired)
-    // - &mut access to some metadata, specific to the event source (in our case, a
-    //   timer handle)
-    // - &mut access to the global shared data that was passed to EventLoop::run or
-    //   EventLoop::dispatch (in our case, a LoopSignal object to stop the loop)
-    //
-    // The return type is just () because nothing uses it. Some
-    // sources will expect a Result of some kind instead.
-    trace!("Timeout for {:?} expired!", event);
-    let next_gap = shared_data.block_generator.generate_once(&mut shared_data.sender_queue);
-
-    // The timer event source requires us to return a TimeoutAction to
-    // specify if the timer should be rescheduled. In our case we just drop it.
-    if next_gap.is_some() {
-        return TimeoutAction::ToDuration(std::time::Duration::from_secs_f32(next_gap.unwrap()));
-    } else {
-        // notify the event loop to stop running using the signal in the shared data
-        // (see below)
-        // TODO: remove when implement the whole system
-        shared_data.signal.as_ref().unwrap().stop();
-        return TimeoutAction::Drop;
-    }
-}
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use calloop::EventLoop;
+use dtp_utils::get_dtp_config;
+use rust_callback_test::{cert, emulator, simulation, tcp};
+use rust_callback_test::{DtpClient, DtpClientBuilder, DtpServer, DtpServerBuilder, QuicOptions};
+use rust_callback_test::scheduler::{DtpCoefficients, SchedulerConfig, new_scheduler};
+use rust_callback_test::sender::TransportMode;
 
 const USAGE: &str = "Usage:
-server [options] CONFIG
+server loopback [options] CONFIG
+server server [options] CONFIG
+server client [options]
+server gen-cert [options]
 server -h | --help
+
+Commands:
+loopback                 Run the server and the client in one process.
+server                   Only run the server, sending the trace of CONFIG to the client.
+client                   Only run the client, connecting to the server at --peer.
+gen-cert                 Write a new self-signed certificate and its key to --cert and --key.
+
 Options:
 -h --help                Show this screen.
+--listen=<addr>          Address the server listens on [default: 127.0.0.1:7736].
+--peer=<addr>            Address of the server the client connects to [default: 127.0.0.1:7736].
+--bind=<addr>            Local address of the client [default: 0.0.0.0:0].
+--cert=<file>            Certificate chain of the server, in PEM [default: cert.crt].
+--key=<file>             Private key of the server, in PEM [default: cert.key].
+--ca=<file>              The client verifies the certificate of the server against the CA
+                         certificates of <file>, in PEM, such as the --cert of gen-cert.
+--server-name=<name>     Name the client expects in the certificate of the server, the address
+                         of --peer by default.
+--names=<list>           gen-cert: the DNS names and IP addresses of the certificate
+                         [default: localhost,127.0.0.1].
+--days=<n>               gen-cert: validity of the certificate [default: 365].
+--force                  gen-cert: overwrite existing files.
+--alpn=<list>            Application protocols offered in the QUIC handshake
+                         [default: hq-interop,hq-29,hq-28,hq-27,http/0.9].
+--idle-timeout=<ms>      Close the QUIC connection after <ms> without packets [default: 5000].
+--no-retry               Accept the connections without validating the address of the client
+                         with a stateless retry first.
+-c --clients=<n>         The server exits once <n> clients received the trace, each on its own
+                         connection; loopback runs <n> clients at once [default: 1].
+-m --mode=<mode>         How the blocks are sent: stream (one QUIC stream per block),
+                         framed (all the blocks on one stream), datagram (QUIC DATAGRAM
+                         frames, without retransmission), tcp (one TCP connection per
+                         block) or tcp-framed (all the blocks on one TCP connection)
+                         [default: stream].
+-r --report=<file>       Write the per-block report of the client to <file> instead of stdout,
+                         with more than one client to <file>.0, <file>.1...
+-s --scheduler=<name>    Block scheduler of the server: fifo, edf, priority, wfq, dtp, plugin [default: fifo].
+-w --weights=<list>      Weights of priority 1, 2, 3 for the wfq scheduler [default: 1,2,3].
+--dtp-coef=<list>        Weights of the priority, deadline and size terms and the late
+                         penalty of the dtp scheduler [default: 1,1,1,10].
+--fec=<list>             Redundancy ratio (repair symbols per piece) of priority 1, 2, 3
+                         in datagram mode, 0 disables FEC [default: 0,0,0].
+--plugin=<path>          Shared object of the plugin scheduler, see dtp_utils/include/solution.h.
+--keep-expired           Keep sending the blocks whose deadline passes mid-transfer instead of
+                         resetting their QUIC stream (stream mode only).
+--simulate               Loopback only: model the scheduler of the framed mode (--mode framed) on a
+                         virtual clock over the emulated link, with a simplified transport in place
+                         of QUIC, see src/simulation.rs.
+
+Emulated link, both directions (loopback with the QUIC modes or --simulate):
+--bandwidth=<mbps>       Bandwidth in Mbit/s, 0 for unlimited [default: 0].
+--delay=<ms>             One-way delay [default: 0].
+--jitter=<ms>            The delay varies uniformly by up to <ms> [default: 0].
+--loss=<rate>            Random loss rate [default: 0].
+--burst-loss=<p,q>       Gilbert-Elliott burst loss: the probability to enter and to
+                         leave the state where every packet is lost.
+--reorder=<rate>         Rate of packets skipping the delay, overtaking the previous ones [default: 0].
+--queue=<bytes>          Queue size in front of the bottleneck, 0 for unlimited [default: 0].
+--seed=<n>               Seed of the random impairments [default: 0].
+--uplink-trace=<file>    Capacity of the link from the client over time instead of a
+                         constant bandwidth: a Mahimahi trace, or time (ms),bandwidth (Mbit/s) lines.
+--downlink-trace=<file>  Capacity of the link to the client over time, in the same formats.
 ";
-use dtp_utils::*;
-#[derive(Default)]
-struct ServerGlobalData<'a> {
-    sender_queue: SenderDeque,
-    block_generator: BlockGenerator,
-    
-    socket: Option<UdpSocket>,
-    clients: Option<ClientMap>,
-    conn_id_seed: Option<ring::hmac::Key>,
-    local_addr: Option<SocketAddr>,
-    config: Option<quiche::Config>,
-
-    handle: Option<LoopHandle<'a, ServerGlobalData<'a>>>,
-    timeout_dispatcher: Option<Dispatcher<'a, Timer, ServerGlobalData<'a>>>,
-    timeout_token: Option<RegistrationToken>,
-    signal: Option<LoopSignal>
-}
-#[derive(Default)]
-struct ClientGlobalData<'b> {
-    scid: Option<ConnectionId<'b>>,
-    local_addr: Option<SocketAddr>,
-    peer_addr: Option<SocketAddr>, 
-    config: Option<quiche::Config>,
-    req_start: Option<Instant>,
-    req_sent: bool,
-
-
-    conn: Option<quiche::Connection>,
-    socket: Option<UdpSocket>,
-
-    handle: Option<LoopHandle<'b, ClientGlobalData<'b>>>,
-    timeout_dispatcher: Option<Dispatcher<'b, Timer, ClientGlobalData<'b>>>,
-    timeout_token: Option<RegistrationToken>,
-    signal: Option<LoopSignal>
-}
-
-fn server_recv_cb(
-    _readiness: calloop::Readiness, 
-    io_object: &mut UdpSocket, 
-    shared_data: &mut ServerGlobalData
-) -> Result<calloop::PostAction, std::io::Error>{
-    // The first argument of the callback is a Readiness
-    // The second is a &mut reference to your object
-    let clients = shared_data.clients.as_mut().unwrap();
-    let socket = io_object;
-    let conn_id_seed = shared_data.conn_id_seed.as_ref().unwrap();
-    let local_addr = shared_data.local_addr.as_ref().unwrap();
-    let config = shared_data.config.as_mut().unwrap();
-
-    let mut buf = [0; 65535];
-    let mut out = [0; MAX_DATAGRAM_SIZE];
-
-    'read: loop {
-        // If the event loop reported no events, it means that the timeout
-        // has expired, so handle it without attempting to read packets. We
-        // will then proceed with the send loop.
-        let (len, from) = match socket.recv_from(&mut buf) {
-            Ok(v) => v,
-
-            Err(e) => {
-                // There are no more UDP packets to read, so end the read
-                // loop.
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    debug!("recv() would block");
-                    break 'read;
-                }
-
-                panic!("recv() failed: {:?}", e);
-            },
-        };
-
-        debug!("server got {} bytes", len);
-
-        let pkt_buf = &mut buf[..len];
-
-        // Parse the QUIC packet's header.
-        let hdr = match quiche::Header::from_slice(
-            pkt_buf,
-            quiche::MAX_CONN_ID_LEN,
-        ) {
-            Ok(v) => v,
-
-            Err(e) => {
-                error!("Parsing packet header failed: {:?}", e);
-                continue 'read;
-            },
-        };
-
-        trace!("server got packet {:?}", hdr);
-
-        let conn_id = ring::hmac::sign(&conn_id_seed, &hdr.dcid);
-        let conn_id = &conn_id.as_ref()[..quiche::MAX_CONN_ID_LEN];
-        let conn_id = conn_id.to_vec().into();
-
-        // Lookup a connection based on the packet's connection ID. If there
-        // is no connection matching, create a new one.
-        let client = if !clients.contains_key(&hdr.dcid) &&
-            !clients.contains_key(&conn_id)
-        {
-            if hdr.ty != quiche::Type::Initial {
-                error!("Packet is not Initial");
-                continue 'read;
-            }
-
-            if !quiche::version_is_supported(hdr.version) {
-                warn!("Doing version negotiation");
-
-                let len =
-                    quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut out)
-                        .unwrap();
-
-                let out = &out[..len];
-
-                if let Err(e) = socket.send_to(out, from) {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        debug!("send() would block");
-                        break;
-                    }
-
-                    panic!("send() failed: {:?}", e);
-                }
-                continue 'read;
-            }
-
-            let mut scid = [0; quiche::MAX_CONN_ID_LEN];
-            scid.copy_from_slice(&conn_id);
-
-            let scid = quiche::ConnectionId::from_ref(&scid);
-
-            // Token is always present in Initial packets.
-            let token = hdr.token.as_ref().unwrap();
-
-            // Do stateless retry if the client didn't send a token.
-            if token.is_empty() {
-                warn!("Doing stateless retry");
-
-                let new_token = mint_token(&hdr, &from);
-
-                let len = quiche::retry(
-                    &hdr.scid,
-                    &hdr.dcid,
-                    &scid,
-                    &new_token,
-                    hdr.version,
-                    &mut out,
-                )
-                .unwrap();
-
-                let out = &out[..len];
-
-                if let Err(e) = socket.send_to(out, from) {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        debug!("send() would block");
-                        break;
-                    }
-
-                    panic!("send() failed: {:?}", e);
-                }
-                continue 'read;
-            }
-
-            let odcid = validate_token(&from, token);
-
-            // The token was not valid, meaning the retry failed, so
-            // drop the packet.
-            if odcid.is_none() {
-                error!("Invalid address validation token");
-                continue 'read;
-            }
-
-            if scid.len() != hdr.dcid.len() {
-                error!("Invalid destination connection ID");
-                continue 'read;
-            }
-
-            // Reuse the source connection ID we sent in the Retry packet,
-            // instead of changing it again.
-            let scid = hdr.dcid.clone();
-
-            debug!("New connection: dcid={:?} scid={:?}", hdr.dcid, scid);
-
-            let conn = quiche::accept(
-                &scid,
-                odcid.as_ref(),
-                *local_addr,
-                from,
-                config,
-            )
-            .unwrap();
-
-            let client = Client {
-                conn,
-                partial_responses: HashMap::new(),
-            };
 
-            clients.insert(scid.clone(), client);
-
-            clients.get_mut(&scid).unwrap()
-        } else {
-            match clients.get_mut(&hdr.dcid) {
-                Some(v) => v,
-
-                None => clients.get_mut(&conn_id).unwrap(),
-            }
-        };
-
-        let recv_info = quiche::RecvInfo {
-            to: socket.local_addr().unwrap(),
-            from,
-        };
-
-        // Process potentially coalesced packets.
-        let read = match client.conn.recv(pkt_buf, recv_info) {
-            Ok(v) => v,
-
-            Err(e) => {
-                error!("{} recv failed: {:?}", client.conn.trace_id(), e);
-                continue 'read;
-            },
-        };
-
-        debug!("{} processed {} bytes", client.conn.trace_id(), read);
-
-        if client.conn.is_in_early_data() || client.conn.is_established() {
-            // Handle writable streams.
-            for stream_id in client.conn.writable() {
-                handle_writable(client, stream_id);
-            }
-
-            // Process all readable streams.
-            for s in client.conn.readable() {
-                while let Ok((read, fin)) =
-                    client.conn.stream_recv(s, &mut buf)
-                {
-                    debug!(
-                        "{} received {} bytes",
-                        client.conn.trace_id(),
-                        read
-                    );
-
-                    let stream_buf = &buf[..read];
-
-                    debug!(
-                        "{} stream {} has {} bytes (fin? {})",
-                        client.conn.trace_id(),
-                        s,
-                        stream_buf.len(),
-                        fin
-                    );
-
-                    handle_stream(client, s, stream_buf, "examples/root");
-                }
-            }
-        }
+fn main() -> Result<()> {
+    // parse args
+    let args = docopt::Docopt::new(USAGE)
+        .and_then(|dopt| dopt.parse())
+        .unwrap_or_else(|e| e.exit());
+    // init logger
+    env_logger::init();
+    if args.get_bool("gen-cert") {
+        return gen_cert(&args);
     }
+    let cfg_path = args.get_str("CONFIG").to_owned();
+    let scheduler = args.get_str("--scheduler").to_owned();
+    let dtp_coef = parse_float_list(args.get_str("--dtp-coef"))?;
+    if dtp_coef.len() != 4 {
+        return Err(anyhow!("--dtp-coef expects 4 numbers, got {:?}", dtp_coef));
+    }
+    let scheduler_config = SchedulerConfig {
+        priority_weights: parse_float_list(args.get_str("--weights"))?,
+        dtp_coefficients: DtpCoefficients {
+            priority: dtp_coef[0],
+            deadline: dtp_coef[1],
+            size: dtp_coef[2],
+            late_penalty: dtp_coef[3],
+        },
+        plugin_path: match args.get_str("--plugin") {
+            "" => None,
+            path => Some(path.to_owned()),
+        },
+    };
+    let transport_mode: TransportMode = args.get_str("--mode").parse()?;
+    let reset_expired = !args.get_bool("--keep-expired");
+    let fec_ratios = parse_float_list(args.get_str("--fec"))?;
+    let report_path = match args.get_str("--report") {
+        "" => None,
+        path => Some(path.to_owned()),
+    };
+    let clients: usize = args.get_str("--clients").parse()
+        .map_err(|e| anyhow!("invalid --clients {:?}: {}", args.get_str("--clients"), e))?;
+    if clients == 0 {
+        return Err(anyhow!("--clients must be at least 1"));
+    }
+    // let cfg_path = "aitrans_block.txt";
 
-    collect_garbage_connection(clients);
-
-    server_flush_quic_packets(clients, socket).unwrap();
-
-    let timer_dispatcher= shared_data.timeout_dispatcher.as_ref().unwrap();
-    let handle = shared_data.handle.as_ref().unwrap();
-    let timer_token = shared_data.timeout_token.as_ref().unwrap();
-    update_timer_outside_cb(clients, timer_dispatcher, handle, timer_token);
-    
-    // your callback needs to return a Result<PostAction, std::io::Error>
-    // if it returns an error, the event loop will consider this event
-    // event source as erroring and report it to the user.
-    Ok(PostAction::Continue)
-}
-
-/// Generate outgoing QUIC packets for all active connections and send
-/// them on the UDP socket, until quiche reports that there are no more
-/// packets to be sent.
-fn server_flush_quic_packets(clients: &mut ClientMap, socket: &mut UdpSocket) -> Result<()> {
-    let mut out = [0; MAX_DATAGRAM_SIZE];
-    for client in clients.values_mut() {
-        loop {
-            let (write, send_info) = match client.conn.send(&mut out) {
-                Ok(v) => v,
-
-                Err(quiche::Error::Done) => {
-                    debug!("{} done writing", client.conn.trace_id());
-                    break;
-                },
-
-                Err(e) => {
-                    error!("{} send failed: {:?}", client.conn.trace_id(), e);
-
-                    client.conn.close(false, 0x1, b"fail").ok();
-                    break;
-                },
-            };
-
-            if let Err(e) = socket.send_to(&out[..write], send_info.to) {
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    debug!("send() would block");
-                    break;
-                }
-
-                panic!("send() failed: {:?}", e);
-            }
+    let link_config = parse_link_config(&args)?;
+    let load_trace = |name: &str| match args.get_str(name) {
+        "" => Ok(None),
+        path => emulator::CapacityTrace::load(path).map(Some),
+    };
+    let uplink = emulator::LinkConfig {
+        capacity: load_trace("--uplink-trace")?,
+        ..link_config.clone()
+    };
+    // the downlink draws different random numbers
+    let downlink = emulator::LinkConfig {
+        capacity: load_trace("--downlink-trace")?,
+        seed: link_config.seed.wrapping_add(1),
+        ..link_config
+    };
 
-            debug!("{} written {} bytes", client.conn.trace_id(), write);
-        }
+    let loopback = !args.get_bool("server") && !args.get_bool("client");
+    if !loopback && (!uplink.is_ideal() || !downlink.is_ideal() || args.get_bool("--simulate")) {
+        return Err(anyhow!("the emulated link and the simulation only run in loopback mode"));
     }
-    Ok(())
-}
 
-// Garbage collect closed connections.
-fn collect_garbage_connection(clients: &mut ClientMap) {
-    clients.retain(|_, ref mut c| {
-        debug!("Server Collecting garbage");
-
-        if c.conn.is_closed() {
-            info!(
-                "{} connection collected {:?}",
-                c.conn.trace_id(),
-                c.conn.stats()
-            );
+    if args.get_bool("--simulate") {
+        if transport_mode != TransportMode::Framed {
+            return Err(anyhow!("--simulate only models the framed mode, use --mode framed"));
         }
-
-        !c.conn.is_closed()
-    });
-}
-
-/// update timer dispatcher and register it
-fn update_timer_outside_cb(
-    clients: &mut ClientMap, 
-    timer_dispatcher: &Dispatcher<Timer, ServerGlobalData>, 
-    handle: &LoopHandle<ServerGlobalData>, 
-    timer_token: &RegistrationToken
-) {
-    let mut timer = timer_dispatcher.as_source_mut();
-    if let Some(next_timeout) = clients.values().filter_map(|c| c.conn.timeout()).min() {
-        timer.set_duration(next_timeout);
-        handle.update(timer_token).unwrap();
-    } else {
-        debug!("recv packet but all timeout is None, set the timeout as timer idle timeout");
-        timer.set_duration(Duration::from_secs(IDLE_TIMEOUT));
-        handle.update(timer_token).unwrap();
+        let cfgs = get_dtp_config(&cfg_path)
+            .map_err(|e| anyhow!("invalid config {}: {}", cfg_path, e))?;
+        let sender_queue = new_scheduler(&scheduler, &scheduler_config)?;
+        let (receiver, duration) = simulation::SchedulerSimulation::new(cfgs, sender_queue, uplink, downlink).run()?;
+        info!("simulated {:.3} s", duration as f64 / 1e6);
+        receiver.finish(report_path.as_deref());
+        return Ok(());
     }
-}
-
-fn server_timeout_cb(
-    _event: Instant, 
-    _metadata: &mut (), 
-    shared_data: &mut ServerGlobalData
-) -> TimeoutAction {
-    let clients = shared_data.clients.as_mut().unwrap();
-    let socket = shared_data.socket.as_mut().unwrap();
-    // handle timeout
-    clients.values_mut().for_each(|c| c.conn.on_timeout());
-
-    collect_garbage_connection(clients);
-
-    server_flush_quic_packets(clients, socket).unwrap();
 
-    // update timer
-    if let Some(next_timeout ) = 
-        clients.values().filter_map(|c| c.conn.timeout()).min() {
-        TimeoutAction::ToDuration(next_timeout)
-    } else {
-        if clients.len() == 0 {
-            debug!("all timeout is None and no client in timeout_cb, stop the evloop");
-            shared_data.signal.as_ref().unwrap().stop();
+    let quic_options = parse_quic_options(&args)?;
+    let server_quic_options = quic_options.clone();
+    let retry = !args.get_bool("--no-retry");
+    let listen_addr = parse_addr(&args, "--listen")?;
+    let bind_addr = parse_addr(&args, "--bind")?;
+    let framed = transport_mode == TransportMode::TcpFramed;
+    let run_server = move || {
+        if transport_mode.is_tcp() {
+            return Ok(tcp::init_tcp_server(listen_addr, cfg_path.as_str(), scheduler.as_str(), &scheduler_config, framed)?);
+        }
+        let cfgs = get_dtp_config(&cfg_path)
+            .map_err(|e| anyhow!("invalid config {}: {}", cfg_path, e))?;
+        if cfgs.is_empty() {
+            return Err(anyhow!("No configs in the file {}", cfg_path));
+        }
+        // every connection gets its own scheduler, check the options once
+        new_scheduler(&scheduler, &scheduler_config)?;
+        let builder = DtpServer::builder(listen_addr)
+            .trace(cfgs)
+            .scheduler(move || new_scheduler(&scheduler, &scheduler_config).expect("the scheduler was checked"))
+            .finish_after(clients)
+            .retry(retry)
+            .transport_mode(transport_mode)
+            .reset_expired(reset_expired)
+            .fec_ratios(fec_ratios)
+            .quic_options(server_quic_options);
+        init_server(builder)
+    };
+    let run_client = move |peer_addr: SocketAddr, connect_delay: Duration, report_path: Option<String>| {
+        if transport_mode.is_tcp() {
+            return Ok(tcp::init_tcp_client(peer_addr, framed, report_path)?);
         }
-        TimeoutAction::Drop
+        let builder = DtpClient::builder(peer_addr)
+            .bind(bind_addr)
+            .connect_delay(connect_delay)
+            .quic_options(quic_options);
+        init_client(builder, report_path)
+    };
+    if args.get_bool("server") {
+        return run_server();
     }
-}
-
-fn init_server(addr: SocketAddr, cfg_path: &str) -> Result<()> {
-    // init global data
-    let mut global_data= ServerGlobalData::default();
-    // init socket
-    let server_addr = addr;
-    let server_socket = UdpSocket::bind(server_addr)?;
-    let local_addr = server_socket.local_addr().unwrap();
-    server_socket.set_nonblocking(true)?;
-    global_data.local_addr = Some(local_addr);
-    global_data.socket = Some(server_socket.try_clone().unwrap());
-    // init config file
-    let cfgs = get_dtp_config(cfg_path);
-    if cfgs.len() == 0 {
-        return Err(anyhow!("No configs in the file or filename error"));
+    if args.get_bool("client") {
+        return run_client(parse_addr(&args, "--peer")?, Duration::ZERO, report_path);
     }
-    global_data.block_generator.load_cfgs(cfgs);
-    // init quiche
-    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
-    set_quiche_conn_config(&mut config);
-    global_data.config = Some(config);
-
-    // init random seed
-    let rng = SystemRandom::new();
-    let conn_id_seed =
-        ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng).unwrap();
-    global_data.conn_id_seed = Some(conn_id_seed);
-
-    // init client map
-    let clients = ClientMap::new();
-    global_data.clients = Some(clients);
-
-    // init eventloop
-    // search for crate calloop for more information
-
-    let mut event_loop: EventLoop<ServerGlobalData> =
-        EventLoop::try_new().expect("Failed to initialize the event loop!");
-
-    // Retrieve a handle. It is used to insert new sources into the event loop
-    // It can be cloned, allowing you to insert sources from within source
-    // callbacks.
-    let handle = event_loop.handle();
-
-    // Create our event source, a timer, that will expire in 2 seconds
-    // let source = Timer::from_duration(std::time::Duration::from_secs_f32(global_data.block_generator.first_time_gap().unwrap()));
-
-    // Inserting an event source takes this general form. It can also be done
-    // from within the callback of another event source.
-    // add block generator_cb
-    // handle
-    //     .insert_source(
-    //         // a type which implements the EventSource trait
-    //         source,
-    //         // a callback that is invoked whenever this source generates an event
-    //         generate_cb,
-    //     )
-    //     .expect("Failed to insert generate_cb!");
-    // TODO: add sender_cb
-    // add recv_cb
-    handle.insert_source(
-        // wrap your IO object in a Generic, here we register for read readiness
-        // in level-triggering mode
-        Generic::new(server_socket, Interest::READ, Mode::Level),
-        server_recv_cb
-    ).expect("Failed to insert recv_cb!");
-    // add timeout cb
-    let timeout_timer = Timer::from_duration(std::time::Duration::from_secs(IDLE_TIMEOUT));
-
-    let timeout_dispatcher = Dispatcher::new(timeout_timer, server_timeout_cb);
-
-    let timeout_token = handle.register_dispatcher(timeout_dispatcher.clone()).expect("Failed to insert timeout");
- 
-    global_data.handle = Some(handle);
-    global_data.timeout_dispatcher = Some(timeout_dispatcher);
-    global_data.timeout_token = Some(timeout_token);
-    global_data.signal = Some(event_loop.get_signal());
-
-    // Create the shared data for our loop.
-    let mut shared_data = global_data;
-
-    // Actually run the event loop. This will dispatch received events to their
-    // callbacks, waiting at most 20ms for new events between each invocation of
-    // the provided callback (pass None for the timeout argument if you want to
-    // wait indefinitely between events).
-    //
-    // This is where we pass the *value* of the shared data, as a mutable
-    // reference that will be forwarded to all your callbacks, allowing them to
-    // share some state
-    event_loop
-        .run(
-            std::time::Duration::from_secs(10),
-            &mut shared_data,
-            |_shared_data| {
-                // Finally, this is where you can insert the processing you need
-                // to do do between each waiting event eg. drawing logic if
-                // you're doing a GUI app.
-            },
-        )
-        .expect("Error during event loop!");
-    Ok(())
-}
-
-// Generate outgoing QUIC packets and send them on the UDP socket, until
-// quiche reports that there are no more packets to be sent.
-fn client_recv_cb(
-    _readiness: calloop::Readiness, 
-    _io_object: &mut UdpSocket, 
-    shared_data: &mut ClientGlobalData 
-) -> Result<calloop::PostAction, std::io::Error>{
-    let mut buf = [0; 65535];
-    let socket = shared_data.socket.as_mut().unwrap();
-    let conn = shared_data.conn.as_mut().unwrap();
-    let req_sent = &mut shared_data.req_sent;
-    let req_start = shared_data.req_start.as_ref().unwrap();
-    let peer_addr = shared_data.peer_addr.as_ref().unwrap();
-    // Read incoming UDP packets from the socket and feed them to quiche,
-    // until there are no more packets to read.
-    'read: loop {
-        let (len, from) = match socket.recv_from(&mut buf) {
-            Ok(v) => v,
-
-            Err(e) => {
-                // There are no more UDP packets to read, so end the read
-                // loop.
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    debug!("client recv() would block");
-                    break 'read;
-                }
-
-                panic!("client recv() failed: {:?}", e);
-            },
-        };
-
-        debug!("client got {} bytes", len);
-
-        let recv_info = quiche::RecvInfo {
-            to: socket.local_addr().unwrap(),
-            from,
-        };
-
-        // Process potentially coalesced packets.
-        let read = match conn.recv(&mut buf[..len], recv_info) {
-            Ok(v) => v,
-
-            Err(e) => {
-                error!("recv failed: {:?}", e);
-                continue 'read;
-            },
-        };
 
-        debug!("client processed {} bytes", read);
+    if clients > 1 && (report_path.is_none() || transport_mode.is_tcp() || !uplink.is_ideal() || !downlink.is_ideal()) {
+        return Err(anyhow!("more than one client needs a QUIC mode, --report and no emulated link"));
     }
 
-    debug!("client done reading");
+    // the client talks to the emulator, which relays to the server
+    let emulator = if uplink.is_ideal() && downlink.is_ideal() {
+        None
+    } else {
+        if transport_mode.is_tcp() {
+            return Err(anyhow!("the emulated link only carries the QUIC modes"));
+        }
+        let emulator_addr = SocketAddr::from(([127, 0, 0, 1], 7737));
+        Some(emulator::spawn_emulator(emulator_addr, listen_addr, uplink, downlink)?)
+    };
+    let peer_addr = emulator.as_ref().map_or(listen_addr, |emulator| emulator.local_addr());
 
-    if conn.is_closed() {
-        info!("client connection closed, {:?}", conn.stats());
-        return Ok(PostAction::Continue);
+    use std::thread;
+    let server_handle = thread::spawn(run_server);
+    // give the server some time to start
+    let client_handles: Vec<_> = (0..clients)
+        .map(|i| {
+            let report_path = match &report_path {
+                Some(path) if clients > 1 => Some(format!("{}.{}", path, i)),
+                path => path.clone(),
+            };
+            let run_client = run_client.clone();
+            thread::spawn(move || run_client(peer_addr, Duration::from_secs(2), report_path))
+        })
+        .collect();
+
+    // a failed client returns right away, the server would wait for it
+    for client_handle in client_handles {
+        client_handle.join().expect("The client thread has panicked")?;
     }
-
-    // Send an HTTP request as soon as the connection is established.
-    if conn.is_established() && !*req_sent {
-        info!("sending HTTP request for {:?}", peer_addr.to_string());
-
-        let req = format!("GET {}\r\n", "Hello world");
-        conn.stream_send(HTTP_REQ_STREAM_ID, req.as_bytes(), true)
-            .unwrap();
-
-        *req_sent = true;
+    server_handle.join().expect("The server thread has panicked")?;
+    if let Some(emulator) = emulator {
+        emulator.stop()?;
     }
 
-    // Process all readable streams.
-    for s in conn.readable() {
-        while let Ok((read, fin)) = conn.stream_recv(s, &mut buf) {
-            debug!("client received {} bytes", read);
-
-            let stream_buf = &buf[..read];
-
-            debug!(
-                "client stream {} has {} bytes (fin? {})",
-                s,
-                stream_buf.len(),
-                fin
-            );
-
-            print!("{}", unsafe {
-                std::str::from_utf8_unchecked(stream_buf)
-            });
-
-            // The server reported that it has no more data to send, which
-            // we got the full response. Close the connection.
-            if s == HTTP_REQ_STREAM_ID && fin {
-                info!(
-                    "client response received in {:?}, closing...",
-                    req_start.elapsed()
-                );
-
-                conn.close(true, 0x00, b"kthxbye").unwrap();
-            }
+    Ok(())
+}
+
+/// Run a `DtpServer` in its own event loop until its clients leave or
+/// its socket fails
+fn init_server(builder: DtpServerBuilder) -> Result<()> {
+    let mut event_loop: EventLoop<()> = EventLoop::try_new()?;
+    let server = builder.build(&event_loop.handle())?;
+    let signal = event_loop.get_signal();
+    event_loop.run(Duration::from_secs(10), &mut (), |_| {
+        if server.is_finished() {
+            signal.stop();
         }
+    })?;
+    match server.take_error() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
     }
+}
 
-    client_flush_quic_packets(socket, conn).unwrap();
-
-    if conn.is_closed() {
-        info!("connection closed, {:?}", conn.stats());
-        shared_data.signal.as_ref().unwrap().stop();
-        return Ok(PostAction::Remove);
+/// Run a `DtpClient` in its own event loop until the connection closes,
+/// then write the block report into the file at `report_path`, or stdout
+fn init_client(builder: DtpClientBuilder, report_path: Option<String>) -> Result<()> {
+    let mut event_loop: EventLoop<()> = EventLoop::try_new()?;
+    let client = builder.build(&event_loop.handle())?;
+    let signal = event_loop.get_signal();
+    event_loop.run(Duration::from_secs(10), &mut (), |_| {
+        if client.is_closed() {
+            signal.stop();
+        }
+    })?;
+    client.receiver().finish(report_path.as_deref());
+    match client.take_error() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
     }
-    Ok(PostAction::Continue)
 }
 
-fn client_flush_quic_packets(socket: &mut UdpSocket, conn: &mut quiche::Connection) -> Result<()> {
-    let mut out = [0; MAX_DATAGRAM_SIZE];
-    loop {
-        let (write, send_info) = match conn.send(&mut out) {
-            Ok(v) => v,
-
-            Err(quiche::Error::Done) => {
-                debug!("client done writing");
-                break;
-            },
-
-            Err(e) => {
-                error!("client send failed: {:?}", e);
-
-                conn.close(false, 0x1, b"fail").ok();
-                break;
-            },
-        };
-
-        if let Err(e) = socket.send_to(&out[..write], send_info.to) {
-            if e.kind() == std::io::ErrorKind::WouldBlock {
-                debug!("send() would block");
-                break;
-            }
-
-            panic!("send() failed: {:?}", e);
+/// Write a self-signed certificate and its key to the paths of --cert and --key
+fn gen_cert(args: &docopt::ArgvMap) -> Result<()> {
+    let names: Vec<String> = args.get_str("--names").split(',').map(|n| n.trim().to_owned()).collect();
+    let days = args.get_str("--days").parse().map_err(|e| anyhow!("invalid --days {:?}: {}", args.get_str("--days"), e))?;
+    let (cert_path, key_path) = (args.get_str("--cert"), args.get_str("--key"));
+    let force = args.get_bool("--force");
+    // check both files first, not to leave a certificate without its key
+    if !force {
+        if let Some(path) = [cert_path, key_path].into_iter().find(|path| std::path::Path::new(path).exists()) {
+            return Err(anyhow!("{} exists, use --force to overwrite it", path));
         }
-
-        debug!("client written {}", write);
     }
+    let pair = cert::generate_self_signed(&names, days)?;
+    write_file(key_path, &pair.key_pem, 0o600, force)?;
+    write_file(cert_path, &pair.cert_pem, 0o644, force)?;
+    info!("wrote a certificate for {:?} to {} and its key to {}", names, cert_path, key_path);
     Ok(())
 }
 
-fn client_timeout_cb(
-    _event: Instant, 
-    _metadata: &mut (), 
-    shared_data: &mut ClientGlobalData
-) -> TimeoutAction {
-    let socket = shared_data.socket.as_mut().unwrap();
-    let conn = shared_data.conn.as_mut().unwrap();
-    // handle timeout
-    conn.on_timeout();
-
-    if conn.is_closed() {
-        info!("connection closed, {:?}", conn.stats());
-        shared_data.signal.as_ref().unwrap().stop();
-        return TimeoutAction::Drop;
-    }
-
-    client_flush_quic_packets(socket, conn).unwrap();
-
-    // update timer
-    if let Some(next_timeout) = conn.timeout() {
-        TimeoutAction::ToDuration(next_timeout)
-    } else {
-        TimeoutAction::Drop
-    }
+/// Write `contents` to a new file with permissions `mode`, or over an
+/// existing one with `overwrite`
+fn write_file(path: &str, contents: &str, mode: u32, overwrite: bool) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).mode(mode);
+    match overwrite {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    let mut file = options.open(path).map_err(|e| anyhow!("failed to create {}: {}", path, e))?;
+    // the mode only applies to the files created
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+        .and_then(|_| file.write_all(contents.as_bytes()))
+        .map_err(|e| anyhow!("failed to write {}: {}", path, e))
 }
 
-fn init_client(addr: SocketAddr, peer_addr: SocketAddr) -> Result<()> {
-    // init global data
-    let mut global_data= ClientGlobalData::default();
-    // init socket
-    let client_addr = addr;
-    let client_socket = UdpSocket::bind(client_addr)?;
-    client_socket.set_nonblocking(true)?;
-    let local_addr = client_socket.local_addr().unwrap();
-    global_data.local_addr = Some(local_addr);
-    global_data.socket = Some(client_socket.try_clone().unwrap());
-    global_data.peer_addr = Some(peer_addr);
-    // init quiche
-    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
-    set_quiche_conn_config(&mut config);
-    global_data.config = Some(config);
-
-    // Generate a random source connection ID for the connection.
-    let mut scid = [0; quiche::MAX_CONN_ID_LEN];
-    SystemRandom::new().fill(&mut scid[..]).unwrap();
-
-    let scid = quiche::ConnectionId::from_ref(&scid);
-    global_data.scid = Some(scid);
-
-    // TODO: init data structure
-
-    // init eventloop
-    // search for crate calloop for more information
-
-    let mut event_loop: EventLoop<ClientGlobalData> =
-        EventLoop::try_new().expect("Failed to initialize the client event loop!");
-
-    // Retrieve a handle. It is used to insert new sources into the event loop
-    // It can be cloned, allowing you to insert sources from within source
-    // callbacks.
-    let handle = event_loop.handle();
-
-    // Create our event source, a timer, that will expire in 2 seconds
-    let source = Timer::from_duration(std::time::Duration::from_secs_f32(2.0));
-
-    // Inserting an event source takes this general form. It can also be done
-    // from within the callback of another event source.
-    // add block generator_cb
-    handle
-        .insert_source(
-            // a type which implements the EventSource trait
-            source,
-            // a callback that is invoked whenever this source generates an event
-            |_event: Instant, _metadata: &mut (), shared_data: &mut ClientGlobalData| {
-                let mut out = [0; MAX_DATAGRAM_SIZE];
-                let config = shared_data.config.as_mut().unwrap();
-                let socket = shared_data.socket.as_mut().unwrap();
-                let scid = shared_data.scid.as_ref().unwrap();
-                // Create a QUIC connection and initiate handshake.
-                let mut conn =
-                    quiche::connect(Some(peer_addr.to_string().as_str()), &scid, local_addr, peer_addr, config)
-                        .unwrap();
-
-                info!(
-                    "connecting to {:} from {:} with scid {}",
-                    peer_addr,
-                    socket.local_addr().unwrap(),
-                    hex_dump(&scid)
-                );
-
-                let (write, send_info) = conn.send(&mut out).expect("initial send failed");
-
-                while let Err(e) = socket.send_to(&out[..write], send_info.to) {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        debug!("send() would block");
-                        continue;
-                    }
-
-                    panic!("send() failed: {:?}", e);
-                }
-
-                debug!("written {}", write);
-
-                shared_data.req_start = Some(std::time::Instant::now());
-
-                shared_data.req_sent = false;
-
-                shared_data.conn = Some(conn);
-                return TimeoutAction::Drop
-        }
-    ).expect("Failed to insert generate_cb!");
-    // TODO: add sender_cb
-    // add recv_cb
-    handle.insert_source(
-        // wrap your IO object in a Generic, here we register for read readiness
-        // in level-triggering mode
-        Generic::new(client_socket, Interest::READ, Mode::Level),
-        client_recv_cb
-    ).expect("Failed to insert recv_cb!");
-    // add timeout cb
-    let timeout_timer = Timer::from_duration(std::time::Duration::from_secs(IDLE_TIMEOUT));
-
-    let timeout_dispatcher = Dispatcher::new(timeout_timer, client_timeout_cb);
-
-    let timeout_token = handle.register_dispatcher(timeout_dispatcher.clone()).expect("Failed to insert timeout");
- 
-    global_data.handle = Some(handle);
-    global_data.timeout_dispatcher = Some(timeout_dispatcher);
-    global_data.timeout_token = Some(timeout_token);
-    global_data.signal = Some(event_loop.get_signal());
-
-    // Create the shared data for our loop.
-    let mut shared_data = global_data;
-
-    // Actually run the event loop. This will dispatch received events to their
-    // callbacks, waiting at most 20ms for new events between each invocation of
-    // the provided callback (pass None for the timeout argument if you want to
-    // wait indefinitely between events).
-    //
-    // This is where we pass the *value* of the shared data, as a mutable
-    // reference that will be forwarded to all your callbacks, allowing them to
-    // share some state
-    event_loop
-        .run(
-            std::time::Duration::from_secs(10),
-            &mut shared_data,
-            |_shared_data| {
-                // Finally, this is where you can insert the processing you need
-                // to do do between each waiting event eg. drawing logic if
-                // you're doing a GUI app.
-            },
-        )
-        .expect("Error during event loop!");
-    Ok(())
+/// Parse the socket address given to option `name`
+fn parse_addr(args: &docopt::ArgvMap, name: &str) -> Result<SocketAddr> {
+    args.get_str(name).parse().map_err(|e| anyhow!("invalid {} {:?}: {}", name, args.get_str(name), e))
 }
 
-fn main() -> Result<()> {
-    // parse args
-    let args = docopt::Docopt::new(USAGE)
-        .and_then(|dopt| dopt.parse())
-        .unwrap_or_else(|e| e.exit());
-    // init logger
-    env_logger::init();
-    let cfg_path = args.get_str("CONFIG").to_owned();
-    // let cfg_path = "aitrans_block.txt";
+/// The QUIC settings from the command line
+fn parse_quic_options(args: &docopt::ArgvMap) -> Result<QuicOptions> {
+    let idle_timeout = args.get_str("--idle-timeout");
+    Ok(QuicOptions {
+        cert_path: args.get_str("--cert").to_owned(),
+        key_path: args.get_str("--key").to_owned(),
+        ca_path: match args.get_str("--ca") {
+            "" => None,
+            path => Some(path.to_owned()),
+        },
+        server_name: match args.get_str("--server-name") {
+            "" => None,
+            name => Some(name.to_owned()),
+        },
+        alpn: args.get_str("--alpn").split(',').map(|p| p.trim().as_bytes().to_vec()).collect(),
+        idle_timeout: idle_timeout.parse().map_err(|e| anyhow!("invalid --idle-timeout {:?}: {}", idle_timeout, e))?,
+    })
+}
 
-    let server_addr = SocketAddr::from(([127, 0, 0, 1], 7736));
-    let client_addr = SocketAddr::from(([127, 0, 0, 1], 8847));
+/// The emulated link from the command line
+fn parse_link_config(args: &docopt::ArgvMap) -> Result<emulator::LinkConfig> {
+    let number = |name: &str| -> Result<f64> {
+        args.get_str(name).parse().map_err(|e| anyhow!("invalid {} {:?}: {}", name, args.get_str(name), e))
+    };
+    let ms_to_us = |ms: f64| (ms * 1000.0).round() as u64;
+    let burst_loss = match args.get_str("--burst-loss") {
+        "" => None,
+        list => match parse_float_list(list)?.as_slice() {
+            [enter, leave] => Some((*enter, *leave)),
+            _ => return Err(anyhow!("--burst-loss expects 2 numbers, got {:?}", list)),
+        },
+    };
+    Ok(emulator::LinkConfig {
+        bandwidth: number("--bandwidth")? * 1e6,
+        capacity: None,
+        delay: ms_to_us(number("--delay")?),
+        jitter: ms_to_us(number("--jitter")?),
+        loss: number("--loss")?,
+        burst_loss,
+        reorder: number("--reorder")?,
+        queue_size: number("--queue")? as usize,
+        seed: number("--seed")? as u64,
+    })
+}
 
-    use std::thread;
-    let server_handle = thread::spawn(move ||{
-        init_server(server_addr, cfg_path.as_str()).unwrap();
-    });
-    let client_handle = thread::spawn(move ||{
-        init_client(client_addr, server_addr).unwrap();
-    });
-
-    server_handle.join().expect("The server thread has panicked");
-    client_handle.join().expect("The client thread has panicked");
-    
-    return Ok(());
+/// Parse a comma separated list of numbers such as `1,2,3`
+fn parse_float_list(list: &str) -> Result<Vec<f64>> {
+    list.split(',')
+        .map(|v| v.trim().parse::<f64>().map_err(|e| anyhow!("invalid number {:?} in {:?}: {}", v, list, e)))
+        .collect()
 }
 
 #[cfg(test)]
@@ -870,139 +381,65 @@ mod tests {
     fn something() {
 
     }
-}
-
-fn set_quiche_conn_config(config: &mut Config) {
-    config
-        .load_cert_chain_from_pem_file("cert.crt")
-        .unwrap();
-    config
-        .load_priv_key_from_pem_file("cert.key")
-        .unwrap();
-
-    config
-        .set_application_protos(&[
-            b"hq-interop",
-            b"hq-29",
-            b"hq-28",
-            b"hq-27",
-            b"http/0.9",
-        ])
-        .unwrap();
-
-    config.set_max_idle_timeout(IDLE_TIMEOUT * 1000);
-    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_initial_max_data(10_000_000);
-    config.set_initial_max_stream_data_bidi_local(1_000_000);
-    config.set_initial_max_stream_data_bidi_remote(1_000_000);
-    config.set_initial_max_stream_data_uni(1_000_000);
-    config.set_initial_max_streams_bidi(100);
-    config.set_initial_max_streams_uni(100);
-    config.set_disable_active_migration(true);
-    config.enable_early_data();
-}
-
-/// Generate a stateless retry token.
-///
-/// The token includes the static string `"quiche"` followed by the IP address
-/// of the client and by the original destination connection ID generated by the
-/// client.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn mint_token(hdr: &quiche::Header, src: &net::SocketAddr) -> Vec<u8> {
-    let mut token = Vec::new();
-
-    token.extend_from_slice(b"quiche");
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    token.extend_from_slice(&addr);
-    token.extend_from_slice(&hdr.dcid);
-
-    token
-}
-
-/// Validates a stateless retry token.
-///
-/// This checks that the ticket includes the `"quiche"` static string, and that
-/// the client IP address matches the address stored in the ticket.
-///
-/// Note that this function is only an example and doesn't do any cryptographic
-/// authenticate of the token. *It should not be used in production system*.
-fn validate_token<'a>(
-    src: &net::SocketAddr, token: &'a [u8],
-) -> Option<quiche::ConnectionId<'a>> {
-    if token.len() < 6 {
-        return None;
-    }
-
-    if &token[..6] != b"quiche" {
-        return None;
-    }
-
-    let token = &token[6..];
-
-    let addr = match src.ip() {
-        std::net::IpAddr::V4(a) => a.octets().to_vec(),
-        std::net::IpAddr::V6(a) => a.octets().to_vec(),
-    };
-
-    if token.len() < addr.len() || &token[..addr.len()] != addr.as_slice() {
-        return None;
-    }
-
-    Some(quiche::ConnectionId::from_ref(&token[addr.len()..]))
-}
-
-/// Handles newly writable streams.
-fn handle_writable(client: &mut Client, stream_id: u64) {
-    let conn = &mut client.conn;
 
-    debug!("{} stream {} is writable", conn.trace_id(), stream_id);
-
-    if !client.partial_responses.contains_key(&stream_id) {
-        return;
+    #[test]
+    fn subcommands() {
+        let parse = |argv: &[&str]| docopt::Docopt::new(USAGE).unwrap().argv(argv.iter().copied()).parse().unwrap();
+        let args = parse(&["dtp", "client", "--peer", "10.0.0.1:4433", "-m", "framed"]);
+        assert!(args.get_bool("client") && !args.get_bool("server"));
+        assert_eq!(args.get_str("CONFIG"), "");
+        assert_eq!(parse_addr(&args, "--peer").unwrap(), SocketAddr::from(([10, 0, 0, 1], 4433)));
+
+        let args = parse(&["dtp", "server", "--alpn", "dtp", "--idle-timeout", "30000", "trace.txt"]);
+        assert!(args.get_bool("server"));
+        assert_eq!(args.get_str("CONFIG"), "trace.txt");
+        let options = parse_quic_options(&args).unwrap();
+        assert_eq!(options.alpn, vec![b"dtp".to_vec()]);
+        assert_eq!(options.idle_timeout, 30_000);
+
+        let args = parse(&["dtp", "loopback", "trace.txt"]);
+        assert!(args.get_bool("loopback"));
+        assert_eq!(args.get_str("CONFIG"), "trace.txt");
+        assert_eq!(parse_quic_options(&args).unwrap().alpn, QuicOptions::default().alpn);
+
+        let args = parse(&["dtp", "gen-cert", "--cert", "a.crt", "--names", "dtp.test"]);
+        assert!(args.get_bool("gen-cert") && !args.get_bool("client"));
+        assert_eq!(args.get_str("--days"), "365");
+
+        let args = parse(&["dtp", "client", "--ca", "a.crt", "--server-name", "dtp.test"]);
+        let options = parse_quic_options(&args).unwrap();
+        assert_eq!(options.ca_path.as_deref(), Some("a.crt"));
+        assert_eq!(options.server_name.as_deref(), Some("dtp.test"));
     }
 
-    let resp = client.partial_responses.get_mut(&stream_id).unwrap();
-    let body = &resp.body[resp.written..];
-
-    let written = match conn.stream_send(stream_id, body, true) {
-        Ok(v) => v,
-
-        Err(quiche::Error::Done) => 0,
-
-        Err(e) => {
-            client.partial_responses.remove(&stream_id);
-
-            error!("{} stream send failed {:?}", conn.trace_id(), e);
-            return;
-        },
-    };
-
-    resp.written += written;
-
-    if resp.written == resp.body.len() {
-        client.partial_responses.remove(&stream_id);
+    #[test]
+    fn gen_cert_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("dtp-gen-cert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("a.crt").to_str().unwrap().to_owned();
+        let key = dir.join("a.key").to_str().unwrap().to_owned();
+        let args = |force: bool| {
+            let mut argv = vec!["dtp", "gen-cert", "--cert", &cert, "--key", &key];
+            if force {
+                argv.push("--force");
+            }
+            docopt::Docopt::new(USAGE).unwrap().argv(argv).parse().unwrap()
+        };
+        let mode = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // an existing key is not overwritten, and no certificate is written
+        std::fs::write(&key, "old").unwrap();
+        assert!(gen_cert(&args(false)).is_err());
+        assert_eq!(std::fs::read_to_string(&key).unwrap(), "old");
+        assert!(!std::path::Path::new(&cert).exists());
+
+        gen_cert(&args(true)).unwrap();
+        assert_eq!(mode(&key), 0o600);
+        assert!(std::fs::read_to_string(&key).unwrap().contains("PRIVATE KEY"));
+        let cert_pem = std::fs::read_to_string(&cert).unwrap();
+        assert!(gen_cert(&args(false)).is_err());
+        assert_eq!(std::fs::read_to_string(&cert).unwrap(), cert_pem);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
-
-/// Handles incoming HTTP/0.9 requests.
-fn handle_stream(client: &mut Client, stream_id: u64, buf: &[u8], _root: &str) {
-    // pass
-    println!("server recv client: {}, stream_id: {}, buf: {}", client.conn.trace_id(), stream_id, String::from_utf8_lossy(&buf));
-}
-
-fn hex_dump(buf: &[u8]) -> String {
-    let vec: Vec<String> = buf.iter().map(|b| format!("{b:02x}")).collect();
-
-    vec.join("")
-}
-
-mod block;
-mod sender;
\ No newline at end of file
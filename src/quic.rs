@@ -0,0 +1,165 @@
+//! The QUIC settings shared by `DtpServer` and `DtpClient`
+use std::net::UdpSocket;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use calloop::{timer::Timer, Dispatcher, LoopHandle, RegistrationToken};
+
+use crate::error::{Error, Result};
+
+pub const MAX_DATAGRAM_SIZE: usize = 1350;
+/// The idle timeout, in seconds
+pub const IDLE_TIMEOUT: u64 = 5;
+/// The receive buffer asked for the UDP sockets, in bytes. The default of
+/// Linux is smaller than a large block, whose lost datagrams are not
+/// retransmitted.
+pub const RECV_BUFFER_SIZE: usize = 4 << 20;
+
+/// The QUIC settings of a server or a client
+#[derive(Debug, Clone)]
+pub struct QuicOptions {
+    /// the PEM certificate chain and private key of the server
+    pub cert_path: String,
+    pub key_path: String,
+    /// the PEM CA certificates the client verifies the server against,
+    /// None to accept any server
+    pub ca_path: Option<String>,
+    /// the name the client expects in the certificate of the server, the
+    /// IP address of the server by default
+    pub server_name: Option<String>,
+    /// the application protocols offered in the handshake
+    pub alpn: Vec<Vec<u8>>,
+    /// in milliseconds
+    pub idle_timeout: u64,
+}
+
+impl Default for QuicOptions {
+    fn default() -> Self {
+        QuicOptions {
+            cert_path: "cert.crt".to_owned(),
+            key_path: "cert.key".to_owned(),
+            ca_path: None,
+            server_name: None,
+            alpn: ["hq-interop", "hq-29", "hq-28", "hq-27", "http/0.9"]
+                .iter()
+                .map(|p| p.as_bytes().to_vec())
+                .collect(),
+            idle_timeout: IDLE_TIMEOUT * 1000,
+        }
+    }
+}
+
+/// Enlarge the receive buffer of `socket` to `RECV_BUFFER_SIZE`. The kernel
+/// caps it at `net.core.rmem_max`, which only deserves a warning.
+pub(crate) fn set_recv_buffer(socket: &UdpSocket) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    let size = RECV_BUFFER_SIZE as libc::c_int;
+    let len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, &size as *const _ as *const libc::c_void, len)
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let (mut actual, mut actual_len): (libc::c_int, libc::socklen_t) = (0, len);
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, &mut actual as *mut _ as *mut libc::c_void, &mut actual_len)
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // Linux reports twice the size it was given, for its bookkeeping
+    if (actual as usize) < RECV_BUFFER_SIZE {
+        warn!(
+            "the receive buffer of the socket is {} bytes instead of {}, raise net.core.rmem_max to avoid losing datagrams",
+            actual, RECV_BUFFER_SIZE
+        );
+    }
+    Ok(())
+}
+
+/// Load the certificate chain and private key the server presents
+pub(crate) fn load_certificate(config: &mut quiche::Config, options: &QuicOptions) -> Result<()> {
+    config
+        .load_cert_chain_from_pem_file(&options.cert_path)
+        .map_err(|e| Error::Config(format!("invalid certificate {}: {:?}", options.cert_path, e)))?;
+    config
+        .load_priv_key_from_pem_file(&options.key_path)
+        .map_err(|e| Error::Config(format!("invalid private key {}: {:?}", options.key_path, e)))?;
+    Ok(())
+}
+
+/// Verify the certificate of the server if the client is given a CA file
+pub(crate) fn load_verify_locations(config: &mut quiche::Config, options: &QuicOptions) -> Result<()> {
+    match &options.ca_path {
+        Some(ca_path) => {
+            config
+                .load_verify_locations_from_file(ca_path)
+                .map_err(|e| Error::Config(format!("invalid CA file {}: {:?}", ca_path, e)))?;
+            config.verify_peer(true);
+        },
+        None => config.verify_peer(false),
+    }
+    Ok(())
+}
+
+pub(crate) fn set_quiche_conn_config(config: &mut quiche::Config, options: &QuicOptions) -> Result<()> {
+    let alpn: Vec<&[u8]> = options.alpn.iter().map(|p| p.as_slice()).collect();
+    config.set_application_protos(&alpn)
+        .map_err(|e| Error::Config(format!("invalid application protocols {:?}: {:?}", options.alpn, e)))?;
+
+    config.set_max_idle_timeout(options.idle_timeout);
+    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
+    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
+    // quiche does not give back the connection flow control credit of the
+    // bytes discarded by a reset stream, so leave room for the abandoned
+    // blocks: every stream can take 1 MB, 100 streams at a time
+    config.set_initial_max_data(100_000_000);
+    config.set_initial_max_stream_data_bidi_local(1_000_000);
+    config.set_initial_max_stream_data_bidi_remote(1_000_000);
+    config.set_initial_max_stream_data_uni(1_000_000);
+    config.set_initial_max_streams_bidi(100);
+    config.set_initial_max_streams_uni(100);
+    config.set_disable_active_migration(true);
+    // for the datagram mode, a full send queue makes the sender wait for
+    // the next writable event
+    config.enable_dgram(true, 1000, 1000);
+    config.enable_early_data();
+    Ok(())
+}
+
+/// The timer of the QUIC timeouts in the event loop of the application.
+///
+/// It hides the type of the data of the loop from the server and client
+/// state, so that they can be embedded in any loop.
+pub(crate) trait LoopTimer {
+    /// Fire after `timeout` instead of the previous time
+    fn set_duration(&mut self, timeout: Duration);
+}
+
+/// A timer registered once, whose duration is updated in place
+pub(crate) struct DispatcherTimer<'l, D> {
+    pub handle: LoopHandle<'l, D>,
+    pub dispatcher: Dispatcher<'l, Timer, D>,
+    pub token: RegistrationToken,
+}
+
+impl<'l, D> LoopTimer for DispatcherTimer<'l, D> {
+    fn set_duration(&mut self, timeout: Duration) {
+        self.dispatcher.as_source_mut().set_duration(timeout);
+        if let Err(e) = self.handle.update(&self.token) {
+            error!("failed to update the timer: {:?}", e);
+        }
+    }
+}
+
+/// Close a connection that failed, the other connections go on
+pub(crate) fn close_connection(conn: &mut quiche::Connection, e: &Error) {
+    error!("{} closing the connection: {}", conn.trace_id(), e);
+    conn.close(false, e.close_code(), e.to_string().as_bytes()).ok();
+}
+
+pub(crate) fn hex_dump(buf: &[u8]) -> String {
+    let vec: Vec<String> = buf.iter().map(|b| format!("{b:02x}")).collect();
+
+    vec.join("")
+}
@@ -0,0 +1,605 @@
+use crate::block::{BlockHeader, BlockInfo, BLOCK_EXPIRED_ERROR, BLOCK_HEADER_LEN, CONTROL_STREAM_ID, FRAMED_STREAM_ID, stream_id_to_block_id};
+use crate::datagram::DatagramHeader;
+use crate::fec::{FecDecoder, RepairSymbol, MAX_GROUP_SIZE};
+use crate::frame::{FrameDecoder, StreamFrame};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// A block being reassembled from its stream
+#[derive(Debug, Default)]
+pub struct ReceiverBlock {
+    header_buf: Vec<u8>,
+    header: Option<BlockHeader>,
+    /// the info announced on the control stream before the block was sent
+    announced: Option<BlockInfo>,
+    received: usize,
+    fin: bool,
+    /// the server gave up on the block
+    dropped: bool,
+    /// the server reset the stream of the block because its deadline passed
+    abandoned: bool,
+    /// the pieces of the block received in datagrams
+    datagrams: FecDecoder,
+    /// the data of the block, None unless the receiver keeps it
+    data: Option<Vec<u8>>,
+    /// the arrival time of the first byte of the stream, in microseconds
+    first_byte_time: Option<u64>,
+    /// the arrival time of the last byte of the stream, in microseconds
+    last_byte_time: Option<u64>,
+}
+
+impl ReceiverBlock {
+    pub fn info(&self) -> Option<&BlockInfo> {
+        self.announced.as_ref().or(self.header.as_ref().map(|h| &h.info))
+    }
+    /// Whether any data of the block arrived
+    pub fn has_arrived(&self) -> bool {
+        self.first_byte_time.is_some()
+    }
+    pub fn is_complete(&self) -> bool {
+        match self.info() {
+            Some(info) => self.fin && self.received == info.size,
+            None => false,
+        }
+    }
+    /// The time the block was generated, in microseconds, None before its header arrives
+    pub fn create_time(&self) -> Option<u64> {
+        self.header.map(|h| h.create_time)
+    }
+    /// Time between the generation of the block and the arrival of its last byte, in microseconds.
+    /// None if the block is not complete.
+    ///
+    /// The generation time is read from the clock of the server and the arrival from the
+    /// one of the client, so across hosts the time includes the offset between their clocks.
+    pub fn completion_time(&self) -> Option<u64> {
+        if !self.is_complete() {
+            return None;
+        }
+        let create_time = self.header?.create_time;
+        Some(self.last_byte_time?.saturating_sub(create_time))
+    }
+    /// Time between the generation of the block and the arrival of its first byte, in microseconds.
+    /// Like the completion time, it depends on the offset between the clocks of the server and client.
+    pub fn first_byte_delay(&self) -> Option<u64> {
+        let create_time = self.header?.create_time;
+        Some(self.first_byte_time?.saturating_sub(create_time))
+    }
+    pub fn is_dropped(&self) -> bool {
+        self.dropped && !self.is_complete()
+    }
+    pub fn is_abandoned(&self) -> bool {
+        self.abandoned && !self.is_complete()
+    }
+    pub fn is_deadline_met(&self) -> bool {
+        match (self.info(), self.completion_time()) {
+            (Some(info), Some(t)) => t <= info.deadline as u64 * 1000,
+            _ => false,
+        }
+    }
+    fn recv(&mut self, mut buf: &[u8], fin: bool, now: u64) {
+        if self.first_byte_time.is_none() && (!buf.is_empty() || fin) {
+            self.first_byte_time = Some(now);
+        }
+        // the block header comes first
+        if self.header.is_none() {
+            let needed = (BLOCK_HEADER_LEN - self.header_buf.len()).min(buf.len());
+            self.header_buf.extend_from_slice(&buf[..needed]);
+            buf = &buf[needed..];
+            self.header = BlockHeader::from_bytes(&self.header_buf);
+        }
+        if let Some(data) = self.data.as_mut() {
+            data.extend_from_slice(buf);
+        }
+        self.received += buf.len();
+        self.fin |= fin;
+        self.last_byte_time = Some(now);
+    }
+    /// The `BlockInfo` frame of the block arrived
+    fn recv_info(&mut self, header: BlockHeader, now: u64) {
+        self.header = Some(header);
+        self.first_byte_time.get_or_insert(now);
+        self.last_byte_time = Some(now);
+    }
+    /// A `BlockData` frame or a datagram of the block arrived. There is no
+    /// end of stream in the framed and datagram modes, the block ends with
+    /// its last byte.
+    fn recv_data(&mut self, len: usize, now: u64) {
+        self.first_byte_time.get_or_insert(now);
+        self.received += len;
+        self.last_byte_time = Some(now);
+        if let Some(info) = self.info() {
+            self.fin = self.received >= info.size;
+        }
+    }
+}
+
+/// The number of blocks of each outcome
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub complete: usize,
+    /// complete before the deadline
+    pub met: usize,
+    pub dropped: usize,
+    pub abandoned: usize,
+}
+
+/// Tracks the blocks arriving on the QUIC streams of the client
+/// and produces the per-block report when the connection closes
+#[derive(Debug, Default)]
+pub struct BlockReceiver {
+    blocks: BTreeMap<usize, ReceiverBlock>,
+    /// the number of blocks announced by the server
+    expected: Option<usize>,
+    control: FrameDecoder,
+    frames: FrameDecoder,
+    /// keep the data of the blocks until `take_data`
+    keep_data: bool,
+    /// the blocks dropped or abandoned by the server, until `take_given_up`
+    given_up: Vec<usize>,
+}
+
+impl BlockReceiver {
+    /// A receiver keeping the data of the blocks, see `take_data`
+    pub fn with_data() -> Self {
+        BlockReceiver { keep_data: true, ..Default::default() }
+    }
+
+    fn block_mut(&mut self, id: usize) -> &mut ReceiverBlock {
+        let keep_data = self.keep_data;
+        self.blocks.entry(id).or_insert_with(|| ReceiverBlock {
+            data: keep_data.then(Vec::new),
+            ..Default::default()
+        })
+    }
+
+    /// Feed data read from a stream into the receiver.
+    /// `now` is the current time in microseconds.
+    ///
+    /// Return the ids of the blocks this data completed.
+    pub fn on_stream_data(&mut self, stream_id: u64, buf: &[u8], fin: bool, now: u64) -> Vec<usize> {
+        if stream_id == CONTROL_STREAM_ID {
+            self.on_control_data(buf);
+            return Vec::new();
+        }
+        if stream_id == FRAMED_STREAM_ID {
+            return self.on_framed_data(buf, now);
+        }
+        let id = match stream_id_to_block_id(stream_id) {
+            Some(id) => id,
+            None => {
+                debug!("ignore data on non-block stream {}", stream_id);
+                return Vec::new();
+            }
+        };
+        let block = self.block_mut(id);
+        block.recv(buf, fin, now);
+        if fin && block.is_complete() {
+            debug!("block {} complete in {:?} us", id, block.completion_time());
+            return vec![id];
+        }
+        Vec::new()
+    }
+
+    /// The framed stream carries all the blocks as `StreamFrame`s
+    fn on_framed_data(&mut self, buf: &[u8], now: u64) -> Vec<usize> {
+        self.frames.push(buf);
+        let mut completed = Vec::new();
+        loop {
+            let frame = match self.frames.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    // there is no way to find the next frame, give up on the stream
+                    error!("invalid frame on the framed stream: {}", e);
+                    self.frames = FrameDecoder::default();
+                    break;
+                },
+            };
+            match frame {
+                StreamFrame::BlockInfo { id, size, priority, deadline, create_time } => {
+                    let header = BlockHeader {
+                        info: BlockInfo { id, size, priority, deadline },
+                        create_time,
+                    };
+                    self.block_mut(id).recv_info(header, now);
+                },
+                StreamFrame::BlockData { id, data } => {
+                    let block = self.block_mut(id);
+                    block.recv_data(data.len(), now);
+                    if let Some(kept) = block.data.as_mut() {
+                        kept.extend_from_slice(&data);
+                    }
+                    if block.is_complete() {
+                        debug!("block {} complete in {:?} us", id, block.completion_time());
+                        completed.push(id);
+                    }
+                },
+                frame => {
+                    warn!("ignore {:?} on the framed stream", frame);
+                },
+            }
+        }
+        trace!("{} bytes wait for the rest of their frame", self.frames.pending());
+        completed
+    }
+
+    /// Feed a QUIC DATAGRAM into the receiver, see `crate::datagram`.
+    ///
+    /// Return the id of the block this datagram completed.
+    pub fn on_datagram(&mut self, buf: &[u8], now: u64) -> Option<usize> {
+        let mut b = octets::Octets::with_slice(buf);
+        let header = match DatagramHeader::from_bytes(&mut b) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("ignore invalid datagram: {}", e);
+                return None;
+            },
+        };
+        let data = b.as_ref();
+        if let Some(group_size) = header.repair {
+            if data.is_empty() || group_size == 0 || group_size > MAX_GROUP_SIZE || header.offset > header.size {
+                warn!("ignore invalid repair symbol {:?} of {} bytes", header, data.len());
+                return None;
+            }
+        }
+        let block = self.block_mut(header.id);
+        if block.header.is_none() {
+            let info = BlockInfo { id: header.id, size: header.size, priority: header.priority, deadline: header.deadline };
+            block.header = Some(BlockHeader { info, create_time: header.create_time });
+        }
+        let was_complete = block.is_complete();
+        // the bytes of the block the datagram brings, none for duplicates
+        let new = match header.repair {
+            None => block.datagrams.on_source(header.offset, data, header.size),
+            Some(group_size) => {
+                let repair = RepairSymbol { offset: header.offset, group_size, data: data.to_vec() };
+                block.datagrams.on_repair(repair, header.size)
+            },
+        };
+        if new == 0 {
+            block.first_byte_time.get_or_insert(now);
+            return None;
+        }
+        block.recv_data(new, now);
+        if !was_complete && block.is_complete() {
+            if block.data.is_some() {
+                block.data = Some(block.datagrams.contiguous_data());
+            }
+            debug!("block {} complete in {:?} us", header.id, block.completion_time());
+            return Some(header.id);
+        }
+        None
+    }
+
+    /// The server reset a stream with `error_code`
+    pub fn on_stream_reset(&mut self, stream_id: u64, error_code: u64) {
+        let id = match stream_id_to_block_id(stream_id) {
+            Some(id) => id,
+            None => {
+                warn!("stream {} is reset with error {}", stream_id, error_code);
+                return;
+            }
+        };
+        if error_code != BLOCK_EXPIRED_ERROR {
+            warn!("the stream of block {} is reset with unknown error {}", id, error_code);
+        }
+        debug!("block {} is abandoned by the server", id);
+        self.block_mut(id).abandoned = true;
+        self.given_up.push(id);
+    }
+
+    /// The control stream announces the blocks of the trace, then tells
+    /// which blocks are dropped, see `CONTROL_STREAM_ID`
+    fn on_control_data(&mut self, buf: &[u8]) {
+        self.control.push(buf);
+        loop {
+            let frame = match self.control.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("invalid frame on the control stream: {}", e);
+                    self.control = FrameDecoder::default();
+                    break;
+                },
+            };
+            match frame {
+                StreamFrame::DtpConfig { cfg_len } => {
+                    info!("the server announces {} blocks", cfg_len);
+                    self.expected = Some(cfg_len);
+                },
+                StreamFrame::BlockInfo { id, size, priority, deadline, .. } => {
+                    self.block_mut(id).announced = Some(BlockInfo { id, size, priority, deadline });
+                },
+                StreamFrame::BlockDrop { id } => {
+                    debug!("block {} is dropped by the server", id);
+                    self.block_mut(id).dropped = true;
+                    self.given_up.push(id);
+                },
+                StreamFrame::BlockData { id, .. } => {
+                    warn!("ignore data of block {} on the control stream", id);
+                },
+                StreamFrame::BlockAck { id } => {
+                    warn!("ignore the acknowledgement of block {} on the control stream", id);
+                },
+            }
+        }
+    }
+
+    pub fn get_block(&self, id: usize) -> Option<&ReceiverBlock> {
+        self.blocks.get(&id)
+    }
+
+    /// Take the data of a block received by a receiver `with_data`, all of
+    /// it once the block is complete
+    pub fn take_data(&mut self, id: usize) -> Option<Vec<u8>> {
+        self.blocks.get_mut(&id)?.data.take()
+    }
+
+    /// Take the ids of the blocks the server dropped or abandoned since the
+    /// last call
+    pub fn take_given_up(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.given_up)
+    }
+
+    /// The number of blocks announced by the server, None before the announcement
+    pub fn expected(&self) -> Option<usize> {
+        self.expected
+    }
+
+    /// The number of blocks of which some data arrived
+    pub fn arrived(&self) -> usize {
+        self.blocks.values().filter(|b| b.has_arrived()).count()
+    }
+
+    /// Whether every announced block is either complete, dropped or abandoned
+    pub fn is_finished(&self) -> bool {
+        match self.expected {
+            Some(expected) => {
+                let done = self.blocks.values().filter(|b| b.is_complete() || b.is_dropped() || b.is_abandoned()).count();
+                done >= expected
+            },
+            None => false,
+        }
+    }
+
+    /// Write the per-block report, one line per block:
+    ///
+    /// `id,size,priority,deadline(ms),received(B),first_byte(ms),completion(ms),status`
+    ///
+    /// where status is one of `met`, `missed` (complete but late), `dropped`
+    /// (the server gave up on it, `received` tells whether it was ever sent),
+    /// `abandoned` (the server reset its stream when the deadline passed),
+    /// `incomplete` or `never_arrived` (announced but no data arrived).
+    pub fn write_report<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "id,size,priority,deadline,received,first_byte,completion,status")?;
+        for (id, block) in self.blocks.iter() {
+            let (size, priority, deadline) = match block.info() {
+                Some(info) => (info.size.to_string(), info.priority.to_string(), info.deadline.to_string()),
+                None => (String::new(), String::new(), String::new()),
+            };
+            let to_ms = |t: Option<u64>| t.map(|t| format!("{:.3}", t as f64 / 1000.0)).unwrap_or_default();
+            let status = if block.is_dropped() {
+                "dropped"
+            } else if block.is_abandoned() {
+                "abandoned"
+            } else if !block.has_arrived() {
+                "never_arrived"
+            } else if !block.is_complete() {
+                "incomplete"
+            } else if block.is_deadline_met() {
+                "met"
+            } else {
+                "missed"
+            };
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{}",
+                id,
+                size,
+                priority,
+                deadline,
+                block.received,
+                to_ms(block.first_byte_delay()),
+                to_ms(block.completion_time()),
+                status
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Log the outcome of the blocks and write the report into the file at
+    /// `report_path`, or stdout
+    pub fn finish(&self, report_path: Option<&str>) {
+        let summary = self.summary();
+        let expected = self.expected().map(|n| n.to_string()).unwrap_or_else(|| "?".to_owned());
+        info!(
+            "client received {}/{} blocks, {} complete, {} met the deadline, {} dropped and {} abandoned by the server",
+            self.arrived(),
+            expected,
+            summary.complete,
+            summary.met,
+            summary.dropped,
+            summary.abandoned
+        );
+
+        let written = match report_path {
+            Some(path) => std::fs::File::create(path)
+                .and_then(|mut f| self.write_report(&mut f)),
+            None => self.write_report(&mut std::io::stdout()),
+        };
+        if let Err(e) = written {
+            error!("failed to write the block report: {:?}", e);
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        let count = |f: fn(&ReceiverBlock) -> bool| self.blocks.values().filter(|b| f(b)).count();
+        Summary {
+            complete: count(ReceiverBlock::is_complete),
+            met: count(ReceiverBlock::is_deadline_met),
+            dropped: count(ReceiverBlock::is_dropped),
+            abandoned: count(ReceiverBlock::is_abandoned),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::block_id_to_stream_id;
+
+    fn header(id: usize, size: usize, create_time: u64) -> [u8; BLOCK_HEADER_LEN] {
+        BlockHeader {
+            info: BlockInfo { id, size, priority: 1, deadline: 200 },
+            create_time,
+        }.to_bytes()
+    }
+
+    /// The beginning of the control stream for blocks of (id, size)
+    fn announcement(blocks: &[(usize, usize)]) -> Vec<u8> {
+        let mut buf = StreamFrame::DtpConfig { cfg_len: blocks.len() }.to_vec();
+        for &(id, size) in blocks {
+            let info = BlockInfo { id, size, priority: 1, deadline: 200 };
+            buf.extend_from_slice(&StreamFrame::block_info(&info, 0).to_vec());
+        }
+        buf
+    }
+
+    #[test]
+    fn reassemble_split_header() {
+        let mut receiver = BlockReceiver::default();
+        let stream_id = block_id_to_stream_id(3);
+        let h = header(3, 10, 1_000);
+
+        assert!(receiver.on_stream_data(stream_id, &h[..7], false, 1_100).is_empty());
+        assert!(receiver.get_block(3).unwrap().info().is_none());
+
+        let mut rest = h[7..].to_vec();
+        rest.extend_from_slice(&[0u8; 4]);
+        assert!(receiver.on_stream_data(stream_id, &rest, false, 1_200).is_empty());
+        assert_eq!(receiver.get_block(3).unwrap().info().unwrap().size, 10);
+
+        assert_eq!(receiver.on_stream_data(stream_id, &[0u8; 6], true, 51_000), vec![3]);
+        let block = receiver.get_block(3).unwrap();
+        assert_eq!(block.first_byte_delay(), Some(100));
+        assert_eq!(block.completion_time(), Some(50_000));
+        assert!(block.is_deadline_met());
+    }
+
+    #[test]
+    fn report_statuses() {
+        let mut receiver = BlockReceiver::default();
+        // met
+        let mut buf = header(0, 2, 0).to_vec();
+        buf.extend_from_slice(&[1, 2]);
+        receiver.on_stream_data(block_id_to_stream_id(0), &buf, true, 100_000);
+        // missed
+        let mut buf = header(1, 2, 0).to_vec();
+        buf.extend_from_slice(&[1, 2]);
+        receiver.on_stream_data(block_id_to_stream_id(1), &buf, true, 300_000);
+        // incomplete
+        let mut buf = header(2, 2, 0).to_vec();
+        buf.push(1);
+        receiver.on_stream_data(block_id_to_stream_id(2), &buf, false, 300_000);
+
+        let mut out = Vec::new();
+        receiver.write_report(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[1], "0,2,1,200,2,100.000,100.000,met");
+        assert_eq!(lines[2], "1,2,1,200,2,300.000,300.000,missed");
+        assert_eq!(lines[3], "2,2,1,200,1,300.000,,incomplete");
+        assert_eq!(receiver.summary(), Summary { complete: 2, met: 1, ..Default::default() });
+    }
+
+    #[test]
+    fn dropped_blocks() {
+        let mut receiver = BlockReceiver::default();
+        // block 0 was partially sent before being dropped
+        let mut buf = header(0, 4, 0).to_vec();
+        buf.extend_from_slice(&[1, 2]);
+        receiver.on_stream_data(block_id_to_stream_id(0), &buf, false, 10_000);
+
+        // block 1 was never sent, the control stream is split in two reads
+        let mut control = announcement(&[(0, 4), (1, 8)]);
+        control.extend_from_slice(&StreamFrame::BlockDrop { id: 0 }.to_vec());
+        control.extend_from_slice(&StreamFrame::BlockDrop { id: 1 }.to_vec());
+        let split = control.len() - 3;
+        receiver.on_stream_data(CONTROL_STREAM_ID, &control[..split], false, 20_000);
+        receiver.on_stream_data(CONTROL_STREAM_ID, &control[split..], false, 20_000);
+
+        let mut out = Vec::new();
+        receiver.write_report(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[1], "0,4,1,200,2,10.000,,dropped");
+        assert_eq!(lines[2], "1,8,1,200,0,,,dropped");
+        assert_eq!(receiver.summary(), Summary { dropped: 2, ..Default::default() });
+        assert!(receiver.is_finished());
+    }
+
+    #[test]
+    fn malformed_repair_symbols() {
+        let mut receiver = BlockReceiver::default();
+        let header = DatagramHeader { id: 0, offset: 0, size: 1000, priority: 1, deadline: 200, create_time: 0, repair: Some(4) };
+        let malformed = [
+            header.to_vec(&[]),
+            DatagramHeader { repair: Some(0), ..header }.to_vec(&[1; 100]),
+            DatagramHeader { repair: Some(1 << 40), ..header }.to_vec(&[1; 100]),
+            DatagramHeader { offset: 1001, ..header }.to_vec(&[1; 100]),
+        ];
+        for dgram in malformed.iter() {
+            assert_eq!(receiver.on_datagram(dgram, 1_000), None);
+        }
+        assert!(receiver.get_block(0).is_none());
+
+        // a valid repair symbol of the last piece of the block
+        let source = DatagramHeader { repair: None, ..header };
+        assert_eq!(receiver.on_datagram(&source.to_vec(&[1; 500]), 2_000), None);
+        let repair = DatagramHeader { offset: 500, repair: Some(1), ..header };
+        assert_eq!(receiver.on_datagram(&repair.to_vec(&[2; 500]), 3_000), Some(0));
+    }
+
+    #[test]
+    fn unannounced_datagram_block() {
+        let mut receiver = BlockReceiver::default();
+        let header = DatagramHeader { id: 7, offset: 0, size: 1000, priority: 3, deadline: 100, create_time: 0, repair: None };
+        assert_eq!(receiver.on_datagram(&header.to_vec(&[1; 600]), 40_000), None);
+        let rest = DatagramHeader { offset: 600, ..header };
+        assert_eq!(receiver.on_datagram(&rest.to_vec(&[1; 400]), 90_000), Some(7));
+
+        let block = receiver.get_block(7).unwrap();
+        let info = block.info().unwrap();
+        assert_eq!((info.size, info.priority, info.deadline), (1000, 3, 100));
+        assert!(block.is_deadline_met());
+        assert_eq!(receiver.summary(), Summary { complete: 1, met: 1, ..Default::default() });
+    }
+
+    #[test]
+    fn announced_blocks() {
+        let mut receiver = BlockReceiver::default();
+        let mut buf = header(0, 2, 0).to_vec();
+        buf.extend_from_slice(&[1, 2]);
+        // the data of block 0 may arrive before the announcement
+        receiver.on_stream_data(block_id_to_stream_id(0), &buf, true, 10_000);
+        assert_eq!(receiver.expected(), None);
+        assert!(!receiver.is_finished());
+
+        receiver.on_stream_data(CONTROL_STREAM_ID, &announcement(&[(0, 2), (1, 2)]), false, 20_000);
+        assert_eq!(receiver.expected(), Some(2));
+        assert_eq!(receiver.arrived(), 1);
+        assert!(!receiver.is_finished());
+
+        let mut out = Vec::new();
+        receiver.write_report(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[1], "0,2,1,200,2,10.000,10.000,met");
+        assert_eq!(lines[2], "1,2,1,200,0,,,never_arrived");
+
+        let mut buf = header(1, 2, 0).to_vec();
+        buf.extend_from_slice(&[1, 2]);
+        receiver.on_stream_data(block_id_to_stream_id(1), &buf, true, 30_000);
+        assert!(receiver.is_finished());
+    }
+}
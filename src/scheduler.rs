@@ -0,0 +1,177 @@
+mod dtp;
+mod edf;
+mod plugin;
+mod priority;
+
+pub use dtp::{DtpCoefficients, DtpScheduler};
+pub use edf::EdfScheduler;
+pub use plugin::PluginScheduler;
+pub use priority::{PriorityScheduler, WeightedFairScheduler};
+
+use anyhow::{anyhow, Result};
+
+use crate::sender::{SenderBlock, SenderDeque};
+
+/// What the sender knows about the network when it picks a block
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkStats {
+    /// smoothed round trip time, in microseconds
+    pub rtt: u64,
+    /// estimated delivery rate, in bytes per second, 0 if unknown
+    pub bandwidth: u64,
+    /// congestion window, in bytes
+    pub cwnd: usize,
+}
+
+impl NetworkStats {
+    /// Read the estimates of the active path of the connection
+    pub fn from_connection(conn: &quiche::Connection) -> Self {
+        match conn.path_stats().find(|p| p.active) {
+            Some(path) => {
+                let rtt = path.rtt.as_micros() as u64;
+                // before the first delivery rate sample, one window per RTT
+                let bandwidth = if path.delivery_rate > 0 {
+                    path.delivery_rate
+                } else {
+                    (path.cwnd as u64 * 1_000_000).checked_div(rtt).unwrap_or(0)
+                };
+                NetworkStats { rtt, bandwidth, cwnd: path.cwnd }
+            },
+            None => NetworkStats::default(),
+        }
+    }
+}
+
+/// A policy deciding which block is sent next.
+///
+/// The scheduler owns the blocks waiting to be sent. The sender asks it for
+/// a block every time the connection can take more data, and reports back
+/// how the block is progressing. All times are in microseconds, on the same
+/// clock as `SenderBlock::create_time`.
+pub trait BlockScheduler {
+    /// Add a newly generated block
+    fn push_block(&mut self, block: SenderBlock);
+
+    /// Pick the block to send now, None if there is nothing to send
+    fn next_block_to_send_mut(&mut self, now: u64) -> Option<&mut SenderBlock>;
+
+    /// New estimates of the network, given before picking blocks
+    fn on_network_update(&mut self, _stats: &NetworkStats) {}
+
+    /// `sent` more bytes of the block were written to the connection
+    fn on_block_progress(&mut self, _id: usize, _sent: usize) {}
+
+    /// The block was sent completely, remove it from the scheduler
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock>;
+
+    /// Remove a block the sender gives up on before it is sent completely
+    fn remove_block(&mut self, id: usize) -> Option<SenderBlock> {
+        self.on_block_complete(id)
+    }
+
+    /// Called before picking blocks, remove and return the blocks the policy
+    /// gives up on because their deadline has passed. Keep everything by default.
+    fn on_expire(&mut self, _now: u64) -> Vec<SenderBlock> {
+        Vec::new()
+    }
+
+    /// The number of blocks waiting to be sent
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Box<dyn BlockScheduler> {
+    fn default() -> Self {
+        Box::<SenderDeque>::default()
+    }
+}
+
+/// The tunable parameters of the schedulers
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    /// The weights of priority 1, 2, 3... for the weighted fair scheduler
+    pub priority_weights: Vec<f64>,
+    /// The weights of the score of the dtp scheduler
+    pub dtp_coefficients: DtpCoefficients,
+    /// The shared object loaded by the plugin scheduler
+    pub plugin_path: Option<String>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            priority_weights: vec![1.0, 2.0, 3.0],
+            dtp_coefficients: DtpCoefficients::default(),
+            plugin_path: None,
+        }
+    }
+}
+
+/// The names accepted by `new_scheduler`
+pub const SCHEDULERS: &[&str] = &["fifo", "edf", "priority", "wfq", "dtp", "plugin"];
+
+/// Create a scheduler by its name
+pub fn new_scheduler(name: &str, config: &SchedulerConfig) -> Result<Box<dyn BlockScheduler>> {
+    Ok(match name {
+        "fifo" => Box::<SenderDeque>::default(),
+        "edf" => Box::<EdfScheduler>::default(),
+        "priority" => Box::<PriorityScheduler>::default(),
+        "wfq" => Box::new(WeightedFairScheduler::new(config.priority_weights.clone())),
+        "dtp" => Box::new(DtpScheduler::new(config.dtp_coefficients)),
+        "plugin" => {
+            let path = config.plugin_path.as_ref()
+                .ok_or_else(|| anyhow!("the plugin scheduler needs the path of a shared object"))?;
+            Box::new(PluginScheduler::load(path)?)
+        },
+        _ => return Err(anyhow!("unknown scheduler {}, expected one of {:?}", name, SCHEDULERS)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockInfo;
+
+    /// Build a block of the trace generated at `create_time` (ms)
+    pub fn block(id: usize, size: usize, priority: usize, deadline: usize, create_time: u64) -> SenderBlock {
+        SenderBlock::new(BlockInfo { id, size, priority, deadline }, create_time * 1000)
+    }
+
+    /// Send every block `chunk` bytes at a time like `StreamSender::send`,
+    /// return the id of the block picked for every chunk
+    pub fn send_order(scheduler: &mut dyn BlockScheduler, chunk: usize) -> Vec<usize> {
+        let mut order = Vec::new();
+        while let Some(b) = scheduler.next_block_to_send_mut(0) {
+            let sent = b.remain_bytes().min(chunk);
+            b.send_bytes(sent);
+            let (id, complete) = (b.info.id, b.is_send_complete());
+            order.push(id);
+            scheduler.on_block_progress(id, sent);
+            if complete {
+                scheduler.on_block_complete(id);
+            }
+        }
+        order
+    }
+
+    #[test]
+    fn fifo_keeps_generation_order() {
+        let mut scheduler = new_scheduler("fifo", &SchedulerConfig::default()).unwrap();
+        scheduler.push_block(block(0, 10, 1, 200, 0));
+        scheduler.push_block(block(1, 10, 3, 50, 1));
+        scheduler.push_block(block(2, 10, 2, 100, 2));
+        assert_eq!(scheduler.on_expire(1_000_000).len(), 0);
+
+        assert_eq!(send_order(scheduler.as_mut(), 10), vec![0, 1, 2]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn unknown_scheduler() {
+        assert!(new_scheduler("lifo", &SchedulerConfig::default()).is_err());
+        assert!(new_scheduler("plugin", &SchedulerConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::scheduler::{BlockScheduler, NetworkStats};
+use crate::sender::SenderBlock;
+use dtp_utils::PRIORITIES;
+
+/// The weights of the terms of the DTP score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DtpCoefficients {
+    /// favour the blocks of high priority
+    pub priority: f64,
+    /// favour the blocks close to their deadline
+    pub deadline: f64,
+    /// favour the blocks that can be finished quickly
+    pub size: f64,
+    /// subtracted from the blocks that are expected to miss their deadline
+    pub late_penalty: f64,
+}
+
+impl Default for DtpCoefficients {
+    fn default() -> Self {
+        DtpCoefficients {
+            priority: 1.0,
+            deadline: 1.0,
+            size: 1.0,
+            late_penalty: 10.0,
+        }
+    }
+}
+
+/// The scheduler of DTP: every time the connection is writable, score each
+/// pending block from its priority, the time left before its deadline and
+/// the time needed to deliver its remaining bytes with the current bandwidth
+/// and RTT estimates, and send the best one.
+///
+/// All the terms are normalized to [0, 1]:
+///
+/// - priority: `priority / max priority`
+/// - deadline: `1 - time left / deadline`
+/// - size: `1 - delivery time / deadline`
+///
+/// where delivery time is `RTT / 2 + remaining bytes / bandwidth`. Blocks
+/// whose delivery time exceeds the time left get the late penalty, so they
+/// are only sent when nothing else can make it.
+#[derive(Default)]
+pub struct DtpScheduler {
+    coefficients: DtpCoefficients,
+    stats: NetworkStats,
+    blocks: Vec<SenderBlock>,
+}
+
+impl DtpScheduler {
+    pub fn new(coefficients: DtpCoefficients) -> Self {
+        DtpScheduler {
+            coefficients,
+            ..Default::default()
+        }
+    }
+
+    /// The estimated time to deliver the rest of the block, in microseconds
+    fn delivery_time(&self, block: &SenderBlock) -> f64 {
+        let transfer = if self.stats.bandwidth > 0 {
+            block.remain_bytes() as f64 * 1_000_000.0 / self.stats.bandwidth as f64
+        } else {
+            0.0
+        };
+        self.stats.rtt as f64 / 2.0 + transfer
+    }
+
+    fn score(&self, block: &SenderBlock, now: u64) -> f64 {
+        let c = &self.coefficients;
+        let deadline = (block.info.deadline as f64 * 1000.0).max(1.0);
+        let time_left = block.deadline_time() as f64 - now as f64;
+        let delivery_time = self.delivery_time(block);
+
+        let priority = block.info.priority as f64 / *PRIORITIES.end() as f64;
+        let urgency = 1.0 - (time_left / deadline).clamp(0.0, 1.0);
+        let shortness = 1.0 - (delivery_time / deadline).clamp(0.0, 1.0);
+
+        let mut score = c.priority * priority + c.deadline * urgency + c.size * shortness;
+        if delivery_time > time_left {
+            score -= c.late_penalty;
+        }
+        score
+    }
+}
+
+impl BlockScheduler for DtpScheduler {
+    fn push_block(&mut self, block: SenderBlock) {
+        self.blocks.push(block);
+    }
+    fn next_block_to_send_mut(&mut self, now: u64) -> Option<&mut SenderBlock> {
+        let mut best: Option<(usize, f64)> = None;
+        for (index, block) in self.blocks.iter().enumerate() {
+            let score = self.score(block, now);
+            trace!("dtp score of block {}: {}", block.info.id, score);
+            // the blocks are kept in generation order, the oldest wins on ties,
+            // `Option::is_none_or` needs Rust 1.82
+            #[allow(clippy::unnecessary_map_or)]
+            if best.map_or(true, |(_, s)| score > s) {
+                best = Some((index, score));
+            }
+        }
+        let (index, _) = best?;
+        self.blocks.get_mut(index)
+    }
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let index = self.blocks.iter().position(|b| b.info.id == id)?;
+        Some(self.blocks.remove(index))
+    }
+    fn on_network_update(&mut self, stats: &NetworkStats) {
+        self.stats = *stats;
+    }
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::tests::block;
+
+    fn scheduler(coefficients: DtpCoefficients) -> DtpScheduler {
+        let mut scheduler = DtpScheduler::new(coefficients);
+        // 1 MB/s, 20 ms
+        scheduler.on_network_update(&NetworkStats { rtt: 20_000, bandwidth: 1_000_000, cwnd: 0 });
+        scheduler
+    }
+
+    fn next(scheduler: &mut DtpScheduler, now_ms: u64) -> usize {
+        scheduler.next_block_to_send_mut(now_ms * 1000).unwrap().info.id
+    }
+
+    #[test]
+    fn priority_first_when_both_can_make_it() {
+        let mut s = scheduler(DtpCoefficients::default());
+        s.push_block(block(0, 10_000, 1, 200, 0));
+        s.push_block(block(1, 10_000, 3, 200, 0));
+        assert_eq!(next(&mut s, 0), 1);
+    }
+
+    #[test]
+    fn skip_blocks_that_will_be_late() {
+        let mut s = scheduler(DtpCoefficients::default());
+        // 150 KB needs 160 ms but only 100 ms are left
+        s.push_block(block(0, 150_000, 3, 200, 0));
+        s.push_block(block(1, 10_000, 1, 200, 0));
+        assert_eq!(next(&mut s, 100), 1);
+        // when nothing can make it, still send the best of them
+        s.on_block_complete(1);
+        assert_eq!(next(&mut s, 100), 0);
+    }
+
+    #[test]
+    fn deadline_weight_favours_urgent_blocks() {
+        let coefficients = DtpCoefficients { priority: 0.0, deadline: 1.0, size: 0.0, late_penalty: 0.0 };
+        let mut s = scheduler(coefficients);
+        s.push_block(block(0, 1_000, 1, 200, 50));
+        s.push_block(block(1, 1_000, 1, 200, 0));
+        assert_eq!(next(&mut s, 60), 1);
+    }
+
+    #[test]
+    fn size_weight_favours_short_blocks() {
+        let coefficients = DtpCoefficients { priority: 0.0, deadline: 0.0, size: 1.0, late_penalty: 0.0 };
+        let mut s = scheduler(coefficients);
+        s.push_block(block(0, 100_000, 1, 200, 0));
+        s.push_block(block(1, 1_000, 1, 200, 0));
+        assert_eq!(next(&mut s, 0), 1);
+    }
+}
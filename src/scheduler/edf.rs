@@ -0,0 +1,74 @@
+use crate::scheduler::BlockScheduler;
+use crate::sender::SenderBlock;
+
+/// Earliest deadline first: always send the block whose absolute deadline
+/// (generation time + `BlockInfo.deadline`) comes first, and drop the blocks
+/// that can no longer make it instead of wasting bandwidth on them
+#[derive(Default)]
+pub struct EdfScheduler {
+    blocks: Vec<SenderBlock>,
+}
+
+impl BlockScheduler for EdfScheduler {
+    fn push_block(&mut self, block: SenderBlock) {
+        self.blocks.push(block);
+    }
+    fn next_block_to_send_mut(&mut self, _now: u64) -> Option<&mut SenderBlock> {
+        // ties are broken by the generation order
+        self.blocks
+            .iter_mut()
+            .min_by_key(|b| (b.deadline_time(), b.info.id))
+    }
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let index = self.blocks.iter().position(|b| b.info.id == id)?;
+        Some(self.blocks.remove(index))
+    }
+    fn on_expire(&mut self, now: u64) -> Vec<SenderBlock> {
+        let (expired, pending) = std::mem::take(&mut self.blocks)
+            .into_iter()
+            .partition(|b| b.deadline_time() < now);
+        self.blocks = pending;
+        expired
+    }
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::tests::block;
+
+    #[test]
+    fn earliest_deadline_first() {
+        let mut scheduler = EdfScheduler::default();
+        // absolute deadlines: 200, 51, 102, 102
+        scheduler.push_block(block(0, 10, 1, 200, 0));
+        scheduler.push_block(block(1, 10, 1, 50, 1));
+        scheduler.push_block(block(2, 10, 1, 100, 2));
+        scheduler.push_block(block(3, 10, 1, 99, 3));
+
+        let mut order = Vec::new();
+        while let Some(b) = scheduler.next_block_to_send_mut(0) {
+            let id = b.info.id;
+            order.push(id);
+            scheduler.on_block_complete(id);
+        }
+        assert_eq!(order, vec![1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn drop_expired_blocks() {
+        let mut scheduler = EdfScheduler::default();
+        scheduler.push_block(block(0, 10, 1, 200, 0));
+        scheduler.push_block(block(1, 10, 1, 50, 0));
+        scheduler.push_block(block(2, 10, 1, 100, 0));
+
+        // at 100ms, only block 1 is late
+        let expired: Vec<usize> = scheduler.on_expire(100_000).iter().map(|b| b.info.id).collect();
+        assert_eq!(expired, vec![1]);
+        assert_eq!(scheduler.len(), 2);
+        assert_eq!(scheduler.next_block_to_send_mut(100_000).unwrap().info.id, 2);
+    }
+}
@@ -0,0 +1,237 @@
+use std::ffi::{CStr, CString, c_void};
+
+use anyhow::{anyhow, Result};
+
+use crate::block::BlockInfo;
+use crate::scheduler::{BlockScheduler, NetworkStats};
+use crate::sender::SenderBlock;
+
+/// `struct SolutionBlock` in `dtp_utils/include/solution.h`
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SolutionBlock {
+    pub info: BlockInfo,
+    pub create_time: u64,
+    pub remaining_size: u64,
+}
+
+/// `struct SolutionCcInfo` in `dtp_utils/include/solution.h`
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SolutionCcInfo {
+    pub event_time: u64,
+    pub rtt: u64,
+    pub bandwidth: u64,
+    pub congestion_window: u64,
+}
+
+type SolutionInit = unsafe extern "C" fn(*mut u64, *mut u64);
+type SolutionSelectBlock = unsafe extern "C" fn(*const SolutionBlock, u64, u64) -> u64;
+type SolutionCcTrigger = unsafe extern "C" fn(*const SolutionCcInfo, *mut u64, *mut u64);
+
+/// A scheduler written in C following the AItrans solution format,
+/// loaded from a shared object at runtime.
+///
+/// See `dtp_utils/include/solution.h` for the interface. The network
+/// estimates are given to `SolutionCcTrigger` right before the next
+/// `SolutionSelectBlock`, so that the plugin sees the time of the scheduler.
+///
+/// Every connection loads the plugin again and runs `SolutionInit`, but
+/// `dlopen` returns the library already loaded: the static variables of the
+/// plugin are shared by all the connections of the server.
+pub struct PluginScheduler {
+    handle: *mut c_void,
+    select_block: SolutionSelectBlock,
+    cc_trigger: Option<SolutionCcTrigger>,
+    // the estimates not given to `cc_trigger` yet
+    network: Option<NetworkStats>,
+    blocks: Vec<SenderBlock>,
+    // reused for every call of `SolutionSelectBlock`
+    solution_blocks: Vec<SolutionBlock>,
+}
+
+impl PluginScheduler {
+    pub fn load(path: &str) -> Result<Self> {
+        let c_path = CString::new(path)?;
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(anyhow!("failed to load scheduler plugin {}: {}", path, dlerror()));
+        }
+        let select_block = match symbol(handle, "SolutionSelectBlock") {
+            Some(f) => unsafe { std::mem::transmute::<*mut c_void, SolutionSelectBlock>(f) },
+            None => {
+                unsafe { libc::dlclose(handle) };
+                return Err(anyhow!("scheduler plugin {} has no SolutionSelectBlock", path));
+            },
+        };
+        let cc_trigger = symbol(handle, "SolutionCcTrigger")
+            .map(|f| unsafe { std::mem::transmute::<*mut c_void, SolutionCcTrigger>(f) });
+        if let Some(init) = symbol(handle, "SolutionInit") {
+            let init = unsafe { std::mem::transmute::<*mut c_void, SolutionInit>(init) };
+            let (mut cwnd, mut pacing_rate) = (0, 0);
+            unsafe { init(&mut cwnd, &mut pacing_rate) };
+            info!("scheduler plugin {} initialized, cwnd {} pacing rate {}", path, cwnd, pacing_rate);
+        }
+        Ok(PluginScheduler {
+            handle,
+            select_block,
+            cc_trigger,
+            network: None,
+            blocks: Vec::new(),
+            solution_blocks: Vec::new(),
+        })
+    }
+}
+
+fn symbol(handle: *mut c_void, name: &str) -> Option<*mut c_void> {
+    let c_name = CString::new(name).unwrap();
+    let f = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+    if f.is_null() {
+        None
+    } else {
+        Some(f)
+    }
+}
+
+fn dlerror() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        "unknown error".to_owned()
+    } else {
+        unsafe { CStr::from_ptr(err) }.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for PluginScheduler {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+impl BlockScheduler for PluginScheduler {
+    fn push_block(&mut self, block: SenderBlock) {
+        self.blocks.push(block);
+    }
+    fn next_block_to_send_mut(&mut self, now: u64) -> Option<&mut SenderBlock> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        if let (Some(cc_trigger), Some(stats)) = (self.cc_trigger, self.network.take()) {
+            let cc_info = SolutionCcInfo {
+                event_time: now,
+                rtt: stats.rtt,
+                bandwidth: stats.bandwidth,
+                congestion_window: stats.cwnd as u64,
+            };
+            let (mut cwnd, mut pacing_rate) = (0, 0);
+            unsafe { cc_trigger(&cc_info, &mut cwnd, &mut pacing_rate) };
+            trace!("scheduler plugin suggests cwnd {} pacing rate {}", cwnd, pacing_rate);
+        }
+        self.solution_blocks.clear();
+        self.solution_blocks.extend(self.blocks.iter().map(|b| SolutionBlock {
+            info: b.info,
+            create_time: b.create_time,
+            remaining_size: b.remain_bytes() as u64,
+        }));
+        let index = unsafe {
+            (self.select_block)(self.solution_blocks.as_ptr(), self.solution_blocks.len() as u64, now)
+        } as usize;
+        if index >= self.blocks.len() {
+            warn!("scheduler plugin selected block {} out of {}, send the first one", index, self.blocks.len());
+            return self.blocks.first_mut();
+        }
+        self.blocks.get_mut(index)
+    }
+    fn on_network_update(&mut self, stats: &NetworkStats) {
+        self.network = Some(*stats);
+    }
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let index = self.blocks.iter().position(|b| b.info.id == id)?;
+        Some(self.blocks.remove(index))
+    }
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::tests::{block, send_order};
+    use std::process::Command;
+
+    /// Build `dtp_utils/src/solution_example.c` into a shared object
+    fn build_example() -> String {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        build_plugin("example", &format!("{}/dtp_utils/src/solution_example.c", manifest_dir))
+    }
+
+    /// Build the C source at `source` into a shared object named after `name`
+    fn build_plugin(name: &str, source: &str) -> String {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let out = std::env::temp_dir().join(format!("libsolution_{}_{}.so", name, std::process::id()));
+        let status = Command::new("cc")
+            .args(["-shared", "-fPIC", "-I"])
+            .arg(format!("{}/dtp_utils/include", manifest_dir))
+            .arg(source)
+            .arg("-o")
+            .arg(&out)
+            .status()
+            .expect("failed to run cc");
+        assert!(status.success());
+        out.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn example_plugin() {
+        let path = build_example();
+        let mut scheduler = PluginScheduler::load(&path).unwrap();
+        scheduler.push_block(block(0, 10, 1, 200, 0));
+        scheduler.push_block(block(1, 10, 2, 200, 5));
+        scheduler.push_block(block(2, 10, 2, 100, 5));
+        scheduler.push_block(block(3, 10, 1, 50, 0));
+        assert_eq!(send_order(&mut scheduler, 10), vec![2, 1, 3, 0]);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn network_estimates() {
+        // select the second block only once the estimates arrived at the time of the selection
+        let source = std::env::temp_dir().join(format!("solution_cc_{}.c", std::process::id()));
+        std::fs::write(&source, r#"
+#include "solution.h"
+static struct SolutionCcInfo last;
+uint64_t SolutionSelectBlock(const struct SolutionBlock *blocks, uint64_t block_num, uint64_t current_time)
+{
+    (void)blocks; (void)block_num;
+    return last.event_time == current_time && last.rtt == 20000 &&
+        last.bandwidth == 1000000 && last.congestion_window == 13500;
+}
+void SolutionCcTrigger(const struct SolutionCcInfo *cc_info, uint64_t *congestion_window, uint64_t *pacing_rate)
+{
+    last = *cc_info;
+    *congestion_window = cc_info->congestion_window;
+    *pacing_rate = cc_info->bandwidth;
+}
+"#).unwrap();
+        let path = build_plugin("cc", source.to_str().unwrap());
+        let mut scheduler = PluginScheduler::load(&path).unwrap();
+        scheduler.push_block(block(0, 10, 1, 200, 0));
+        scheduler.push_block(block(1, 10, 1, 200, 0));
+        assert_eq!(scheduler.next_block_to_send_mut(5_000).unwrap().info.id, 0);
+
+        scheduler.on_network_update(&NetworkStats { rtt: 20_000, bandwidth: 1_000_000, cwnd: 13_500 });
+        assert_eq!(scheduler.next_block_to_send_mut(7_000).unwrap().info.id, 1);
+        // the estimates are given once, at the time of the first selection after them
+        assert_eq!(scheduler.next_block_to_send_mut(8_000).unwrap().info.id, 0);
+        std::fs::remove_file(path).ok();
+        std::fs::remove_file(source).ok();
+    }
+
+    #[test]
+    fn missing_plugin() {
+        assert!(PluginScheduler::load("/nonexistent/libsolution.so").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::scheduler::BlockScheduler;
+use crate::sender::SenderBlock;
+
+/// Strict priority: always send a block of the highest priority class,
+/// lower classes only get the bandwidth left over. Blocks of the same
+/// class are sent in the order they are generated.
+#[derive(Default)]
+pub struct PriorityScheduler {
+    blocks: Vec<SenderBlock>,
+}
+
+impl BlockScheduler for PriorityScheduler {
+    fn push_block(&mut self, block: SenderBlock) {
+        self.blocks.push(block);
+    }
+    fn next_block_to_send_mut(&mut self, _now: u64) -> Option<&mut SenderBlock> {
+        self.blocks
+            .iter_mut()
+            .max_by_key(|b| (b.info.priority, std::cmp::Reverse(b.info.id)))
+    }
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let index = self.blocks.iter().position(|b| b.info.id == id)?;
+        Some(self.blocks.remove(index))
+    }
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[derive(Default)]
+struct PriorityClass {
+    queue: VecDeque<SenderBlock>,
+    /// bytes sent from this class divided by its weight
+    virtual_time: f64,
+}
+
+/// Weighted fair queueing between the priority classes: every class with
+/// pending blocks gets a share of the bandwidth proportional to its weight.
+/// Blocks of the same class are sent in the order they are generated.
+pub struct WeightedFairScheduler {
+    /// the weight of priority `p` is `weights[p - 1]`
+    weights: Vec<f64>,
+    classes: BTreeMap<usize, PriorityClass>,
+    block_priority: HashMap<usize, usize>,
+    /// the virtual time of the class being served, kept while every class
+    /// is idle
+    virtual_time: f64,
+}
+
+impl WeightedFairScheduler {
+    pub fn new(weights: Vec<f64>) -> Self {
+        WeightedFairScheduler {
+            weights,
+            classes: BTreeMap::new(),
+            block_priority: HashMap::new(),
+            virtual_time: 0.0,
+        }
+    }
+    fn weight(&self, priority: usize) -> f64 {
+        priority
+            .checked_sub(1)
+            .and_then(|i| self.weights.get(i))
+            .copied()
+            .filter(|w| *w > 0.0)
+            .unwrap_or(1.0)
+    }
+    fn min_active_virtual_time(&self) -> Option<f64> {
+        self.classes
+            .values()
+            .filter(|c| !c.queue.is_empty())
+            .map(|c| c.virtual_time)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+}
+
+impl BlockScheduler for WeightedFairScheduler {
+    fn push_block(&mut self, block: SenderBlock) {
+        let priority = block.info.priority;
+        // an idle class must not build up credit while it has nothing to send
+        let class = self.classes.entry(priority).or_default();
+        class.virtual_time = class.virtual_time.max(self.virtual_time);
+        self.block_priority.insert(block.info.id, priority);
+        class.queue.push_back(block);
+    }
+    fn next_block_to_send_mut(&mut self, _now: u64) -> Option<&mut SenderBlock> {
+        // the class that is the most behind its share, the highest priority on ties
+        let class = self
+            .classes
+            .values_mut()
+            .rev()
+            .filter(|c| !c.queue.is_empty())
+            .min_by(|a, b| a.virtual_time.total_cmp(&b.virtual_time))?;
+        class.queue.front_mut()
+    }
+    fn on_block_progress(&mut self, id: usize, sent: usize) {
+        if let Some(&priority) = self.block_priority.get(&id) {
+            let weight = self.weight(priority);
+            if let Some(class) = self.classes.get_mut(&priority) {
+                class.virtual_time += sent as f64 / weight;
+            }
+            if let Some(virtual_time) = self.min_active_virtual_time() {
+                self.virtual_time = self.virtual_time.max(virtual_time);
+            }
+        }
+    }
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let priority = self.block_priority.remove(&id)?;
+        let class = self.classes.get_mut(&priority)?;
+        let index = class.queue.iter().position(|b| b.info.id == id)?;
+        class.queue.remove(index)
+    }
+    fn len(&self) -> usize {
+        self.block_priority.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::tests::{block, send_order};
+
+    /// | id | size | priority |
+    /// | 0  | 30   | 1        |
+    /// | 1  | 20   | 2        |
+    /// | 2  | 10   | 1        |
+    /// | 3  | 20   | 3        |
+    fn push_trace(scheduler: &mut dyn BlockScheduler) {
+        scheduler.push_block(block(0, 30, 1, 200, 0));
+        scheduler.push_block(block(1, 20, 2, 200, 0));
+        scheduler.push_block(block(2, 10, 1, 200, 0));
+        scheduler.push_block(block(3, 20, 3, 200, 0));
+    }
+
+    #[test]
+    fn strict_priority_order() {
+        let mut scheduler = PriorityScheduler::default();
+        push_trace(&mut scheduler);
+        assert_eq!(
+            send_order(&mut scheduler, 10),
+            vec![3, 3, 1, 1, 0, 0, 0, 2]
+        );
+    }
+
+    #[test]
+    fn strict_priority_preempts() {
+        let mut scheduler = PriorityScheduler::default();
+        scheduler.push_block(block(0, 30, 1, 200, 0));
+        // block 0 is half sent when a more important block arrives
+        let b = scheduler.next_block_to_send_mut(0).unwrap();
+        b.send_bytes(10);
+        scheduler.push_block(block(1, 10, 2, 200, 0));
+        assert_eq!(send_order(&mut scheduler, 10), vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn weighted_fair_shares() {
+        let mut scheduler = WeightedFairScheduler::new(vec![1.0, 2.0, 4.0]);
+        push_trace(&mut scheduler);
+        // priority 3 gets 4 chunks for every chunk of priority 1
+        assert_eq!(
+            send_order(&mut scheduler, 5),
+            vec![3, 1, 0, 3, 3, 1, 3, 1, 0, 1, 0, 0, 0, 0, 2, 2]
+        );
+    }
+
+    #[test]
+    fn weighted_fair_equal_weights_alternate() {
+        let mut scheduler = WeightedFairScheduler::new(vec![1.0, 1.0, 1.0]);
+        scheduler.push_block(block(0, 20, 1, 200, 0));
+        scheduler.push_block(block(1, 20, 2, 200, 0));
+        assert_eq!(send_order(&mut scheduler, 10), vec![1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn weighted_fair_idle_class_has_no_credit() {
+        let mut scheduler = WeightedFairScheduler::new(vec![1.0, 1.0, 1.0]);
+        scheduler.push_block(block(0, 40, 1, 200, 0));
+        assert_eq!(send_order(&mut scheduler, 10), vec![0, 0, 0, 0]);
+        // priority 1 was alone so far, priority 2 joins without a backlog of credit
+        scheduler.push_block(block(1, 20, 1, 200, 0));
+        scheduler.push_block(block(2, 20, 2, 200, 0));
+        assert_eq!(send_order(&mut scheduler, 10), vec![2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn weighted_fair_idle_period() {
+        let mut scheduler = WeightedFairScheduler::new(vec![1.0, 1.0, 1.0]);
+        scheduler.push_block(block(0, 100, 1, 200, 0));
+        assert_eq!(send_order(&mut scheduler, 50), vec![0, 0]);
+        // every queue drained, priority 2 arrives first and must not starve priority 1
+        scheduler.push_block(block(1, 20, 2, 200, 0));
+        scheduler.push_block(block(2, 20, 1, 200, 0));
+        assert_eq!(send_order(&mut scheduler, 10), vec![1, 2, 1, 2]);
+    }
+}
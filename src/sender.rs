@@ -1,5 +1,9 @@
-use crate::block::BlockInfo;
-use std::collections::{VecDeque, HashMap};
+use crate::block::{BlockInfo, BlockHeader, BLOCK_EXPIRED_ERROR, BLOCK_HEADER_LEN, FRAMED_STREAM_ID, block_id_to_stream_id};
+use crate::datagram::DatagramHeader;
+use crate::fec::{self, FecEncoder};
+use crate::frame::StreamFrame;
+use crate::scheduler::BlockScheduler;
+use std::collections::{BTreeMap, VecDeque, HashMap};
 use std::net::TcpStream;
 use std::io::Write;
 use dtp_utils::dtp_config;
@@ -10,84 +14,636 @@ use rand::Rng;
 pub struct SenderBlock {
     pub info: BlockInfo,
     pub data: Vec<u8>,
+    /// the time the block was generated, in microseconds
+    pub create_time: u64,
     sent_size: usize,
+    header_sent: usize,
     has_begun: bool,
 }
 impl SenderBlock {
-    fn new(info: BlockInfo) -> Self {
+    pub fn new(info: BlockInfo, create_time: u64) -> Self {
         SenderBlock {
             info,
             data: Vec::new(),
+            create_time,
             sent_size: 0,
+            header_sent: 0,
             has_begun: false
         }
     }
-    fn has_begun_sending(&self) -> bool {
-        return self.has_begun;
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            info: self.info,
+            create_time: self.create_time,
+        }
+    }
+    pub fn has_begun_sending(&self) -> bool {
+        self.has_begun
     }
     fn begin_sending(&mut self) {
         self.has_begun = true;
     }
-    fn is_send_complete(&self) -> bool {
-        return self.info.size == self.sent_size;
+    pub fn is_send_complete(&self) -> bool {
+        self.info.size == self.sent_size
     }
-    fn send_bytes(&mut self, bytes: usize) {
+    pub fn send_bytes(&mut self, bytes: usize) {
         assert!(self.sent_size + bytes <= self.info.size);
         self.sent_size += bytes;
     }
-    fn remain_bytes(&self) -> usize {
-        return self.info.size - self.sent_size;
+    pub fn sent_bytes(&self) -> usize {
+        self.sent_size
+    }
+    pub fn remain_bytes(&self) -> usize {
+        self.info.size - self.sent_size
+    }
+    /// The absolute deadline of the block, in microseconds
+    pub fn deadline_time(&self) -> u64 {
+        self.create_time + self.info.deadline as u64 * 1000
     }
 }
+
+/// The FIFO scheduler: blocks are sent one after another in the order they
+/// are generated, priority and deadline are ignored
 #[derive(Default)]
 pub struct SenderDeque {
     queue: VecDeque<SenderBlock>
 }
-impl SenderDeque {
-    fn next_block_to_send(&self) -> Option<&SenderBlock>{
-        return self.queue.front();
+impl BlockScheduler for SenderDeque {
+    fn push_block(&mut self, block: SenderBlock) {
+        self.queue.push_back(block);
     }
-    fn next_block_to_send_mut(&mut self) -> Option<&mut SenderBlock>{
-        return self.queue.front_mut();
+    fn next_block_to_send_mut(&mut self, _now: u64) -> Option<&mut SenderBlock>{
+        self.queue.front_mut()
     }
-    fn remove_block(&mut self) -> Option<SenderBlock> {
-        return self.queue.pop_front();
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let index = self.queue.iter().position(|b| b.info.id == id)?;
+        self.queue.remove(index)
     }
     fn len(&self) -> usize {
-        return self.queue.len();
+        self.queue.len()
+    }
+}
+/// The sender asks the scheduler for a block again after writing this many
+/// bytes, so that schedulers can interleave or preempt blocks.
+pub const MAX_SEND_CHUNK: usize = 8192;
+
+/// Send every block on its own QUIC stream, and optionally reset the streams
+/// of the blocks whose deadline passes before they are delivered.
+///
+/// quiche 0.16 computes the final size of a reset stream from the data
+/// waiting to be sent, including the lost data waiting for retransmission,
+/// so such a reset can be smaller than what the peer already received, which
+/// closes the connection with a FINAL_SIZE_ERROR. A stream is therefore only
+/// reset when the connection lost no packet since it was opened; the other
+/// expired blocks are delivered late.
+#[derive(Debug, Default)]
+pub struct StreamSender {
+    reset_expired: bool,
+    /// the blocks whose stream was opened and is not acknowledged yet, by id
+    in_flight: BTreeMap<usize, InFlightStream>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InFlightStream {
+    deadline: u64,
+    /// the packets the connection had lost when the stream was opened
+    lost: usize,
+}
+
+impl StreamSender {
+    pub fn new(reset_expired: bool) -> Self {
+        StreamSender {
+            reset_expired,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// Push the queued blocks into the QUIC connection, one stream per block.
+    ///
+    /// Return the number of blocks left in the queue. Blocks stay in the queue
+    /// until all their data has been accepted by quiche, so the function should
+    /// be called again whenever the connection becomes writable.
+    /// Expired blocks should be collected with `abandon_expired` and
+    /// `BlockScheduler::on_expire` before.
+    pub fn send(&mut self, sender_queue: &mut dyn BlockScheduler, conn: &mut quiche::Connection, now: u64) -> Result<usize, quiche::Error> {
+        let reset_expired = self.reset_expired;
+        let lost = conn.stats().lost;
+        send_data_to_quic(sender_queue, conn, now, |block| {
+            if reset_expired {
+                let deadline = block.deadline_time();
+                self.in_flight.entry(block.info.id).or_insert(InFlightStream { deadline, lost });
+            }
+        })
+    }
+
+    /// Give up on the blocks whose deadline has passed after they began to
+    /// be sent: remove them from the queue and reset their stream, so that
+    /// quiche stops (re)transmitting their data.
+    ///
+    /// Return the ids of the blocks whose stream was reset. Blocks whose
+    /// stream is already delivered are forgotten silently, and so are those
+    /// that cannot be reset safely, see `StreamSender`.
+    pub fn abandon_expired(&mut self, sender_queue: &mut dyn BlockScheduler, conn: &mut quiche::Connection, now: u64) -> Vec<usize> {
+        // quiche collects a stream once it is fully written and acknowledged
+        self.in_flight.retain(|id, _| conn.stream_capacity(block_id_to_stream_id(*id)).is_ok());
+        let expired: Vec<(usize, InFlightStream)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, stream)| stream.deadline < now)
+            .map(|(id, stream)| (*id, *stream))
+            .collect();
+        let lost = conn.stats().lost;
+        let mut abandoned = Vec::new();
+        for (id, stream) in expired {
+            self.in_flight.remove(&id);
+            if lost != stream.lost {
+                debug!("{} cannot reset block {} safely, packets were lost since it began", conn.trace_id(), id);
+                continue;
+            }
+            sender_queue.remove_block(id);
+            let stream_id = block_id_to_stream_id(id);
+            match conn.stream_shutdown(stream_id, quiche::Shutdown::Write, BLOCK_EXPIRED_ERROR) {
+                Ok(()) => abandoned.push(id),
+                // the stream is complete and collected
+                Err(quiche::Error::Done) => {},
+                Err(e) => error!("{} failed to reset stream {}: {:?}", conn.trace_id(), stream_id, e),
+            }
+        }
+        abandoned
     }
 }
-fn send_data(sender_queue: &mut SenderDeque, tcp_map: &mut HashMap<usize, TcpStream>) -> Result<usize, std::io::Error>{
-    loop {
-        // select a block to send
-        let block = sender_queue.next_block_to_send_mut().unwrap();
+
+/// Push the queued blocks into the QUIC connection, one stream per block,
+/// `on_written` is called every time quiche takes data of a block, so its
+/// stream exists.
+fn send_data_to_quic<F>(sender_queue: &mut dyn BlockScheduler, conn: &mut quiche::Connection, now: u64, mut on_written: F) -> Result<usize, quiche::Error>
+where
+    F: FnMut(&SenderBlock),
+{
+    while let Some(block) = sender_queue.next_block_to_send_mut(now) {
         // mark whether the block is sent first time
         if !block.has_begun_sending() {
             block.begin_sending();
         }
-        // try to send data into socket, record the total bytes sent
-        match send_block_to_tcp(block, tcp_map) {
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+        // try to send data into the stream of the block
+        let want = block.remain_bytes().min(MAX_SEND_CHUNK);
+        let sent = match send_block_to_quic(block, conn, want) {
+            // no capacity left in the connection or no stream credit,
+            // wait for the next writable event
+            Err(quiche::Error::Done) | Err(quiche::Error::StreamLimit) => {
                 return Ok(sender_queue.len());
             },
             Err(err) => return Err(err),
-            Ok(_) => {},
-        }
+            Ok(sent) => sent,
+        };
+        on_written(block);
+        let (id, complete) = (block.info.id, block.is_send_complete());
+        sender_queue.on_block_progress(id, sent);
         // if the data has been sent completely, pop the block from the queue
-        if block.is_send_complete() {
-            sender_queue.remove_block();
-        } else {
+        if complete {
+            sender_queue.on_block_complete(id);
+        } else if sent < want {
             // or leave the function and wait until the connection is ready
             return Ok(sender_queue.len());
         }
     }
+    Ok(0)
+}
+/// Write at most `len` bytes of the block into its stream
+fn send_block_to_quic(block: &mut SenderBlock, conn: &mut quiche::Connection, len: usize) -> Result<usize, quiche::Error> {
+    let stream_id = block_id_to_stream_id(block.info.id);
+    // the header goes first so that the receiver knows what the block is
+    if block.header_sent < BLOCK_HEADER_LEN {
+        let header = block.header().to_bytes();
+        block.header_sent += conn.stream_send(stream_id, &header[block.header_sent..], false)?;
+        if block.header_sent < BLOCK_HEADER_LEN {
+            return Ok(0);
+        }
+    }
+    let end = block.sent_size + len;
+    let buf = &block.data[block.sent_size..end];
+    let sent = conn.stream_send(stream_id, buf, end == block.info.size)?;
+    block.send_bytes(sent);
+    Ok(sent)
+}
+
+/// The errors telling that the transport cannot take more data for now
+pub trait WouldBlock {
+    fn would_block(&self) -> bool;
+}
+
+impl WouldBlock for quiche::Error {
+    fn would_block(&self) -> bool {
+        matches!(self, quiche::Error::Done | quiche::Error::StreamLimit)
+    }
+}
+
+impl WouldBlock for std::io::Error {
+    fn would_block(&self) -> bool {
+        self.kind() == std::io::ErrorKind::WouldBlock
+    }
+}
+
+/// Send every block on its own TCP connection, see `TransportMode::Tcp`.
+///
+/// The client opens the connections in advance: a block takes an idle
+/// connection when it starts to be sent, and the connection is shut down
+/// once the whole block is written. A block waits for the next connection
+/// when none is idle. A block whose connection fails is given up, see
+/// `take_broken`.
+#[derive(Debug, Default)]
+pub struct TcpSender {
+    /// the connections waiting for a block
+    idle: VecDeque<TcpStream>,
+    /// the connection of the blocks being sent, by block id
+    tcp_map: HashMap<usize, TcpStream>,
+    /// the blocks whose connection failed, until `take_broken`
+    broken: Vec<usize>,
+}
+
+impl TcpSender {
+    /// A new non-blocking connection is ready to carry a block
+    pub fn add_connection(&mut self, stream: TcpStream) {
+        self.idle.push_back(stream);
+    }
+
+    /// The blocks given up because their connection failed since the last
+    /// call, the client does not get the rest of them
+    pub fn take_broken(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.broken)
+    }
+
+    /// The scheduler gave up on a block, close its connection if it has one
+    pub fn on_block_dropped(&mut self, id: usize) {
+        if let Some(socket) = self.tcp_map.remove(&id) {
+            let _ = socket.shutdown(std::net::Shutdown::Write);
+        }
+    }
+
+    /// Write the queued blocks into their connection.
+    ///
+    /// Return the number of blocks left in the queue, like `StreamSender::send`.
+    /// The function should be called again whenever a connection becomes
+    /// writable or is added.
+    pub fn send(&mut self, sender_queue: &mut dyn BlockScheduler, now: u64) -> usize {
+        while let Some(block) = sender_queue.next_block_to_send_mut(now) {
+            let id = block.info.id;
+            if !self.tcp_map.contains_key(&id) {
+                match self.idle.pop_front() {
+                    Some(socket) => {
+                        self.tcp_map.insert(id, socket);
+                    },
+                    // wait for the client to open a connection
+                    None => return sender_queue.len(),
+                }
+            }
+            // mark whether the block is sent first time
+            if !block.has_begun_sending() {
+                block.begin_sending();
+            }
+            // try to send data into socket, record the total bytes sent
+            let want = block.remain_bytes().min(MAX_SEND_CHUNK);
+            let socket = self.tcp_map.get_mut(&id).unwrap();
+            let sent = match send_block_to_tcp(block, socket, want) {
+                Err(err) if err.would_block() => {
+                    return sender_queue.len();
+                },
+                Err(err) => {
+                    // the other connections may still work
+                    warn!("tcp connection of block {} failed: {:?}", id, err);
+                    self.tcp_map.remove(&id);
+                    sender_queue.remove_block(id);
+                    self.broken.push(id);
+                    continue;
+                },
+                Ok(sent) => sent,
+            };
+            let complete = block.is_send_complete();
+            sender_queue.on_block_progress(id, sent);
+            // if the data has been sent completely, pop the block from the queue
+            // and let the client know with the end of the connection
+            if complete {
+                if let Some(socket) = self.tcp_map.remove(&id) {
+                    // the data is written, the client sees the end of the
+                    // connection either way
+                    if let Err(err) = socket.shutdown(std::net::Shutdown::Write) {
+                        warn!("tcp connection of block {} failed to shut down: {:?}", id, err);
+                    }
+                }
+                sender_queue.on_block_complete(id);
+            } else if sent < want {
+                // or leave the function and wait until the connection is ready
+                return sender_queue.len();
+            }
+        }
+        0
+    }
 }
-fn send_block_to_tcp(block: &mut SenderBlock, tcp_map: &mut HashMap<usize, TcpStream>) -> Result<usize, std::io::Error>{
-    let socket = tcp_map.get_mut(&block.info.id).unwrap();
-    let buf = &block.data[block.sent_size..block.info.size];
+
+/// Write at most `len` bytes of the block into its connection, after its header
+fn send_block_to_tcp(block: &mut SenderBlock, socket: &mut TcpStream, len: usize) -> Result<usize, std::io::Error> {
+    if block.header_sent < BLOCK_HEADER_LEN {
+        let header = block.header().to_bytes();
+        block.header_sent += socket.write(&header[block.header_sent..])?;
+        if block.header_sent < BLOCK_HEADER_LEN {
+            return Ok(0);
+        }
+    }
+    let buf = &block.data[block.sent_size..block.sent_size + len];
     let sent = socket.write(buf)?;
     block.send_bytes(sent);
-    return Ok(sent);
+    Ok(sent)
+}
+
+/// How the blocks are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportMode {
+    /// One stream per block, quiche decides how the streams are interleaved
+    #[default]
+    Stream,
+    /// All the blocks on one stream as `BlockInfo`/`BlockData` frames,
+    /// interleaved by the scheduler
+    Framed,
+    /// The blocks chopped into QUIC DATAGRAM frames, lost pieces are not
+    /// retransmitted
+    Datagram,
+    /// The TCP baseline of the stream mode: one TCP connection per block
+    Tcp,
+    /// The TCP baseline of the framed mode: all the blocks on one TCP
+    /// connection
+    TcpFramed,
+}
+
+impl TransportMode {
+    /// Whether the blocks are sent over TCP instead of QUIC, see `crate::tcp`
+    pub fn is_tcp(self) -> bool {
+        matches!(self, TransportMode::Tcp | TransportMode::TcpFramed)
+    }
+}
+
+impl std::str::FromStr for TransportMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(TransportMode::Stream),
+            "framed" => Ok(TransportMode::Framed),
+            "datagram" => Ok(TransportMode::Datagram),
+            "tcp" => Ok(TransportMode::Tcp),
+            "tcp-framed" => Ok(TransportMode::TcpFramed),
+            _ => Err(anyhow::anyhow!("unknown transport mode {}, expected stream, framed, datagram, tcp or tcp-framed", s)),
+        }
+    }
+}
+
+/// Multiplex the blocks on `FRAMED_STREAM_ID`.
+///
+/// Every block starts with a `BlockInfo` frame, then its data goes in
+/// `BlockData` frames of at most `MAX_SEND_CHUNK` bytes. The scheduler picks
+/// a block for every frame, so blocks are interleaved at frame granularity.
+/// A frame is complete once the block bytes are taken from the queue: the
+/// part of it quiche does not accept yet is kept and written first next time.
+#[derive(Debug, Default)]
+pub struct FramedSender {
+    pending: Vec<u8>,
+}
+
+impl FramedSender {
+    /// Whether every frame was written into the stream
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Push the queued blocks into the framed stream of the connection.
+    ///
+    /// Return the number of blocks left in the queue, like `StreamSender::send`.
+    pub fn send(&mut self, sender_queue: &mut dyn BlockScheduler, conn: &mut quiche::Connection, now: u64) -> Result<usize, quiche::Error> {
+        self.send_with(sender_queue, now, |buf| conn.stream_send(FRAMED_STREAM_ID, buf, false))
+    }
+
+    /// Push the queued blocks into a TCP connection, see `TransportMode::TcpFramed`.
+    pub fn send_to_tcp(&mut self, sender_queue: &mut dyn BlockScheduler, stream: &mut TcpStream, now: u64) -> std::io::Result<usize> {
+        self.send_with(sender_queue, now, |buf| stream.write(buf))
+    }
+
+    /// Push the queued blocks into any byte stream, `write` returns how many
+    /// bytes it took or an error telling it would block.
+    pub fn send_with<E, F>(&mut self, sender_queue: &mut dyn BlockScheduler, now: u64, mut write: F) -> Result<usize, E>
+    where
+        E: WouldBlock,
+        F: FnMut(&[u8]) -> Result<usize, E>,
+    {
+        loop {
+            // finish the previous frame before starting a new one
+            if !self.pending.is_empty() {
+                match write(&self.pending) {
+                    Ok(written) => {
+                        self.pending.drain(..written);
+                    },
+                    Err(err) if err.would_block() => {},
+                    Err(err) => return Err(err),
+                }
+                if !self.pending.is_empty() {
+                    return Ok(sender_queue.len());
+                }
+            }
+            let block = match sender_queue.next_block_to_send_mut(now) {
+                Some(block) => block,
+                None => return Ok(0),
+            };
+            if !block.has_begun_sending() {
+                block.begin_sending();
+                let frame = StreamFrame::block_info(&block.info, block.create_time);
+                self.pending.extend_from_slice(&frame.to_vec());
+            }
+            let start = block.sent_size;
+            let sent = block.remain_bytes().min(MAX_SEND_CHUNK);
+            let frame = StreamFrame::BlockData {
+                id: block.info.id,
+                data: block.data[start..start + sent].to_vec(),
+            };
+            self.pending.extend_from_slice(&frame.to_vec());
+            block.send_bytes(sent);
+
+            let (id, complete) = (block.info.id, block.is_send_complete());
+            sender_queue.on_block_progress(id, sent);
+            if complete {
+                sender_queue.on_block_complete(id);
+            }
+        }
+    }
+}
+
+/// Chop the blocks into QUIC DATAGRAM frames, see `crate::datagram`.
+///
+/// The scheduler picks a block for every datagram. A piece is sent once the
+/// datagram is queued in quiche, it is lost for good if the packet carrying
+/// it is lost, unless the priority class of the block is protected by
+/// repair symbols, see `crate::fec`.
+#[derive(Debug, Default)]
+pub struct DatagramSender {
+    /// the redundancy ratio of priority 1, 2, 3...
+    fec_ratios: Vec<f64>,
+    /// the repair symbols of the blocks being sent, by block id
+    encoders: HashMap<usize, FecEncoder>,
+    /// the repair datagrams waiting for room in the datagram queue
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl DatagramSender {
+    pub fn new(fec_ratios: Vec<f64>) -> Self {
+        DatagramSender {
+            fec_ratios,
+            ..Default::default()
+        }
+    }
+
+    /// Whether every repair datagram was queued in the connection
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Forget the repair symbols of a block the scheduler gave up on
+    pub fn on_block_dropped(&mut self, id: usize) {
+        self.encoders.remove(&id);
+    }
+
+    /// The number of pieces protected by a repair symbol for a priority,
+    /// None if the priority class has no FEC
+    fn group_size(&self, priority: usize) -> Option<usize> {
+        priority
+            .checked_sub(1)
+            .and_then(|i| self.fec_ratios.get(i))
+            .copied()
+            .and_then(fec::group_size)
+    }
+
+    /// Push the queued blocks into the DATAGRAM queue of the connection.
+    ///
+    /// Return the number of blocks left in the queue, like `StreamSender::send`,
+    /// or `BufferTooShort` if a datagram has no room for data.
+    pub fn send(&mut self, sender_queue: &mut dyn BlockScheduler, conn: &mut quiche::Connection, now: u64) -> Result<usize, quiche::Error> {
+        loop {
+            // the repair symbols of the previous pieces go first
+            while let Some(dgram) = self.pending.front() {
+                match conn.dgram_send(dgram) {
+                    Ok(()) => {
+                        self.pending.pop_front();
+                    },
+                    Err(quiche::Error::Done) => return Ok(sender_queue.len()),
+                    Err(err) => return Err(err),
+                }
+            }
+            let block = match sender_queue.next_block_to_send_mut(now) {
+                Some(block) => block,
+                None => return Ok(0),
+            };
+            // the peer did not enable datagrams
+            let max_len = conn.dgram_max_writable_len().ok_or(quiche::Error::InvalidState)?;
+            let id = block.info.id;
+            let header = DatagramHeader {
+                id,
+                offset: block.sent_size,
+                size: block.info.size,
+                priority: block.info.priority,
+                deadline: block.info.deadline,
+                create_time: block.create_time,
+                repair: None,
+            };
+            if !block.has_begun_sending() {
+                // a repair symbol is as long as the pieces it protects, so
+                // all the pieces of the block have the same length and leave
+                // room for the largest repair header
+                if let Some(group_size) = self.group_size(block.info.priority) {
+                    let repair = DatagramHeader { offset: block.info.size, repair: Some(group_size), ..header };
+                    let symbol_len = max_len.saturating_sub(repair.wire_len());
+                    if symbol_len == 0 {
+                        return Err(quiche::Error::BufferTooShort);
+                    }
+                    self.encoders.insert(id, FecEncoder::new(symbol_len, group_size));
+                }
+                block.begin_sending();
+            }
+            let symbol_len = match self.encoders.get(&id) {
+                Some(encoder) => encoder.symbol_len(),
+                None => max_len.saturating_sub(header.wire_len()),
+            };
+            // the datagrams have no room left for data after the header,
+            // the block would never advance
+            if symbol_len == 0 {
+                return Err(quiche::Error::BufferTooShort);
+            }
+            let sent = block.remain_bytes().min(symbol_len);
+            let data = &block.data[header.offset..header.offset + sent];
+            match conn.dgram_send(&header.to_vec(data)) {
+                Ok(()) => {},
+                // the datagram queue is full, wait for the next writable event
+                Err(quiche::Error::Done) => return Ok(sender_queue.len()),
+                Err(err) => return Err(err),
+            }
+            let last = sent == block.remain_bytes();
+            if let Some(encoder) = self.encoders.get_mut(&id) {
+                if let Some(repair) = encoder.push(header.offset, data, last) {
+                    let header = DatagramHeader { offset: repair.offset, repair: Some(repair.group_size), ..header };
+                    self.pending.push_back(header.to_vec(&repair.data));
+                }
+            }
+            block.send_bytes(sent);
+
+            sender_queue.on_block_progress(id, sent);
+            if last {
+                self.encoders.remove(&id);
+                sender_queue.on_block_complete(id);
+            }
+        }
+    }
+}
+
+/// The sender of a connection, depending on the `TransportMode`
+#[derive(Debug)]
+pub enum BlockSender {
+    Stream(StreamSender),
+    Framed(FramedSender),
+    Datagram(DatagramSender),
+}
+
+impl BlockSender {
+    /// `reset_expired` only applies to the stream mode, see `StreamSender`,
+    /// and `fec_ratios` to the datagram mode, see `DatagramSender`
+    pub fn new(mode: TransportMode, reset_expired: bool, fec_ratios: &[f64]) -> Self {
+        match mode {
+            TransportMode::Stream => BlockSender::Stream(StreamSender::new(reset_expired)),
+            TransportMode::Framed => BlockSender::Framed(FramedSender::default()),
+            TransportMode::Datagram => BlockSender::Datagram(DatagramSender::new(fec_ratios.to_vec())),
+            TransportMode::Tcp | TransportMode::TcpFramed => unreachable!("the TCP modes do not use QUIC"),
+        }
+    }
+
+    /// Whether all the data taken from the queue was written into the connection
+    pub fn is_idle(&self) -> bool {
+        match self {
+            BlockSender::Stream(_) => true,
+            BlockSender::Framed(sender) => sender.is_idle(),
+            BlockSender::Datagram(sender) => sender.is_idle(),
+        }
+    }
+
+    /// The scheduler gave up on a block
+    pub fn on_block_dropped(&mut self, id: usize) {
+        if let BlockSender::Datagram(sender) = self {
+            sender.on_block_dropped(id);
+        }
+    }
+
+    /// Push the queued blocks into the connection.
+    /// Return the number of blocks left in the queue.
+    pub fn send(&mut self, sender_queue: &mut dyn BlockScheduler, conn: &mut quiche::Connection, now: u64) -> Result<usize, quiche::Error> {
+        match self {
+            BlockSender::Stream(sender) => sender.send(sender_queue, conn, now),
+            BlockSender::Framed(sender) => sender.send(sender_queue, conn, now),
+            BlockSender::Datagram(sender) => sender.send(sender_queue, conn, now),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -100,38 +656,31 @@ impl BlockGenerator {
     pub fn load_cfgs(&mut self, cfgs: Vec<dtp_config>) {
         self.cfgs = cfgs;
     }
-    /// Generate a block to sender queue
+    /// Generate a block to sender queue, created at `now`
     /// Should be called again after secs of the return value
     /// return next time gap, None if no more block to generate
-    pub fn generate_once(&mut self, sender_queue: &mut SenderDeque) -> Option<f32> {
+    pub fn generate_once(&mut self, sender_queue: &mut dyn BlockScheduler, now: u64) -> Option<f32> {
         let mut rng = rand::thread_rng();
-        static mut RANDOM_BUFFER: [u8; 10000000] = [0u8; 10000000];
         let start = self.next_index_to_generate;
-        for (index, cfg ) in self.cfgs[start..].iter().enumerate() {
+        for cfg in self.cfgs[start..].iter() {
             debug!("generate: ({}, {}, {}, {}, {})", self.next_index_to_generate, cfg.send_time_gap, cfg.block_size, cfg.priority, cfg.deadline);
-            let mut sender_block = 
+            let mut sender_block =
                 SenderBlock::new(
-                    BlockInfo {
-                        id: self.next_index_to_generate,
-                        size: cfg.block_size as usize,
-                        priority: cfg.priority as usize,
-                        deadline: cfg.priority as usize,
-                    }
+                    cfg_to_block_info(self.next_index_to_generate, cfg),
+                    now,
                 );
-            unsafe {
-                let buf=  &mut RANDOM_BUFFER[..sender_block.info.size];
-                rng.fill(buf);
-                sender_block.data = buf.to_vec();
-            }
-            sender_queue.queue.push_back(sender_block);
+            let mut data = vec![0; sender_block.info.size];
+            rng.fill(&mut data[..]);
+            sender_block.data = data;
+            sender_queue.push_block(sender_block);
             self.next_index_to_generate += 1;
-        
+
             if self.next_index_to_generate >= self.cfgs.len() {
                 return None
             }
 
             let next_cfg = self.cfgs[self.next_index_to_generate];
-        
+
             // if send gap is too small
             // we generate the data immediately to avoid
             // timer error
@@ -143,12 +692,254 @@ impl BlockGenerator {
         }
         None
     }
-    
+
+    /// The number of blocks generated so far
+    pub fn generated(&self) -> usize {
+        self.next_index_to_generate
+    }
+
     pub fn first_time_gap(&self) -> Option<f32> {
-        if self.cfgs.len() <= 0 {
-            None
-        } else {
-            Some(self.cfgs[0].send_time_gap)
+        self.cfgs.first().map(|cfg| cfg.send_time_gap)
+    }
+
+    /// The blocks of the whole trace, in the order they are generated
+    pub fn block_infos(&self) -> impl Iterator<Item = BlockInfo> + '_ {
+        self.cfgs.iter().enumerate().map(|(id, cfg)| cfg_to_block_info(id, cfg))
+    }
+}
+
+fn cfg_to_block_info(id: usize, cfg: &dtp_config) -> BlockInfo {
+    BlockInfo {
+        id,
+        size: cfg.block_size as usize,
+        priority: cfg.priority as usize,
+        deadline: cfg.deadline as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::BlockReceiver;
+    use crate::scheduler::PriorityScheduler;
+
+    fn block_with_data(id: usize, size: usize, priority: usize) -> SenderBlock {
+        let mut block = SenderBlock::new(BlockInfo { id, size, priority, deadline: 200 }, 0);
+        block.data = vec![id as u8; size];
+        block
+    }
+
+    #[test]
+    fn generated_blocks() {
+        let trace = [20_000_000, 10].map(|block_size| dtp_config { deadline: 200, priority: 1, block_size, send_time_gap: 0.0 });
+        let mut generator = BlockGenerator::default();
+        generator.load_cfgs(trace.to_vec());
+        let mut scheduler = PriorityScheduler::default();
+        assert_eq!(generator.generate_once(&mut scheduler, 0), None);
+        assert_eq!(generator.generated(), 2);
+        let block = scheduler.on_block_complete(0).unwrap();
+        assert_eq!(block.data.len(), 20_000_000);
+        assert_eq!(scheduler.on_block_complete(1).unwrap().data.len(), 10);
+    }
+
+    #[test]
+    fn framed_blocks_are_interleaved() {
+        let mut scheduler = PriorityScheduler::default();
+        scheduler.push_block(block_with_data(0, MAX_SEND_CHUNK * 2, 1));
+        let mut sender = FramedSender::default();
+        let mut stream = Vec::new();
+        // the stream takes 1000 bytes per call
+        let mut write = |buf: &[u8]| {
+            let len = buf.len().min(1000);
+            stream.extend_from_slice(&buf[..len]);
+            Ok::<_, quiche::Error>(len)
+        };
+        assert_eq!(sender.send_with(&mut scheduler, 0, &mut write), Ok(1));
+        assert!(!sender.is_idle());
+        // a more important block preempts block 0 at the next frame
+        scheduler.push_block(block_with_data(1, 100, 3));
+        while !sender.is_idle() || !scheduler.is_empty() {
+            sender.send_with(&mut scheduler, 0, &mut write).unwrap();
+        }
+
+        let mut receiver = BlockReceiver::default();
+        let mut completed = Vec::new();
+        for piece in stream.chunks(700) {
+            completed.extend(receiver.on_stream_data(FRAMED_STREAM_ID, piece, false, 1_000));
+        }
+        assert_eq!(completed, vec![1, 0]);
+        let summary = receiver.summary();
+        assert_eq!((summary.complete, summary.met), (2, 2));
+    }
+
+    #[test]
+    fn framed_stream_blocked() {
+        let mut scheduler = PriorityScheduler::default();
+        scheduler.push_block(block_with_data(0, 10, 1));
+        let mut sender = FramedSender::default();
+        let blocked = |_: &[u8]| Err(quiche::Error::Done);
+        assert_eq!(sender.send_with(&mut scheduler, 0, blocked), Ok(0));
+        assert!(!sender.is_idle());
+        let mut stream = Vec::new();
+        let write = |buf: &[u8]| {
+            stream.extend_from_slice(buf);
+            Ok::<_, quiche::Error>(buf.len())
+        };
+        assert_eq!(sender.send_with(&mut scheduler, 0, write), Ok(0));
+        assert!(sender.is_idle());
+        let mut decoder = crate::frame::FrameDecoder::default();
+        decoder.push(&stream);
+        assert!(matches!(decoder.next_frame(), Ok(Some(StreamFrame::BlockInfo { id: 0, size: 10, .. }))));
+        assert!(matches!(decoder.next_frame(), Ok(Some(StreamFrame::BlockData { id: 0, .. }))));
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn abandon_expired_streams() {
+        let (mut client, mut server) = crate::tests::connected_pair();
+        let mut scheduler = SenderDeque::default();
+        let mut sender = StreamSender::new(true);
+        // a block of 10 ms generated at 0, bigger than the stream window
+        let mut block = SenderBlock::new(BlockInfo { id: 0, size: 2_000_000, priority: 1, deadline: 10 }, 0);
+        block.data = vec![0; block.info.size];
+        scheduler.push_block(block);
+        scheduler.push_block(block_with_data(1, 100, 1));
+
+        assert_eq!(sender.send(&mut scheduler, &mut server, 0), Ok(2));
+        assert!(sender.abandon_expired(&mut scheduler, &mut server, 5_000).is_empty());
+        // the deadline passes mid-transfer
+        assert_eq!(sender.abandon_expired(&mut scheduler, &mut server, 11_000), vec![0]);
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(sender.send(&mut scheduler, &mut server, 11_000), Ok(0));
+        crate::tests::exchange(&mut client, &mut server);
+
+        let mut receiver = BlockReceiver::default();
+        let mut buf = [0; 65535];
+        for s in client.readable() {
+            loop {
+                match client.stream_recv(s, &mut buf) {
+                    Ok((read, fin)) => {
+                        receiver.on_stream_data(s, &buf[..read], fin, 12_000);
+                    },
+                    Err(quiche::Error::StreamReset(error_code)) => {
+                        receiver.on_stream_reset(s, error_code);
+                        break;
+                    },
+                    Err(_) => break,
+                }
+            }
         }
+        assert!(receiver.get_block(0).unwrap().is_abandoned());
+        assert!(receiver.get_block(1).unwrap().is_complete());
+        assert_eq!(receiver.summary().abandoned, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn expired_streams_after_losses() {
+        let (mut client, mut server) = crate::tests::connected_pair();
+        let mut scheduler = SenderDeque::default();
+        let mut sender = StreamSender::new(true);
+        // block 1 is delivered and acknowledged, its stream is forgotten
+        scheduler.push_block(block_with_data(1, 100, 1));
+        assert_eq!(sender.send(&mut scheduler, &mut server, 0), Ok(0));
+        crate::tests::exchange(&mut client, &mut server);
+
+        let mut block = SenderBlock::new(BlockInfo { id: 0, size: 2_000_000, priority: 1, deadline: 10 }, 0);
+        block.data = vec![0; block.info.size];
+        scheduler.push_block(block);
+        assert_eq!(sender.send(&mut scheduler, &mut server, 0), Ok(1));
+        // lose the first packet of block 0, the acks of the next ones reveal it
+        let mut buf = [0; crate::quic::MAX_DATAGRAM_SIZE];
+        let mut packets = 0;
+        while let Ok((len, send_info)) = server.send(&mut buf) {
+            if packets > 0 {
+                client.recv(&mut buf[..len], quiche::RecvInfo { from: send_info.from, to: send_info.to }).unwrap();
+            }
+            packets += 1;
+        }
+        while let Ok((len, send_info)) = client.send(&mut buf) {
+            server.recv(&mut buf[..len], quiche::RecvInfo { from: send_info.from, to: send_info.to }).unwrap();
+        }
+        assert!(server.stats().lost > 0);
+
+        // the reset could be smaller than what the client received, block 0 goes on
+        assert!(sender.abandon_expired(&mut scheduler, &mut server, 11_000).is_empty());
+        assert!(sender.in_flight.is_empty());
+        assert_eq!(scheduler.len(), 1);
+        crate::tests::exchange(&mut client, &mut server);
+        assert!(!client.is_closed() && !server.is_closed());
+    }
+
+    /// Read the datagrams received by `conn` into `receiver`, skipping the
+    /// ones for which `lose` returns true
+    fn recv_datagrams<F: FnMut(usize) -> bool>(conn: &mut quiche::Connection, receiver: &mut BlockReceiver, mut lose: F) -> Vec<usize> {
+        let mut buf = [0; 65535];
+        let mut completed = Vec::new();
+        let mut index = 0;
+        while let Ok(len) = conn.dgram_recv(&mut buf) {
+            if !lose(index) {
+                completed.extend(receiver.on_datagram(&buf[..len], 1_000));
+            }
+            index += 1;
+        }
+        completed
+    }
+
+    #[test]
+    fn datagram_blocks() {
+        let (mut client, mut server) = crate::tests::connected_pair();
+        let mut scheduler = PriorityScheduler::default();
+        scheduler.push_block(block_with_data(0, 5_000, 1));
+        scheduler.push_block(block_with_data(1, 100, 3));
+        let mut sender = DatagramSender::default();
+        assert_eq!(sender.send(&mut scheduler, &mut server, 0), Ok(0));
+        crate::tests::exchange(&mut client, &mut server);
+
+        let mut receiver = BlockReceiver::default();
+        assert_eq!(recv_datagrams(&mut client, &mut receiver, |_| false), vec![1, 0]);
+        let block = receiver.get_block(0).unwrap();
+        assert_eq!(block.info().unwrap().size, 5_000);
+        assert_eq!(block.completion_time(), Some(1_000));
+        assert_eq!(receiver.summary().complete, 2);
+    }
+
+    #[test]
+    fn lost_datagram() {
+        let (mut client, mut server) = crate::tests::connected_pair();
+        let mut scheduler = SenderDeque::default();
+        scheduler.push_block(block_with_data(0, 5_000, 1));
+        scheduler.push_block(block_with_data(1, 100, 1));
+        let mut sender = DatagramSender::default();
+        assert_eq!(sender.send(&mut scheduler, &mut server, 0), Ok(0));
+        crate::tests::exchange(&mut client, &mut server);
+
+        // the second piece of block 0 is lost and never sent again
+        let mut receiver = BlockReceiver::default();
+        assert_eq!(recv_datagrams(&mut client, &mut receiver, |i| i == 1), vec![1]);
+        let block = receiver.get_block(0).unwrap();
+        assert!(block.has_arrived());
+        assert!(!block.is_complete());
+        assert_eq!(receiver.summary().complete, 1);
+    }
+
+    #[test]
+    fn datagram_fec_by_priority() {
+        let (mut client, mut server) = crate::tests::connected_pair();
+        let mut scheduler = SenderDeque::default();
+        // only priority 2 is protected, one repair symbol for 2 pieces
+        scheduler.push_block(block_with_data(0, 5_000, 2));
+        scheduler.push_block(block_with_data(1, 5_000, 1));
+        let mut sender = DatagramSender::new(vec![0.0, 0.5]);
+        assert_eq!(sender.send(&mut scheduler, &mut server, 0), Ok(0));
+        assert!(sender.is_idle());
+        crate::tests::exchange(&mut client, &mut server);
+
+        // 5 pieces and 3 repair symbols for block 0, then 5 pieces for block 1:
+        // lose the first piece of both blocks
+        let mut receiver = BlockReceiver::default();
+        let completed = recv_datagrams(&mut client, &mut receiver, |i| i == 0 || i == 8);
+        assert_eq!(completed, vec![0]);
+        assert!(receiver.get_block(0).unwrap().is_complete());
+        assert!(!receiver.get_block(1).unwrap().is_complete());
+    }
+}
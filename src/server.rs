@@ -0,0 +1,1141 @@
+//! The QUIC server sending the blocks, see `DtpServer`
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+use calloop::{generic::Generic, timer::{Timer, TimeoutAction}, Dispatcher, Interest, LoopHandle, Mode, PostAction};
+use dtp_utils::{dtp_config, get_current_usec};
+use quiche::ConnectionId;
+use ring::rand::SystemRandom;
+
+use crate::block::{BlockInfo, ACK_STREAM_ID, CONTROL_STREAM_ID};
+use crate::error::{would_block, Error, Result};
+use crate::events::{BlockEvent, Event, ServerEvents};
+use crate::frame::{FrameDecoder, StreamFrame};
+use crate::quic::{close_connection, load_certificate, set_quiche_conn_config, DispatcherTimer, LoopTimer, QuicOptions, IDLE_TIMEOUT, MAX_DATAGRAM_SIZE};
+use crate::scheduler::{BlockScheduler, NetworkStats};
+use crate::sender::{BlockGenerator, BlockSender, SenderBlock, TransportMode};
+use crate::token::{TokenKey, TOKEN_LIFETIME};
+
+struct Client {
+    conn: quiche::Connection,
+    peer: SocketAddr,
+
+    // the blocks of this connection, generated from the trace once the
+    // handshake completes
+    sender_queue: Box<dyn BlockScheduler>,
+    block_generator: BlockGenerator,
+    started: bool,
+    report: ConnectionReport,
+
+    // control messages waiting for stream capacity
+    control_buf: Vec<u8>,
+
+    // writes the blocks into the connection
+    sender: BlockSender,
+
+    // the acknowledgements of the client
+    acks: FrameDecoder,
+    // the blocks sent completely and not acknowledged yet, by id
+    unacked: HashMap<usize, BlockEvent>,
+
+    // the next packet, held until the send time quiche paced it at
+    paced: Option<(Vec<u8>, quiche::SendInfo)>,
+}
+
+/// What happened to the blocks of one connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionReport {
+    pub peer: SocketAddr,
+    pub generated: usize,
+    /// written completely to the connection
+    pub sent: usize,
+    /// acknowledged by the client
+    pub acked: usize,
+    /// dropped or abandoned because of their deadline, or acknowledged after it
+    pub missed: usize,
+}
+
+impl ConnectionReport {
+    fn new(peer: SocketAddr) -> Self {
+        ConnectionReport { peer, generated: 0, sent: 0, acked: 0, missed: 0 }
+    }
+}
+
+/// Forwards to the scheduler and keeps the blocks the sender takes out of
+/// it, to report them once the sender is done.
+struct Recorder<'a> {
+    queue: &'a mut dyn BlockScheduler,
+    // sent completely
+    sent: Vec<SenderBlock>,
+    // given up on mid-transfer
+    removed: Vec<SenderBlock>,
+}
+
+impl<'a> Recorder<'a> {
+    fn new(queue: &'a mut dyn BlockScheduler) -> Self {
+        Recorder { queue, sent: Vec::new(), removed: Vec::new() }
+    }
+}
+
+impl<'a> BlockScheduler for Recorder<'a> {
+    fn push_block(&mut self, block: SenderBlock) {
+        self.queue.push_block(block);
+    }
+    fn next_block_to_send_mut(&mut self, now: u64) -> Option<&mut SenderBlock> {
+        self.queue.next_block_to_send_mut(now)
+    }
+    fn on_network_update(&mut self, stats: &NetworkStats) {
+        self.queue.on_network_update(stats);
+    }
+    fn on_block_progress(&mut self, id: usize, sent: usize) {
+        self.queue.on_block_progress(id, sent);
+    }
+    fn on_block_complete(&mut self, id: usize) -> Option<SenderBlock> {
+        let block = self.queue.on_block_complete(id)?;
+        // the data is not needed anymore
+        self.sent.push(SenderBlock::new(block.info, block.create_time));
+        Some(block)
+    }
+    fn remove_block(&mut self, id: usize) -> Option<SenderBlock> {
+        let block = self.queue.remove_block(id)?;
+        self.removed.push(SenderBlock::new(block.info, block.create_time));
+        Some(block)
+    }
+    fn on_expire(&mut self, now: u64) -> Vec<SenderBlock> {
+        self.queue.on_expire(now)
+    }
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+type ClientMap = HashMap<ConnectionId<'static>, Client>;
+
+/// The event loop the server is inserted in
+trait ServerLoop: LoopTimer {
+    /// Call `generate_cb` for the connection `conn_id` after `gap`, then
+    /// after the gaps it returns
+    fn start_generator(&mut self, conn_id: ConnectionId<'static>, gap: Duration) -> Result<()>;
+}
+
+struct ServerLoopHandle<'l, D> {
+    timer: DispatcherTimer<'l, D>,
+    state: Weak<RefCell<ServerState<'l>>>,
+}
+
+impl<'l, D> LoopTimer for ServerLoopHandle<'l, D> {
+    fn set_duration(&mut self, timeout: Duration) {
+        self.timer.set_duration(timeout);
+    }
+}
+
+impl<'l, D: 'l> ServerLoop for ServerLoopHandle<'l, D> {
+    fn start_generator(&mut self, conn_id: ConnectionId<'static>, gap: Duration) -> Result<()> {
+        let state = self.state.clone();
+        self.timer.handle
+            .insert_source(
+                Timer::from_duration(gap),
+                move |_event, _metadata, _data: &mut D| match state.upgrade() {
+                    Some(state) => {
+                        let action = generate_cb(&mut state.borrow_mut(), &conn_id);
+                        fire_events(&state);
+                        action
+                    },
+                    None => TimeoutAction::Drop,
+                },
+            )?;
+        Ok(())
+    }
+}
+
+/// Creates the scheduler of every connection
+pub type SchedulerFactory<'l> = Box<dyn FnMut() -> Box<dyn BlockScheduler> + 'l>;
+
+struct ServerState<'l> {
+    // every connection receives the whole trace
+    trace: Vec<dtp_config>,
+    new_scheduler: SchedulerFactory<'l>,
+    // the blocks of `send_block` waiting for a connection
+    pending: Vec<SenderBlock>,
+    transport_mode: TransportMode,
+    // reset the stream of the blocks whose deadline passes mid-transfer
+    reset_expired: bool,
+    // the FEC redundancy ratio of every priority class in datagram mode
+    fec_ratios: Vec<f64>,
+    // the number of connections that received the blocks
+    served: usize,
+    // finish once this many connections are served and closed
+    finish_after: usize,
+    // the reports of the closed connections
+    reports: Vec<ConnectionReport>,
+    finished: bool,
+    // the failure of the socket that stopped the server
+    error: Option<Error>,
+
+    socket: UdpSocket,
+    clients: ClientMap,
+    conn_id_seed: ring::hmac::Key,
+    // seals the retry tokens, None without retry
+    token_key: Option<TokenKey>,
+    local_addr: SocketAddr,
+    config: quiche::Config,
+
+    // waiting for the borrow of the state to end, see `fire_events`
+    events: Vec<Event>,
+    callbacks: Rc<RefCell<ServerEvents<'l>>>,
+
+    // set right after the state is shared with the event sources
+    event_loop: Option<Box<dyn ServerLoop + 'l>>,
+}
+
+/// A QUIC server sending blocks to the clients that connect.
+///
+/// Every connection gets its own scheduler and replays the whole trace,
+/// see `DtpServerBuilder::trace`, from the end of its handshake. The blocks
+/// of `DtpServer::send_block` go to every connection. The server
+/// runs in the calloop event loop of the application, the sources it
+/// inserts stop once the `DtpServer` is dropped.
+pub struct DtpServer<'l> {
+    state: Rc<RefCell<ServerState<'l>>>,
+}
+
+impl<'l> DtpServer<'l> {
+    pub fn builder(addr: SocketAddr) -> DtpServerBuilder<'l> {
+        DtpServerBuilder::new(addr)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.state.borrow().local_addr
+    }
+
+    /// Queue a block of `data` on every connection, sent as soon as their
+    /// scheduler picks it. Before the first handshake completes, the block
+    /// waits for the first connection.
+    ///
+    /// The id of `info` must not be used by another block, including the
+    /// blocks of the trace, and its size must be the length of `data`.
+    pub fn send_block(&self, info: BlockInfo, data: Vec<u8>) -> Result<()> {
+        if info.size != data.len() {
+            return Err(Error::Config(format!("block {} has {} bytes instead of {}", info.id, data.len(), info.size)));
+        }
+        let now = get_current_usec();
+        let mut block = SenderBlock::new(info, now);
+        block.data = data;
+        let mut state_ref = self.state.borrow_mut();
+        let state = &mut *state_ref;
+        let mut clients = state.clients.values_mut().filter(|c| c.started).peekable();
+        if clients.peek().is_none() {
+            state.pending.push(block);
+        } else {
+            for client in clients {
+                client.sender_queue.push_block(block.clone());
+                client.report.generated += 1;
+            }
+        }
+        state.events.push(Event::BlockGenerated(BlockEvent { info, create_time: now, time: now }));
+        let result = server_send_trace(state);
+        drop(state_ref);
+        fire_events(&self.state);
+        result
+    }
+
+    /// The reports of the connections closed so far
+    pub fn reports(&self) -> Vec<ConnectionReport> {
+        self.state.borrow().reports.clone()
+    }
+
+    /// Whether the connections of `DtpServerBuilder::finish_after` are
+    /// closed, or the socket failed, see `take_error`
+    pub fn is_finished(&self) -> bool {
+        self.state.borrow().finished
+    }
+
+    /// The failure of the socket that stopped the server. The failures of
+    /// a single connection only close it, they are logged instead.
+    pub fn take_error(&self) -> Option<Error> {
+        self.state.borrow_mut().error.take()
+    }
+}
+
+/// Configure a `DtpServer`, the defaults are those of the command line
+pub struct DtpServerBuilder<'l> {
+    addr: SocketAddr,
+    trace: Vec<dtp_config>,
+    new_scheduler: SchedulerFactory<'l>,
+    finish_after: usize,
+    transport_mode: TransportMode,
+    reset_expired: bool,
+    fec_ratios: Vec<f64>,
+    quic_options: QuicOptions,
+    retry: bool,
+    events: ServerEvents<'l>,
+}
+
+impl<'l> DtpServerBuilder<'l> {
+    /// A server listening on `addr`
+    pub fn new(addr: SocketAddr) -> Self {
+        DtpServerBuilder {
+            addr,
+            trace: Vec::new(),
+            new_scheduler: Box::new(Box::<dyn BlockScheduler>::default),
+            finish_after: 1,
+            transport_mode: TransportMode::default(),
+            reset_expired: true,
+            fec_ratios: Vec::new(),
+            quic_options: QuicOptions::default(),
+            retry: true,
+            events: ServerEvents::default(),
+        }
+    }
+
+    /// Announce the blocks of `trace` to every client and generate them at
+    /// their send time gaps once it connects
+    pub fn trace(mut self, trace: Vec<dtp_config>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Create the scheduler of every connection with `new_scheduler`
+    pub fn scheduler<F: FnMut() -> Box<dyn BlockScheduler> + 'l>(mut self, new_scheduler: F) -> Self {
+        self.new_scheduler = Box::new(new_scheduler);
+        self
+    }
+
+    /// `DtpServer::is_finished` once `connections` connections received
+    /// the blocks and closed, 1 by default
+    pub fn finish_after(mut self, connections: usize) -> Self {
+        self.finish_after = connections;
+        self
+    }
+
+    /// One of the QUIC modes
+    pub fn transport_mode(mut self, mode: TransportMode) -> Self {
+        self.transport_mode = mode;
+        self
+    }
+
+    /// Reset the stream of the blocks whose deadline passes mid-transfer, on
+    /// by default, see `StreamSender`
+    pub fn reset_expired(mut self, reset_expired: bool) -> Self {
+        self.reset_expired = reset_expired;
+        self
+    }
+
+    /// The FEC redundancy ratio of priority 1, 2, 3... in datagram mode
+    pub fn fec_ratios(mut self, fec_ratios: Vec<f64>) -> Self {
+        self.fec_ratios = fec_ratios;
+        self
+    }
+
+    pub fn quic_options(mut self, quic_options: QuicOptions) -> Self {
+        self.quic_options = quic_options;
+        self
+    }
+
+    /// Validate the address of the clients with a stateless retry before
+    /// accepting their connection, on by default. Without it, a spoofed
+    /// Initial packet makes the server send to the spoofed address.
+    pub fn retry(mut self, retry: bool) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Call `callback` when a block is generated by the trace or given to
+    /// `DtpServer::send_block`
+    pub fn on_block_generated<F: FnMut(&BlockEvent) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_block_generated = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` when the last byte of a block is written to the connection
+    pub fn on_block_sent<F: FnMut(&BlockEvent) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_block_sent = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` when the client acknowledges a whole block
+    pub fn on_block_acked<F: FnMut(&BlockEvent) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_block_acked = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` when a block is dropped or abandoned because of its
+    /// deadline, or acknowledged after it
+    pub fn on_deadline_missed<F: FnMut(&BlockEvent) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_deadline_missed = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` when the connection of a client is collected
+    pub fn on_connection_closed<F: FnMut(SocketAddr, &quiche::Stats) + 'l>(mut self, callback: F) -> Self {
+        self.events.on_connection_closed = Some(Box::new(callback));
+        self
+    }
+
+    /// Bind the socket and insert the server into the event loop of `handle`
+    pub fn build<D: 'l>(self, handle: &LoopHandle<'l, D>) -> Result<DtpServer<'l>> {
+        if self.transport_mode.is_tcp() {
+            return Err(Error::Config("the TCP modes do not use QUIC, see crate::tcp".to_owned()));
+        }
+        // init socket
+        let server_socket = UdpSocket::bind(self.addr)?;
+        let local_addr = server_socket.local_addr()?;
+        server_socket.set_nonblocking(true)?;
+        // init quiche
+        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION)?;
+        set_quiche_conn_config(&mut config, &self.quic_options)?;
+        load_certificate(&mut config, &self.quic_options)?;
+        // init random seed
+        let rng = SystemRandom::new();
+        let conn_id_seed = ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng)
+            .map_err(|_| std::io::Error::other("failed to generate the connection id seed"))?;
+        let token_key = match self.retry {
+            true => Some(TokenKey::generate(TOKEN_LIFETIME)?),
+            false => None,
+        };
+
+        let state = Rc::new(RefCell::new(ServerState {
+            trace: self.trace,
+            new_scheduler: self.new_scheduler,
+            pending: Vec::new(),
+            transport_mode: self.transport_mode,
+            reset_expired: self.reset_expired,
+            fec_ratios: self.fec_ratios,
+            served: 0,
+            finish_after: self.finish_after,
+            reports: Vec::new(),
+            finished: false,
+            error: None,
+            socket: server_socket.try_clone()?,
+            clients: ClientMap::new(),
+            conn_id_seed,
+            token_key,
+            local_addr,
+            config,
+            events: Vec::new(),
+            callbacks: Rc::new(RefCell::new(self.events)),
+            event_loop: None,
+        }));
+
+        // The block generators are inserted once the clients connect,
+        // see `start_client`.
+        // add recv_cb
+        let weak = Rc::downgrade(&state);
+        handle.insert_source(
+            // wrap your IO object in a Generic, here we register for read readiness
+            // in level-triggering mode
+            Generic::new(server_socket, Interest::READ, Mode::Level),
+            move |readiness, socket, _data: &mut D| match weak.upgrade() {
+                Some(state) => {
+                    let action = server_recv_cb(readiness, socket, &mut state.borrow_mut());
+                    fire_events(&state);
+                    action
+                },
+                None => Ok(PostAction::Remove),
+            },
+        )?;
+        // add timeout cb
+        let weak = Rc::downgrade(&state);
+        let timeout_dispatcher = Dispatcher::new(
+            Timer::from_duration(Duration::from_secs(IDLE_TIMEOUT)),
+            move |_event, _metadata, _data: &mut D| match weak.upgrade() {
+                Some(state) => {
+                    let action = server_timeout_cb(&mut state.borrow_mut());
+                    fire_events(&state);
+                    action
+                },
+                None => TimeoutAction::Drop,
+            },
+        );
+        let timeout_token = handle.register_dispatcher(timeout_dispatcher.clone())?;
+
+        state.borrow_mut().event_loop = Some(Box::new(ServerLoopHandle {
+            timer: DispatcherTimer {
+                handle: handle.clone(),
+                dispatcher: timeout_dispatcher,
+                token: timeout_token,
+            },
+            state: Rc::downgrade(&state),
+        }));
+        Ok(DtpServer { state })
+    }
+}
+
+fn generate_cb(state: &mut ServerState, conn_id: &ConnectionId<'static>) -> TimeoutAction {
+    let client = match state.clients.get_mut(conn_id) {
+        Some(client) => client,
+        // the connection is closed
+        None => return TimeoutAction::Drop,
+    };
+    let now = get_current_usec();
+    let generated = client.block_generator.generated();
+    let next_gap = client.block_generator.generate_once(client.sender_queue.as_mut(), now);
+    let count = client.block_generator.generated() - generated;
+    client.report.generated += count;
+    for info in client.block_generator.block_infos().skip(generated).take(count) {
+        state.events.push(Event::BlockGenerated(BlockEvent { info, create_time: now, time: now }));
+    }
+
+    // push the new blocks to the client right away instead of waiting
+    // for the next packet from it
+    if let Err(e) = server_send_trace(state) {
+        server_fail(state, e);
+    }
+
+    match next_gap {
+        Some(gap) => TimeoutAction::ToDuration(Duration::from_secs_f32(gap)),
+        None => {
+            info!("all blocks of {:?} are generated", conn_id);
+            TimeoutAction::Drop
+        }
+    }
+}
+
+/// Call the callbacks of the events queued in `state`, once it is not
+/// borrowed anymore. The callbacks may call the server, queueing more events.
+fn fire_events(state: &RefCell<ServerState>) {
+    let callbacks = state.borrow().callbacks.clone();
+    let mut callbacks = match callbacks.try_borrow_mut() {
+        Ok(callbacks) => callbacks,
+        // called back from a callback, whose caller fires the new events
+        Err(_) => return,
+    };
+    loop {
+        let events = std::mem::take(&mut state.borrow_mut().events);
+        if events.is_empty() {
+            return;
+        }
+        for event in events {
+            callbacks.fire(event);
+        }
+    }
+}
+
+fn server_recv_cb(
+    _readiness: calloop::Readiness,
+    io_object: &mut UdpSocket,
+    state: &mut ServerState
+) -> std::io::Result<calloop::PostAction> {
+    match server_recv(io_object, state) {
+        Ok(()) => Ok(PostAction::Continue),
+
+        Err(e) => {
+            server_fail(state, e);
+            Ok(PostAction::Disable)
+        },
+    }
+}
+
+/// The socket failed, stop serving every connection
+fn server_fail(state: &mut ServerState, e: Error) {
+    error!("the server failed: {}", e);
+    state.finished = true;
+    state.error.get_or_insert(e);
+}
+
+/// Read the packets waiting on the socket, feed them to their connection,
+/// and send the packets of every connection.
+fn server_recv(io_object: &mut UdpSocket, state: &mut ServerState) -> Result<()> {
+    let clients = &mut state.clients;
+    let socket = io_object;
+    let conn_id_seed = &state.conn_id_seed;
+    let token_key = state.token_key.as_ref();
+    let local_addr = &state.local_addr;
+    let config = &mut state.config;
+
+    let mut buf = [0; 65535];
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+
+    'read: loop {
+        // If the event loop reported no events, it means that the timeout
+        // has expired, so handle it without attempting to read packets. We
+        // will then proceed with the send loop.
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(v) => v,
+
+            Err(e) => {
+                // There are no more UDP packets to read, so end the read
+                // loop.
+                if would_block(&e) {
+                    debug!("recv() would block");
+                    break 'read;
+                }
+
+                return Err(e.into());
+            },
+        };
+
+        debug!("server got {} bytes", len);
+
+        let pkt_buf = &mut buf[..len];
+
+        // Parse the QUIC packet's header.
+        let hdr = match quiche::Header::from_slice(
+            pkt_buf,
+            quiche::MAX_CONN_ID_LEN,
+        ) {
+            Ok(v) => v,
+
+            Err(e) => {
+                error!("Parsing packet header failed: {:?}", e);
+                continue 'read;
+            },
+        };
+
+        trace!("server got packet {:?}", hdr);
+
+        let conn_id = ring::hmac::sign(conn_id_seed, &hdr.dcid);
+        let conn_id = &conn_id.as_ref()[..quiche::MAX_CONN_ID_LEN];
+        let conn_id = conn_id.to_vec().into();
+
+        // Lookup a connection based on the packet's connection ID. If there
+        // is no connection matching, create a new one.
+        let client = if !clients.contains_key(&hdr.dcid) &&
+            !clients.contains_key(&conn_id)
+        {
+            if hdr.ty != quiche::Type::Initial {
+                error!("Packet is not Initial");
+                continue 'read;
+            }
+
+            if !quiche::version_is_supported(hdr.version) {
+                warn!("Doing version negotiation");
+
+                let len = match quiche::negotiate_version(&hdr.scid, &hdr.dcid, &mut out) {
+                    Ok(v) => v,
+
+                    Err(e) => {
+                        error!("Version negotiation failed: {:?}", e);
+                        continue 'read;
+                    },
+                };
+
+                let out = &out[..len];
+
+                if let Err(e) = socket.send_to(out, from) {
+                    if would_block(&e) {
+                        debug!("send() would block");
+                        break;
+                    }
+
+                    return Err(e.into());
+                }
+                continue 'read;
+            }
+
+            let mut scid = [0; quiche::MAX_CONN_ID_LEN];
+            scid.copy_from_slice(&conn_id);
+
+            let scid = quiche::ConnectionId::from_ref(&scid);
+
+            // Token is always present in Initial packets.
+            let token = hdr.token.as_deref().unwrap_or_default();
+
+            // Do stateless retry if the client didn't send a token.
+            if let (Some(token_key), true) = (token_key, token.is_empty()) {
+                warn!("Doing stateless retry");
+
+                let new_token = match token_key.mint(&hdr.dcid, &from) {
+                    Ok(v) => v,
+
+                    Err(e) => {
+                        error!("Minting a retry token failed: {}", e);
+                        continue 'read;
+                    },
+                };
+
+                let len = match quiche::retry(
+                    &hdr.scid,
+                    &hdr.dcid,
+                    &scid,
+                    &new_token,
+                    hdr.version,
+                    &mut out,
+                ) {
+                    Ok(v) => v,
+
+                    Err(e) => {
+                        error!("Retry failed: {:?}", e);
+                        continue 'read;
+                    },
+                };
+
+                let out = &out[..len];
+
+                if let Err(e) = socket.send_to(out, from) {
+                    if would_block(&e) {
+                        debug!("send() would block");
+                        break;
+                    }
+
+                    return Err(e.into());
+                }
+                continue 'read;
+            }
+
+            let (scid, odcid) = match token_key {
+                Some(token_key) => {
+                    let odcid = token_key.validate(&from, token);
+
+                    // The token was not valid, meaning the retry failed, so
+                    // drop the packet.
+                    if odcid.is_none() {
+                        error!("Invalid address validation token");
+                        continue 'read;
+                    }
+
+                    if scid.len() != hdr.dcid.len() {
+                        error!("Invalid destination connection ID");
+                        continue 'read;
+                    }
+
+                    // Reuse the source connection ID we sent in the Retry packet,
+                    // instead of changing it again.
+                    (hdr.dcid.clone(), odcid)
+                },
+
+                // the address of the client is not validated
+                None => (scid.into_owned(), None),
+            };
+
+            debug!("New connection: dcid={:?} scid={:?}", hdr.dcid, scid);
+
+            let conn = match quiche::accept(
+                &scid,
+                odcid.as_ref(),
+                *local_addr,
+                from,
+                config,
+            ) {
+                Ok(v) => v,
+
+                Err(e) => {
+                    error!("Accepting the connection failed: {:?}", e);
+                    continue 'read;
+                },
+            };
+
+            let mut block_generator = BlockGenerator::default();
+            block_generator.load_cfgs(state.trace.clone());
+            let client = Client {
+                conn,
+                peer: from,
+                sender_queue: (state.new_scheduler)(),
+                block_generator,
+                started: false,
+                report: ConnectionReport::new(from),
+                control_buf: Vec::new(),
+                sender: BlockSender::new(state.transport_mode, state.reset_expired, &state.fec_ratios),
+                acks: FrameDecoder::default(),
+                unacked: HashMap::new(),
+                paced: None,
+            };
+
+            clients.insert(scid.clone(), client);
+
+            clients.get_mut(&scid).unwrap()
+        } else {
+            match clients.get_mut(&hdr.dcid) {
+                Some(v) => v,
+
+                None => clients.get_mut(&conn_id).unwrap(),
+            }
+        };
+
+        let recv_info = quiche::RecvInfo {
+            to: *local_addr,
+            from,
+        };
+
+        // Process potentially coalesced packets.
+        let read = match client.conn.recv(pkt_buf, recv_info) {
+            Ok(v) => v,
+
+            Err(e) => {
+                error!("{} recv failed: {:?}", client.conn.trace_id(), e);
+                continue 'read;
+            },
+        };
+
+        debug!("{} processed {} bytes", client.conn.trace_id(), read);
+
+        // Start generating blocks once the connection is established.
+        if client.conn.is_established() && !client.started {
+            let event_loop = state.event_loop.as_mut().unwrap();
+            start_client(client, &mut state.pending, event_loop.as_mut());
+            state.served += 1;
+        }
+
+        if client.conn.is_in_early_data() || client.conn.is_established() {
+            // Handle writable streams.
+            if client.started {
+                handle_writable(client, &mut state.events);
+            }
+
+            // Process all readable streams.
+            for s in client.conn.readable() {
+                while let Ok((read, fin)) =
+                    client.conn.stream_recv(s, &mut buf)
+                {
+                    debug!(
+                        "{} received {} bytes",
+                        client.conn.trace_id(),
+                        read
+                    );
+
+                    let stream_buf = &buf[..read];
+
+                    debug!(
+                        "{} stream {} has {} bytes (fin? {})",
+                        client.conn.trace_id(),
+                        s,
+                        stream_buf.len(),
+                        fin
+                    );
+
+                    if s == ACK_STREAM_ID {
+                        handle_acks(client, stream_buf, &mut state.events);
+                    } else {
+                        debug!("{} ignores the data of stream {}", client.conn.trace_id(), s);
+                    }
+                }
+            }
+        }
+    }
+
+    collect_garbage_connection(clients, &mut state.reports, &mut state.events);
+
+    server_flush_quic_packets(clients, socket)?;
+
+    update_server_timer(state);
+
+    Ok(())
+}
+
+/// Push the queued blocks to the clients and send the resulting packets.
+fn server_send_trace(state: &mut ServerState) -> Result<()> {
+    let clients = &mut state.clients;
+
+    for client in clients.values_mut().filter(|c| c.started) {
+        handle_writable(client, &mut state.events);
+    }
+
+    server_flush_quic_packets(clients, &mut state.socket)?;
+
+    update_server_timer(state);
+    Ok(())
+}
+
+/// The handshake of `client` completed: announce the trace, start its
+/// generator, and hand it the blocks waiting for a connection.
+fn start_client(client: &mut Client, pending: &mut Vec<SenderBlock>, event_loop: &mut dyn ServerLoop) {
+    info!("{} start sending the trace", client.conn.trace_id());
+    client.started = true;
+    announce_trace(client);
+    if let Some(first_gap) = client.block_generator.first_time_gap() {
+        let conn_id = client.conn.source_id().into_owned();
+        if let Err(e) = event_loop.start_generator(conn_id, Duration::from_secs_f32(first_gap)) {
+            close_connection(&mut client.conn, &e);
+        }
+    }
+    client.report.generated += pending.len();
+    for block in pending.drain(..) {
+        client.sender_queue.push_block(block);
+    }
+}
+
+/// Tell the client which blocks the trace is made of.
+fn announce_trace(client: &mut Client) {
+    let infos: Vec<_> = client.block_generator.block_infos().collect();
+    if infos.is_empty() {
+        // the application sends its own blocks
+        return;
+    }
+    client.control_buf.extend_from_slice(&StreamFrame::DtpConfig { cfg_len: infos.len() }.to_vec());
+    for info in infos.iter() {
+        // the block is not generated yet
+        client.control_buf.extend_from_slice(&StreamFrame::block_info(info, 0).to_vec());
+    }
+    flush_control_stream(&mut client.conn, &mut client.control_buf);
+}
+
+/// The earliest QUIC timeout or paced packet of the connections
+fn next_timeout(clients: &ClientMap) -> Option<Duration> {
+    let now = Instant::now();
+    clients
+        .values()
+        .filter_map(|c| {
+            let paced = c.paced.as_ref().map(|(_, send_info)| send_info.at.saturating_duration_since(now));
+            match (c.conn.timeout(), paced) {
+                (Some(timeout), Some(paced)) => Some(timeout.min(paced)),
+                (timeout, paced) => timeout.or(paced),
+            }
+        })
+        .min()
+}
+
+/// Fire the timeout timer at the earliest QUIC timeout or paced packet of
+/// the connections
+fn update_server_timer(state: &mut ServerState) {
+    let timeout = match next_timeout(&state.clients) {
+        Some(timeout) => timeout,
+        None => {
+            debug!("recv packet but all timeout is None, set the timeout as timer idle timeout");
+            Duration::from_secs(IDLE_TIMEOUT)
+        },
+    };
+    state.event_loop.as_mut().unwrap().set_duration(timeout);
+}
+
+/// Generate outgoing QUIC packets for all active connections and send
+/// them on the UDP socket, until quiche reports that there are no more
+/// packets to be sent.
+///
+/// A packet paced in the future, or that the socket cannot take, is held
+/// in `Client::paced` until the timer of `next_timeout`. Sending the whole
+/// congestion window at once would overflow the receive buffer of the
+/// client, which loses the datagrams for good.
+fn server_flush_quic_packets(clients: &mut ClientMap, socket: &mut UdpSocket) -> Result<()> {
+    let mut out = [0; MAX_DATAGRAM_SIZE];
+    for client in clients.values_mut() {
+        loop {
+            let (write, send_info) = match client.paced.take() {
+                Some((packet, send_info)) => {
+                    let write = packet.len();
+                    out[..write].copy_from_slice(&packet);
+                    (write, send_info)
+                },
+                None => match client.conn.send(&mut out) {
+                    Ok(v) => v,
+
+                    Err(quiche::Error::Done) => {
+                        debug!("{} done writing", client.conn.trace_id());
+                        break;
+                    },
+
+                    Err(e) => {
+                        close_connection(&mut client.conn, &e.into());
+                        break;
+                    },
+                },
+            };
+            if send_info.at > Instant::now() {
+                client.paced = Some((out[..write].to_vec(), send_info));
+                break;
+            }
+
+            if let Err(e) = socket.send_to(&out[..write], send_info.to) {
+                if would_block(&e) {
+                    debug!("send() would block");
+                    client.paced = Some((out[..write].to_vec(), send_info));
+                    break;
+                }
+
+                return Err(e.into());
+            }
+
+            debug!("{} written {} bytes", client.conn.trace_id(), write);
+        }
+    }
+    Ok(())
+}
+
+// Garbage collect closed connections.
+fn collect_garbage_connection(clients: &mut ClientMap, reports: &mut Vec<ConnectionReport>, events: &mut Vec<Event>) {
+    clients.retain(|_, ref mut c| {
+        debug!("Server Collecting garbage");
+
+        if c.conn.is_closed() {
+            info!(
+                "{} connection collected {:?}",
+                c.conn.trace_id(),
+                c.conn.stats()
+            );
+            info!("{} {:?}", c.conn.trace_id(), c.report);
+            if c.started {
+                reports.push(c.report);
+            }
+            events.push(Event::ConnectionClosed(c.peer, c.conn.stats()));
+        }
+
+        !c.conn.is_closed()
+    });
+}
+
+/// The timer of `update_server_timer` fired. It is never dropped, so that
+/// the server can update it.
+fn server_timeout_cb(state: &mut ServerState) -> TimeoutAction {
+    let clients = &mut state.clients;
+    // handle timeout
+    clients.values_mut().for_each(|c| c.conn.on_timeout());
+
+    collect_garbage_connection(clients, &mut state.reports, &mut state.events);
+
+    // the timeout may have freed congestion window for pending blocks
+    for client in clients.values_mut().filter(|c| c.started) {
+        handle_writable(client, &mut state.events);
+    }
+
+    if let Err(e) = server_flush_quic_packets(clients, &mut state.socket) {
+        server_fail(state, e);
+        return TimeoutAction::ToDuration(Duration::from_secs(IDLE_TIMEOUT));
+    }
+
+    // update timer
+    if let Some(next_timeout) = next_timeout(clients) {
+        TimeoutAction::ToDuration(next_timeout)
+    } else {
+        if clients.is_empty() && state.served >= state.finish_after && !state.finished {
+            debug!("all timeout is None and no client in timeout_cb, the server is finished");
+            state.finished = true;
+        }
+        TimeoutAction::ToDuration(Duration::from_secs(IDLE_TIMEOUT))
+    }
+}
+
+/// Pushes the pending blocks into the streams of a writable connection.
+fn handle_writable(client: &mut Client, events: &mut Vec<Event>) {
+    let now = get_current_usec();
+    let mut sender_queue = Recorder::new(client.sender_queue.as_mut());
+
+    // stop sending the blocks that missed their deadline on the way
+    if let BlockSender::Stream(sender) = &mut client.sender {
+        for id in sender.abandon_expired(&mut sender_queue, &mut client.conn, now) {
+            info!("{} abandon block {}, its deadline has passed", client.conn.trace_id(), id);
+        }
+    }
+    for block in sender_queue.removed.drain(..) {
+        client.report.missed += 1;
+        events.push(Event::DeadlineMissed(BlockEvent { info: block.info, create_time: block.create_time, time: now }));
+    }
+
+    // tell the client about the blocks the scheduler gave up on
+    for block in sender_queue.on_expire(now) {
+        info!(
+            "{} drop block {} after sending {}/{} bytes",
+            client.conn.trace_id(),
+            block.info.id,
+            block.sent_bytes(),
+            block.info.size
+        );
+        client.sender.on_block_dropped(block.info.id);
+        client.control_buf.extend_from_slice(&StreamFrame::BlockDrop { id: block.info.id }.to_vec());
+        client.report.missed += 1;
+        events.push(Event::DeadlineMissed(BlockEvent { info: block.info, create_time: block.create_time, time: now }));
+    }
+    flush_control_stream(&mut client.conn, &mut client.control_buf);
+
+    let conn = &mut client.conn;
+
+    // a frame may still wait for stream capacity
+    if sender_queue.is_empty() && client.sender.is_idle() {
+        return;
+    }
+
+    sender_queue.on_network_update(&NetworkStats::from_connection(conn));
+
+    match client.sender.send(&mut sender_queue, conn, now) {
+        Ok(remain) => debug!("{} {} blocks wait to be sent", conn.trace_id(), remain),
+
+        Err(e) => close_connection(conn, &e.into()),
+    }
+
+    for block in sender_queue.sent.drain(..) {
+        let event = BlockEvent { info: block.info, create_time: block.create_time, time: now };
+        client.report.sent += 1;
+        events.push(Event::BlockSent(event));
+        client.unacked.insert(block.info.id, event);
+    }
+}
+
+/// Reports the blocks acknowledged by the client.
+fn handle_acks(client: &mut Client, buf: &[u8], events: &mut Vec<Event>) {
+    client.acks.push(buf);
+    loop {
+        let id = match client.acks.next_frame() {
+            Ok(Some(StreamFrame::BlockAck { id })) => id,
+
+            Ok(Some(frame)) => {
+                warn!("{} ignore {:?} on the ack stream", client.conn.trace_id(), frame);
+                continue;
+            },
+
+            Ok(None) => break,
+
+            Err(e) => {
+                close_connection(&mut client.conn, &Error::Protocol(format!("invalid ack stream: {}", e)));
+                break;
+            },
+        };
+        let sent = match client.unacked.remove(&id) {
+            Some(sent) => sent,
+            None => {
+                debug!("{} block {} acked before it was reported as sent", client.conn.trace_id(), id);
+                continue;
+            },
+        };
+        let event = BlockEvent { time: get_current_usec(), ..sent };
+        client.report.acked += 1;
+        events.push(Event::BlockAcked(event));
+        if event.is_late() {
+            client.report.missed += 1;
+            events.push(Event::DeadlineMissed(event));
+        }
+    }
+}
+
+/// Writes the pending control messages into the control stream.
+fn flush_control_stream(conn: &mut quiche::Connection, control_buf: &mut Vec<u8>) {
+    if control_buf.is_empty() {
+        return;
+    }
+
+    match conn.stream_send(CONTROL_STREAM_ID, control_buf, false) {
+        Ok(written) => {
+            control_buf.drain(..written);
+        },
+
+        Err(quiche::Error::Done) => {},
+
+        Err(e) => close_connection(conn, &e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PROTOCOL_VIOLATION;
+    use crate::tests::{connected_pair, exchange};
+
+    #[test]
+    fn invalid_acks_close_the_connection() {
+        let (mut client_conn, server_conn) = connected_pair();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 1234));
+        let mut client = Client {
+            conn: server_conn,
+            peer,
+            sender_queue: Box::<dyn BlockScheduler>::default(),
+            block_generator: BlockGenerator::default(),
+            started: true,
+            report: ConnectionReport::new(peer),
+            control_buf: Vec::new(),
+            sender: BlockSender::new(TransportMode::Stream, false, &[]),
+            acks: FrameDecoder::default(),
+            unacked: HashMap::new(),
+            paced: None,
+        };
+        let mut events = Vec::new();
+
+        // an ack of a block the server never sent is ignored
+        handle_acks(&mut client, &StreamFrame::BlockAck { id: 3 }.to_vec(), &mut events);
+        assert!(!client.conn.is_closed() && client.conn.local_error().is_none());
+
+        // an unknown frame type
+        handle_acks(&mut client, &[0x30, 0x00], &mut events);
+        assert_eq!(client.conn.local_error().map(|e| e.error_code), Some(PROTOCOL_VIOLATION));
+        exchange(&mut client_conn, &mut client.conn);
+        assert_eq!(client_conn.peer_error().map(|e| e.error_code), Some(PROTOCOL_VIOLATION));
+    }
+}
@@ -0,0 +1,473 @@
+//! A model of the scheduler on a virtual clock, in a single thread
+//!
+//! Only the block generator, the scheduler, the `FramedSender` and the
+//! `BlockReceiver` of the framed mode run here, connected by two emulated
+//! `Link`s. Time only moves from one event to the next, so a trace runs as
+//! fast as the CPU allows and two runs with the same seed produce the same
+//! report.
+//!
+//! This is not the QUIC server and client: quiche reads the wall clock
+//! internally for its timers and congestion control, so `SimTransport`
+//! stands in for it, a reliable transport of the control and framed streams
+//! with Reno-like congestion control, one acknowledgement per packet and
+//! loss detection by packet threshold or probe timeout. The code of
+//! `DtpServer` and `DtpClient` (the generator timers, the block
+//! acknowledgements, the events) and the stream and datagram modes are not
+//! exercised. The results compare schedulers with each other, they say
+//! nothing about the performance of the real transport.
+use std::collections::{BTreeMap, VecDeque};
+use anyhow::{anyhow, Result};
+use dtp_utils::dtp_config;
+
+use crate::block::{CONTROL_STREAM_ID, FRAMED_STREAM_ID};
+use crate::emulator::{Link, LinkConfig};
+use crate::frame::StreamFrame;
+use crate::receiver::BlockReceiver;
+use crate::scheduler::{BlockScheduler, NetworkStats};
+use crate::sender::{BlockGenerator, FramedSender};
+
+/// The payload of a simulated packet, as quiche's `MAX_DATAGRAM_SIZE`
+const MSS: usize = 1350;
+/// The size of a packet carrying an acknowledgement
+const ACK_SIZE: usize = 50;
+/// A packet is lost once a packet sent this much later is acknowledged
+const PACKET_THRESHOLD: u64 = 3;
+const INITIAL_CWND: usize = 10 * MSS;
+const MIN_CWND: usize = 2 * MSS;
+/// The round trip time before the first sample, in microseconds
+const INITIAL_RTT: u64 = 100_000;
+/// The shortest probe timeout, in microseconds
+const MIN_PTO: u64 = 10_000;
+/// Give up when the client receives nothing for this long, in microseconds
+const IDLE_TIMEOUT: u64 = 60_000_000;
+
+/// The bytes of a stream carried by a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    stream_id: u64,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    /// Encode the segment as `| pn | stream_id | offset | data |`, with
+    /// varint header fields
+    fn to_packet(&self, pn: u64) -> Vec<u8> {
+        let header_len = octets::varint_len(pn) + octets::varint_len(self.stream_id) + octets::varint_len(self.offset);
+        let mut buf = vec![0; header_len + self.data.len()];
+        let mut b = octets::OctetsMut::with_slice(&mut buf);
+        b.put_varint(pn).unwrap();
+        b.put_varint(self.stream_id).unwrap();
+        b.put_varint(self.offset).unwrap();
+        buf[header_len..].copy_from_slice(&self.data);
+        buf
+    }
+
+    fn from_packet(packet: &[u8]) -> Option<(u64, Segment)> {
+        let mut b = octets::Octets::with_slice(packet);
+        let pn = b.get_varint().ok()?;
+        let stream_id = b.get_varint().ok()?;
+        let offset = b.get_varint().ok()?;
+        Some((pn, Segment { stream_id, offset, data: b.as_ref().to_vec() }))
+    }
+}
+
+/// The acknowledgement of packet `pn`, padded to `ACK_SIZE`
+fn ack_packet(pn: u64) -> Vec<u8> {
+    let mut buf = vec![0; ACK_SIZE];
+    octets::OctetsMut::with_slice(&mut buf).put_varint(pn).unwrap();
+    buf
+}
+
+/// The sending end of the simulated transport
+#[derive(Debug)]
+struct SimTransport {
+    /// the offset of the first unsent byte and the unsent bytes, by stream
+    unsent: BTreeMap<u64, (u64, Vec<u8>)>,
+    /// the segments to send again, before any new data
+    lost: VecDeque<Segment>,
+    /// the segments on the way and when they were sent, by packet number
+    in_flight: BTreeMap<u64, (Segment, u64)>,
+    bytes_in_flight: usize,
+    next_pn: u64,
+    cwnd: usize,
+    ssthresh: usize,
+    srtt: Option<u64>,
+    /// the losses of the packets sent before this time belong to the
+    /// congestion event that already reduced the window
+    recovery_start: u64,
+}
+
+impl Default for SimTransport {
+    fn default() -> Self {
+        SimTransport {
+            unsent: BTreeMap::new(),
+            lost: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+            bytes_in_flight: 0,
+            next_pn: 0,
+            cwnd: INITIAL_CWND,
+            ssthresh: usize::MAX,
+            srtt: None,
+            recovery_start: 0,
+        }
+    }
+}
+
+impl SimTransport {
+    /// Append `data` to a stream
+    fn write(&mut self, stream_id: u64, data: &[u8]) {
+        self.unsent.entry(stream_id).or_default().1.extend_from_slice(data);
+    }
+
+    /// The number of bytes written into a stream and not sent yet
+    fn unsent_len(&self, stream_id: u64) -> usize {
+        self.unsent.get(&stream_id).map_or(0, |(_, data)| data.len())
+    }
+
+    fn rtt(&self) -> u64 {
+        self.srtt.unwrap_or(INITIAL_RTT)
+    }
+
+    /// When the oldest packet on the way is declared lost if it is still
+    /// not acknowledged
+    fn pto_deadline(&self) -> Option<u64> {
+        let pto = (2 * self.rtt()).max(MIN_PTO);
+        self.in_flight.values().map(|(_, sent)| sent + pto).min()
+    }
+
+    fn stats(&self) -> NetworkStats {
+        let rtt = self.rtt();
+        NetworkStats {
+            rtt,
+            bandwidth: self.cwnd as u64 * 1_000_000 / rtt.max(1),
+            cwnd: self.cwnd,
+        }
+    }
+
+    /// The next piece of new data, the lowest stream id first so that the
+    /// control stream goes before the framed stream
+    fn next_segment(&mut self) -> Option<Segment> {
+        let (stream_id, (offset, data)) = self.unsent.iter_mut().find(|(_, (_, data))| !data.is_empty())?;
+        let len = data.len().min(MSS);
+        let segment = Segment {
+            stream_id: *stream_id,
+            offset: *offset,
+            data: data.drain(..len).collect(),
+        };
+        *offset += len as u64;
+        Some(segment)
+    }
+
+    /// Send as much as the congestion window allows into `link`
+    fn send(&mut self, now: u64, link: &mut Link) {
+        while self.bytes_in_flight + MSS <= self.cwnd {
+            let segment = match self.lost.pop_front().or_else(|| self.next_segment()) {
+                Some(segment) => segment,
+                None => break,
+            };
+            let pn = self.next_pn;
+            self.next_pn += 1;
+            link.send(now, segment.to_packet(pn));
+            self.bytes_in_flight += segment.data.len();
+            self.in_flight.insert(pn, (segment, now));
+        }
+    }
+
+    fn on_ack(&mut self, pn: u64, now: u64) {
+        let (segment, sent) = match self.in_flight.remove(&pn) {
+            Some(packet) => packet,
+            // already declared lost
+            None => return,
+        };
+        self.bytes_in_flight -= segment.data.len();
+        let sample = now - sent;
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (7 * srtt + sample) / 8,
+            None => sample,
+        });
+        if sent >= self.recovery_start {
+            if self.cwnd < self.ssthresh {
+                self.cwnd += segment.data.len();
+            } else {
+                self.cwnd += MSS * segment.data.len() / self.cwnd;
+            }
+        }
+        if pn >= PACKET_THRESHOLD {
+            let lost: Vec<u64> = self.in_flight.range(..=pn - PACKET_THRESHOLD).map(|(pn, _)| *pn).collect();
+            for pn in lost {
+                self.on_lost(pn, now);
+            }
+        }
+    }
+
+    fn on_lost(&mut self, pn: u64, now: u64) {
+        let (segment, sent) = self.in_flight.remove(&pn).unwrap();
+        self.bytes_in_flight -= segment.data.len();
+        if sent >= self.recovery_start {
+            self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+            self.ssthresh = self.cwnd;
+            self.recovery_start = now;
+        }
+        self.lost.push_back(segment);
+    }
+
+    /// Nothing was acknowledged for a probe timeout: everything on the way
+    /// is lost and the window collapses
+    fn on_timeout(&mut self, now: u64) {
+        self.ssthresh = (self.cwnd / 2).max(MIN_CWND);
+        self.cwnd = MIN_CWND;
+        self.recovery_start = now;
+        for (_, (segment, _)) in std::mem::take(&mut self.in_flight) {
+            self.lost.push_back(segment);
+        }
+        self.bytes_in_flight = 0;
+    }
+}
+
+/// The receiving end of a stream of the simulated transport
+#[derive(Debug, Default)]
+struct SimStream {
+    /// the offset of the first byte not delivered yet
+    next_offset: u64,
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SimStream {
+    /// Return the bytes the segment makes contiguous
+    fn recv(&mut self, offset: u64, data: Vec<u8>) -> Vec<u8> {
+        if offset + data.len() as u64 > self.next_offset {
+            self.out_of_order.insert(offset, data);
+        }
+        let mut contiguous = Vec::new();
+        while let Some(entry) = self.out_of_order.first_entry() {
+            let offset = *entry.key();
+            if offset > self.next_offset {
+                break;
+            }
+            let data = entry.remove();
+            let end = offset + data.len() as u64;
+            if end > self.next_offset {
+                contiguous.extend_from_slice(&data[(self.next_offset - offset) as usize..]);
+                self.next_offset = end;
+            }
+        }
+        contiguous
+    }
+}
+
+/// The scheduler of a server sending a trace in the framed mode, over a
+/// simulated transport and path
+pub struct SchedulerSimulation {
+    /// the virtual time, in microseconds
+    now: u64,
+    block_generator: BlockGenerator,
+    /// when the generator is called next, None once every block is generated
+    next_generation: Option<u64>,
+    sender_queue: Box<dyn BlockScheduler>,
+    sender: FramedSender,
+    transport: SimTransport,
+    downlink: Link,
+    uplink: Link,
+    streams: BTreeMap<u64, SimStream>,
+    receiver: BlockReceiver,
+    /// the last time the client received something
+    last_recv: u64,
+}
+
+impl SchedulerSimulation {
+    /// Send the blocks of `cfgs` with `sender_queue` through the `downlink`
+    /// to the client, whose acknowledgements come back through the `uplink`
+    pub fn new(cfgs: Vec<dtp_config>, sender_queue: Box<dyn BlockScheduler>, uplink: LinkConfig, downlink: LinkConfig) -> Self {
+        let mut block_generator = BlockGenerator::default();
+        block_generator.load_cfgs(cfgs);
+        let next_generation = block_generator.first_time_gap().map(secs_to_usec);
+        SchedulerSimulation {
+            now: 0,
+            block_generator,
+            next_generation,
+            sender_queue,
+            sender: FramedSender::default(),
+            transport: SimTransport::default(),
+            downlink: Link::new(downlink),
+            uplink: Link::new(uplink),
+            streams: BTreeMap::new(),
+            receiver: BlockReceiver::default(),
+            last_recv: 0,
+        }
+    }
+
+    /// Run until the client accounted for every block, return the receiver
+    /// holding the report and the virtual duration of the run
+    pub fn run(mut self) -> Result<(BlockReceiver, u64)> {
+        self.announce_trace();
+        loop {
+            self.client_recv();
+            if self.receiver.is_finished() {
+                break;
+            }
+            self.server_recv();
+            if self.next_generation.is_some_and(|t| t <= self.now) {
+                let next_gap = self.block_generator.generate_once(self.sender_queue.as_mut(), self.now);
+                self.next_generation = next_gap.map(|gap| self.now + secs_to_usec(gap).max(1));
+            }
+            self.server_send();
+
+            let next_event = [
+                self.next_generation,
+                self.downlink.next_delivery(),
+                self.uplink.next_delivery(),
+                self.transport.pto_deadline(),
+            ];
+            self.now = match next_event.into_iter().flatten().min() {
+                Some(next) => next.max(self.now),
+                None => return Err(anyhow!("the simulation stalled at {} us", self.now)),
+            };
+            if self.now - self.last_recv > IDLE_TIMEOUT {
+                return Err(anyhow!("the client received nothing for {} s", IDLE_TIMEOUT / 1_000_000));
+            }
+        }
+        let links = (self.downlink.stats(), self.uplink.stats());
+        debug!("simulation over at {} us, downlink {:?}, uplink {:?}", self.now, links.0, links.1);
+        Ok((self.receiver, self.now))
+    }
+
+    /// Tell the client which blocks the trace is made of
+    fn announce_trace(&mut self) {
+        let infos: Vec<_> = self.block_generator.block_infos().collect();
+        self.transport.write(CONTROL_STREAM_ID, &StreamFrame::DtpConfig { cfg_len: infos.len() }.to_vec());
+        for info in infos.iter() {
+            // the block is not generated yet
+            self.transport.write(CONTROL_STREAM_ID, &StreamFrame::block_info(info, 0).to_vec());
+        }
+    }
+
+    /// The client takes the packets delivered by now and acknowledges them
+    fn client_recv(&mut self) {
+        while let Some(packet) = self.downlink.recv(self.now) {
+            self.last_recv = self.now;
+            let (pn, segment) = match Segment::from_packet(&packet) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            self.uplink.send(self.now, ack_packet(pn));
+            let stream = self.streams.entry(segment.stream_id).or_default();
+            let data = stream.recv(segment.offset, segment.data);
+            if !data.is_empty() {
+                self.receiver.on_stream_data(segment.stream_id, &data, false, self.now);
+            }
+        }
+    }
+
+    /// The server takes the acknowledgements delivered by now
+    fn server_recv(&mut self) {
+        while let Some(packet) = self.uplink.recv(self.now) {
+            if let Ok(pn) = octets::Octets::with_slice(&packet).get_varint() {
+                self.transport.on_ack(pn, self.now);
+            }
+        }
+        if self.transport.pto_deadline().is_some_and(|t| t <= self.now) {
+            self.transport.on_timeout(self.now);
+        }
+    }
+
+    /// Push the queued blocks into the transport, as `handle_writable` does
+    /// for the framed mode
+    fn server_send(&mut self) {
+        let now = self.now;
+        for block in self.sender_queue.on_expire(now) {
+            debug!("drop block {} whose deadline passed", block.info.id);
+            self.transport.write(CONTROL_STREAM_ID, &StreamFrame::BlockDrop { id: block.info.id }.to_vec());
+        }
+        self.sender_queue.on_network_update(&self.transport.stats());
+
+        // the framed stream takes up to a window of data ahead of the
+        // network, so that the scheduler picks blocks at the last moment
+        let transport = &mut self.transport;
+        let window = transport.cwnd;
+        let write = |buf: &[u8]| {
+            let room = window.saturating_sub(transport.unsent_len(FRAMED_STREAM_ID));
+            if room == 0 {
+                return Err(quiche::Error::Done);
+            }
+            let written = room.min(buf.len());
+            transport.write(FRAMED_STREAM_ID, &buf[..written]);
+            Ok(written)
+        };
+        // writing into the simulated stream never fails
+        self.sender.send_with(self.sender_queue.as_mut(), now, write).unwrap();
+        self.transport.send(now, &mut self.downlink);
+    }
+}
+
+fn secs_to_usec(secs: f32) -> u64 {
+    (secs as f64 * 1e6).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::EdfScheduler;
+
+    fn trace(blocks: usize) -> Vec<dtp_config> {
+        (0..blocks)
+            .map(|i| dtp_config {
+                deadline: 200,
+                priority: (i % 3) as i32 + 1,
+                block_size: 20_000 + (i as i32 % 7) * 10_000,
+                send_time_gap: 0.01,
+            })
+            .collect()
+    }
+
+    fn report(link: &LinkConfig) -> (String, u64) {
+        let downlink = LinkConfig { seed: link.seed + 1, ..link.clone() };
+        let simulation = SchedulerSimulation::new(trace(200), Box::<EdfScheduler>::default(), link.clone(), downlink);
+        let (receiver, duration) = simulation.run().unwrap();
+        let mut out = Vec::new();
+        receiver.write_report(&mut out).unwrap();
+        (String::from_utf8(out).unwrap(), duration)
+    }
+
+    #[test]
+    fn reassemble_streams() {
+        let mut stream = SimStream::default();
+        assert_eq!(stream.recv(3, vec![3, 4]), Vec::<u8>::new());
+        assert_eq!(stream.recv(0, vec![0, 1, 2]), vec![0, 1, 2, 3, 4]);
+        // a retransmission of delivered data
+        assert_eq!(stream.recv(0, vec![0, 1, 2]), Vec::<u8>::new());
+        assert_eq!(stream.recv(5, vec![5]), vec![5]);
+
+        let segment = Segment { stream_id: FRAMED_STREAM_ID, offset: 70_000, data: vec![1, 2, 3] };
+        assert_eq!(Segment::from_packet(&segment.to_packet(1 << 20)), Some((1 << 20, segment)));
+    }
+
+    #[test]
+    fn whole_trace_on_an_ideal_link() {
+        let (report, duration) = report(&LinkConfig::default());
+        assert_eq!(report.lines().count(), 201);
+        assert_eq!(report.lines().filter(|l| l.ends_with(",met")).count(), 200);
+        // the last block is generated after 2 s
+        assert!((2_000_000..2_100_000).contains(&duration), "{}", duration);
+    }
+
+    #[test]
+    fn same_seed_same_report() {
+        let link = LinkConfig {
+            bandwidth: 10e6,
+            delay: 20_000,
+            jitter: 5_000,
+            loss: 0.02,
+            reorder: 0.01,
+            queue_size: 60_000,
+            seed: 7,
+            ..Default::default()
+        };
+        let (first, _) = report(&link);
+        assert_eq!(report(&link).0, first);
+        // every block is accounted for despite the losses
+        assert_eq!(first.lines().count(), 201);
+        assert!(first.lines().any(|l| !l.ends_with(",met")), "the link is not a bottleneck");
+        assert_ne!(report(&LinkConfig { seed: 8, ..link }).0, first);
+    }
+}
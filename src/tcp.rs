@@ -0,0 +1,509 @@
+//! The TCP baseline of the QUIC modes, see `TransportMode::Tcp` and
+//! `TransportMode::TcpFramed`
+//!
+//! The client opens a control connection carrying the same `StreamFrame`s
+//! as `CONTROL_STREAM_ID`, then either one connection carrying all the
+//! blocks as frames like `FRAMED_STREAM_ID`, or `BLOCK_CONNECTIONS`
+//! connections carrying one block each: a block connection starts with the
+//! `BlockHeader` of its block and ends with the block, and the client opens
+//! a new connection whenever one ends. Every connection starts with a byte
+//! telling the server its kind.
+//!
+//! A failure of the control or the framed connection stops the server and
+//! the client with an `Error`, a failed block connection only loses its
+//! block, which the server reports as dropped.
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use calloop::{generic::Generic, timer::{TimeoutAction, Timer}};
+use calloop::{EventLoop, Interest, LoopHandle, LoopSignal, Mode, PostAction};
+use dtp_utils::{get_current_usec, get_dtp_config};
+use crate::block::{BlockHeader, CONTROL_STREAM_ID, FRAMED_STREAM_ID, block_id_to_stream_id};
+use crate::error::{would_block, Error, Result};
+use crate::frame::StreamFrame;
+use crate::receiver::BlockReceiver;
+use crate::scheduler::{BlockScheduler, SchedulerConfig, new_scheduler};
+use crate::sender::{BlockGenerator, FramedSender, TcpSender};
+
+const CONTROL_CONNECTION: u8 = 0x0;
+const FRAMED_CONNECTION: u8 = 0x1;
+const BLOCK_CONNECTION: u8 = 0x2;
+
+/// The number of block connections the client keeps open
+pub const BLOCK_CONNECTIONS: usize = 16;
+
+#[derive(Default)]
+struct TcpServerData<'a> {
+    sender_queue: Box<dyn BlockScheduler>,
+    block_generator: BlockGenerator,
+    // all the blocks on one connection
+    framed: bool,
+
+    control: Option<TcpStream>,
+    // control messages waiting for room in the control connection
+    control_buf: Vec<u8>,
+    framed_stream: Option<TcpStream>,
+    framed_sender: FramedSender,
+    sender: TcpSender,
+    // the failure that stopped the server
+    error: Option<Error>,
+
+    handle: Option<LoopHandle<'a, TcpServerData<'a>>>,
+    signal: Option<LoopSignal>,
+}
+
+pub fn init_tcp_server(
+    addr: SocketAddr,
+    cfg_path: &str,
+    scheduler: &str,
+    scheduler_config: &SchedulerConfig,
+    framed: bool
+) -> Result<()> {
+    let mut data = TcpServerData::default();
+    let cfgs = get_dtp_config(cfg_path)
+        .map_err(|e| Error::Config(format!("invalid config {}: {}", cfg_path, e)))?;
+    if cfgs.is_empty() {
+        return Err(Error::Config(format!("No configs in the file {}", cfg_path)));
+    }
+    data.block_generator.load_cfgs(cfgs);
+    data.sender_queue = new_scheduler(scheduler, scheduler_config).map_err(|e| Error::Config(e.to_string()))?;
+    data.framed = framed;
+
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    info!("tcp server listening on {}", listener.local_addr()?);
+
+    let mut event_loop: EventLoop<TcpServerData> = EventLoop::try_new()?;
+    let handle = event_loop.handle();
+    handle.insert_source(
+        Generic::new(listener, Interest::READ, Mode::Level),
+        server_accept_cb
+    )?;
+    data.handle = Some(handle);
+    data.signal = Some(event_loop.get_signal());
+
+    event_loop.run(Duration::from_secs(10), &mut data, |_data| {})?;
+    match data.error.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// The control or the framed connection failed, stop the server
+fn server_fail(data: &mut TcpServerData, e: Error) {
+    error!("the tcp server failed: {}", e);
+    data.error.get_or_insert(e);
+    data.signal.as_ref().unwrap().stop();
+}
+
+fn server_accept_cb(
+    _readiness: calloop::Readiness,
+    listener: &mut TcpListener,
+    data: &mut TcpServerData
+) -> std::io::Result<PostAction> {
+    loop {
+        let (stream, from) = match listener.accept() {
+            Ok(v) => v,
+            Err(e) if would_block(&e) => break,
+            Err(e) => return Err(e),
+        };
+        debug!("tcp server accepted a connection from {}", from);
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        // the event source gets a handle of the connection, the server
+        // keeps the other one to write into it once it knows its kind
+        let mut writer = Some(stream.try_clone()?);
+        let mut kind = None;
+        let handle = data.handle.as_ref().unwrap();
+        handle.insert_source(
+            // writable edges tell that a blocked connection can take more data
+            Generic::new(stream, Interest::BOTH, Mode::Edge),
+            move |readiness, socket, data: &mut TcpServerData| {
+                if readiness.readable {
+                    let mut buf = [0; 1024];
+                    loop {
+                        match socket.read(&mut buf) {
+                            Ok(0) => {
+                                server_on_close(kind, data);
+                                return Ok(PostAction::Remove);
+                            },
+                            Ok(_) => {
+                                // the first byte tells the kind of the connection
+                                if kind.is_none() {
+                                    kind = Some(buf[0]);
+                                    if let Err(e) = server_on_open(buf[0], writer.take().unwrap(), data) {
+                                        server_fail(data, e);
+                                    }
+                                }
+                            },
+                            Err(e) if would_block(&e) => break,
+                            Err(e) => {
+                                match kind {
+                                    Some(CONTROL_CONNECTION) | Some(FRAMED_CONNECTION) => server_fail(data, e.into()),
+                                    _ => warn!("tcp server read failed: {:?}", e),
+                                }
+                                server_on_close(kind, data);
+                                return Ok(PostAction::Remove);
+                            },
+                        }
+                    }
+                }
+                if let Err(e) = server_send_trace(data) {
+                    server_fail(data, e);
+                }
+                Ok(PostAction::Continue)
+            }
+        ).map_err(|e| std::io::Error::from(e.error))?;
+    }
+    Ok(PostAction::Continue)
+}
+
+fn server_on_open(kind: u8, stream: TcpStream, data: &mut TcpServerData) -> Result<()> {
+    match kind {
+        CONTROL_CONNECTION if data.control.is_none() => {
+            info!("tcp client connected from {:?}", stream.peer_addr());
+            data.control = Some(stream);
+            announce_trace(data);
+            start_block_generator(data)?;
+        },
+        FRAMED_CONNECTION if data.framed && data.framed_stream.is_none() => {
+            data.framed_stream = Some(stream);
+        },
+        BLOCK_CONNECTION if !data.framed => data.sender.add_connection(stream),
+        _ => warn!("ignore an unexpected tcp connection of kind {}", kind),
+    }
+    Ok(())
+}
+
+fn server_on_close(kind: Option<u8>, data: &mut TcpServerData) {
+    // the client is done with the trace
+    if kind == Some(CONTROL_CONNECTION) {
+        info!("tcp client closed the control connection");
+        data.signal.as_ref().unwrap().stop();
+    }
+}
+
+/// Tell the client which blocks the trace is made of, like the QUIC server.
+fn announce_trace(data: &mut TcpServerData) {
+    let infos: Vec<_> = data.block_generator.block_infos().collect();
+    data.control_buf.extend_from_slice(&StreamFrame::DtpConfig { cfg_len: infos.len() }.to_vec());
+    for info in infos.iter() {
+        // the block is not generated yet
+        data.control_buf.extend_from_slice(&StreamFrame::block_info(info, 0).to_vec());
+    }
+}
+
+/// Insert the timer that feeds the sender queue according to the trace.
+fn start_block_generator(data: &mut TcpServerData) -> Result<()> {
+    let first_gap = match data.block_generator.first_time_gap() {
+        Some(gap) => gap,
+        None => return Ok(()),
+    };
+    let source = Timer::from_duration(Duration::from_secs_f32(first_gap));
+    data.handle.as_ref().unwrap().insert_source(
+        source,
+        |_event: Instant, _metadata: &mut (), data: &mut TcpServerData| {
+            let next_gap = data.block_generator.generate_once(data.sender_queue.as_mut(), get_current_usec());
+            if let Err(e) = server_send_trace(data) {
+                server_fail(data, e);
+            }
+            match next_gap {
+                Some(gap) => TimeoutAction::ToDuration(Duration::from_secs_f32(gap)),
+                None => {
+                    info!("all blocks are generated");
+                    TimeoutAction::Drop
+                }
+            }
+        }
+    )?;
+    Ok(())
+}
+
+/// Push the pending control messages and blocks into the connections.
+fn server_send_trace(data: &mut TcpServerData) -> Result<()> {
+    let now = get_current_usec();
+    let sender_queue = data.sender_queue.as_mut();
+
+    // tell the client about the blocks the scheduler gave up on
+    for block in sender_queue.on_expire(now) {
+        info!(
+            "tcp drop block {} after sending {}/{} bytes",
+            block.info.id,
+            block.sent_bytes(),
+            block.info.size
+        );
+        data.sender.on_block_dropped(block.info.id);
+        data.control_buf.extend_from_slice(&StreamFrame::BlockDrop { id: block.info.id }.to_vec());
+    }
+    flush_control(data)?;
+
+    let remain = if data.framed {
+        match data.framed_stream.as_mut() {
+            Some(stream) => data.framed_sender.send_to_tcp(data.sender_queue.as_mut(), stream, now)?,
+            None => data.sender_queue.len(),
+        }
+    } else {
+        data.sender.send(data.sender_queue.as_mut(), now)
+    };
+    debug!("tcp {} blocks wait to be sent", remain);
+
+    // the client does not get the rest of these blocks
+    for id in data.sender.take_broken() {
+        info!("tcp drop block {}, its connection failed", id);
+        data.control_buf.extend_from_slice(&StreamFrame::BlockDrop { id }.to_vec());
+    }
+    flush_control(data)
+}
+
+/// Write the pending control messages into the control connection.
+fn flush_control(data: &mut TcpServerData) -> Result<()> {
+    let control = match data.control.as_mut() {
+        Some(control) => control,
+        None => return Ok(()),
+    };
+    if !data.control_buf.is_empty() {
+        match control.write(&data.control_buf) {
+            Ok(written) => {
+                data.control_buf.drain(..written);
+            },
+            Err(e) if would_block(&e) => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct TcpClientData<'a> {
+    receiver: BlockReceiver,
+    report_path: Option<String>,
+    peer_addr: Option<SocketAddr>,
+    finished: bool,
+    // the failure that stopped the client
+    error: Option<Error>,
+
+    handle: Option<LoopHandle<'a, TcpClientData<'a>>>,
+    signal: Option<LoopSignal>,
+}
+
+pub fn init_tcp_client(peer_addr: SocketAddr, framed: bool, report_path: Option<String>) -> Result<()> {
+    let mut data = TcpClientData {
+        report_path,
+        peer_addr: Some(peer_addr),
+        ..Default::default()
+    };
+
+    let mut event_loop: EventLoop<TcpClientData> = EventLoop::try_new()?;
+    let handle = event_loop.handle();
+    // give the server some time to start, like the QUIC client
+    handle.insert_source(
+        Timer::from_duration(Duration::from_secs_f32(2.0)),
+        move |_event: Instant, _metadata: &mut (), data: &mut TcpClientData| {
+            let mut kinds = vec![CONTROL_CONNECTION];
+            if framed {
+                kinds.push(FRAMED_CONNECTION);
+            } else {
+                kinds.extend([BLOCK_CONNECTION; BLOCK_CONNECTIONS]);
+            }
+            for kind in kinds {
+                if let Err(e) = client_connect(data, kind) {
+                    client_fail(data, e);
+                    break;
+                }
+            }
+            TimeoutAction::Drop
+        }
+    )?;
+    data.handle = Some(handle);
+    data.signal = Some(event_loop.get_signal());
+
+    event_loop.run(Duration::from_secs(10), &mut data, |_data| {})?;
+    match data.error.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// The beginning of a block connection, until its header tells which
+/// block it carries
+#[derive(Default)]
+struct BlockConnection {
+    header_buf: Vec<u8>,
+    stream_id: Option<u64>,
+}
+
+impl BlockConnection {
+    fn recv(&mut self, buf: &[u8], fin: bool, now: u64, receiver: &mut BlockReceiver) {
+        if let Some(stream_id) = self.stream_id {
+            receiver.on_stream_data(stream_id, buf, fin, now);
+            return;
+        }
+        self.header_buf.extend_from_slice(buf);
+        // a connection closed before its header carries no block
+        if let Some(header) = BlockHeader::from_bytes(&self.header_buf) {
+            let stream_id = block_id_to_stream_id(header.info.id);
+            self.stream_id = Some(stream_id);
+            receiver.on_stream_data(stream_id, &self.header_buf, fin, now);
+        }
+    }
+}
+
+/// Open a connection of `kind` to the server and read from it.
+fn client_connect(data: &mut TcpClientData, kind: u8) -> Result<()> {
+    let peer_addr = data.peer_addr.unwrap();
+    let mut stream = TcpStream::connect(peer_addr)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to connect to {}: {}", peer_addr, e)))?;
+    stream.set_nodelay(true)?;
+    stream.write_all(&[kind])?;
+    stream.set_nonblocking(true)?;
+
+    let mut block = BlockConnection::default();
+    data.handle.as_ref().unwrap().insert_source(
+        Generic::new(stream, Interest::READ, Mode::Level),
+        move |_readiness, socket, data: &mut TcpClientData| {
+            let mut buf = [0; 65535];
+            let mut closed = false;
+            loop {
+                let read = match socket.read(&mut buf) {
+                    Ok(v) => v,
+                    Err(e) if would_block(&e) => break,
+                    // the block of the connection stays incomplete
+                    Err(e) if kind == BLOCK_CONNECTION => {
+                        warn!("tcp client read failed: {:?}", e);
+                        0
+                    },
+                    Err(e) => {
+                        client_fail(data, e.into());
+                        return Ok(PostAction::Remove);
+                    },
+                };
+                // the server closes a block connection at the end of the block
+                let fin = read == 0;
+                let now = get_current_usec();
+                match kind {
+                    CONTROL_CONNECTION => {
+                        data.receiver.on_stream_data(CONTROL_STREAM_ID, &buf[..read], fin, now);
+                    },
+                    FRAMED_CONNECTION => {
+                        data.receiver.on_stream_data(FRAMED_STREAM_ID, &buf[..read], fin, now);
+                    },
+                    _ => block.recv(&buf[..read], fin, now, &mut data.receiver),
+                }
+                if fin {
+                    if kind == CONTROL_CONNECTION {
+                        info!("tcp server closed the control connection");
+                        client_finish(data);
+                    } else if kind == BLOCK_CONNECTION && !data.finished && !data.receiver.is_finished() {
+                        // keep the pool of block connections full
+                        if let Err(e) = client_connect(data, BLOCK_CONNECTION) {
+                            client_fail(data, e);
+                        }
+                    }
+                    closed = true;
+                    break;
+                }
+            }
+            // every announced block is accounted for
+            if data.receiver.is_finished() {
+                client_finish(data);
+            }
+            if closed || data.finished {
+                return Ok(PostAction::Remove);
+            }
+            Ok(PostAction::Continue)
+        }
+    )?;
+    Ok(())
+}
+
+/// A connection failed, stop the client
+fn client_fail(data: &mut TcpClientData, e: Error) {
+    error!("the tcp client failed: {}", e);
+    data.error.get_or_insert(e);
+    client_finish(data);
+}
+
+/// Write the block report and stop the client event loop.
+fn client_finish(data: &mut TcpClientData) {
+    if data.finished {
+        return;
+    }
+    data.finished = true;
+    data.receiver.finish(data.report_path.as_deref());
+    data.signal.as_ref().unwrap().stop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockInfo;
+    use crate::sender::{SenderBlock, SenderDeque};
+
+    #[test]
+    fn one_connection_per_block() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut sender = TcpSender::default();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            clients.push(TcpStream::connect(addr).unwrap());
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            sender.add_connection(stream);
+        }
+        let mut scheduler = SenderDeque::default();
+        for id in 0..3 {
+            let mut block = SenderBlock::new(BlockInfo { id, size: 20_000, priority: 1, deadline: 200 }, 0);
+            block.data = vec![id as u8; block.info.size];
+            scheduler.push_block(block);
+        }
+        // the third block waits for a connection
+        assert_eq!(sender.send(&mut scheduler, 0), 1);
+
+        let mut receiver = BlockReceiver::default();
+        for mut client in clients {
+            let mut connection = BlockConnection::default();
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).unwrap();
+            // the header is split across reads
+            connection.recv(&buf[..10], false, 1_000, &mut receiver);
+            connection.recv(&buf[10..], false, 1_000, &mut receiver);
+            connection.recv(&[], true, 2_000, &mut receiver);
+        }
+        for id in 0..2 {
+            let block = receiver.get_block(id).unwrap();
+            assert!(block.is_complete());
+            assert_eq!(block.completion_time(), Some(2_000));
+        }
+        assert!(receiver.get_block(2).is_none());
+    }
+
+    #[test]
+    fn broken_block_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        drop(client);
+        let mut sender = TcpSender::default();
+        sender.add_connection(stream);
+        let mut scheduler = SenderDeque::default();
+        let mut block = SenderBlock::new(BlockInfo { id: 0, size: 1_000_000, priority: 1, deadline: 200 }, 0);
+        block.data = vec![0; block.info.size];
+        scheduler.push_block(block);
+
+        // the first writes may be buffered before the reset of the client
+        let start = Instant::now();
+        let mut broken = Vec::new();
+        while broken.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            sender.send(&mut scheduler, 0);
+            broken = sender.take_broken();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(broken, vec![0]);
+        assert_eq!(scheduler.len(), 0);
+        // the block is not retried
+        assert_eq!(sender.send(&mut scheduler, 0), 0);
+    }
+}
@@ -0,0 +1,135 @@
+//! The address validation tokens of the stateless retry.
+//!
+//! A token is the original destination connection id chosen by the client
+//! and the time the token was minted, sealed with an AEAD key only the
+//! server knows. The address and port of the client are the associated
+//! data, so a token only opens for the client it was minted for:
+//!
+//! | nonce (12 bytes) | sealed: mint time (u64, seconds) | odcid | tag |
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use quiche::ConnectionId;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::error::Result;
+
+/// How long a retry token is accepted after it is minted
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(10);
+
+const TIME_LEN: usize = 8;
+
+pub(crate) struct TokenKey {
+    key: LessSafeKey,
+    rng: SystemRandom,
+    lifetime: Duration,
+}
+
+impl TokenKey {
+    /// A new random key, the tokens of previous keys are rejected
+    pub fn generate(lifetime: Duration) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let mut key = [0; 32];
+        rng.fill(&mut key)
+            .map_err(|_| std::io::Error::other("failed to generate the token key"))?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .map_err(|_| std::io::Error::other("invalid token key"))?;
+        Ok(TokenKey { key: LessSafeKey::new(key), rng, lifetime })
+    }
+
+    /// The token of a client at `src` whose Initial packet was sent to `odcid`
+    pub fn mint(&self, odcid: &[u8], src: &SocketAddr) -> Result<Vec<u8>> {
+        self.mint_at(odcid, src, unix_time())
+    }
+
+    /// The original destination connection id in `token`, None if the token
+    /// was minted for another address, has expired or was tampered with
+    pub fn validate(&self, src: &SocketAddr, token: &[u8]) -> Option<ConnectionId<'static>> {
+        self.validate_at(src, token, unix_time())
+    }
+
+    fn mint_at(&self, odcid: &[u8], src: &SocketAddr, now: u64) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce)
+            .map_err(|_| std::io::Error::other("failed to generate a token nonce"))?;
+
+        let mut sealed = Vec::with_capacity(TIME_LEN + odcid.len() + CHACHA20_POLY1305.tag_len());
+        sealed.extend_from_slice(&now.to_be_bytes());
+        sealed.extend_from_slice(odcid);
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(address_bytes(src)), &mut sealed)
+            .map_err(|_| std::io::Error::other("failed to seal a token"))?;
+
+        let mut token = nonce.to_vec();
+        token.extend_from_slice(&sealed);
+        Ok(token)
+    }
+
+    fn validate_at(&self, src: &SocketAddr, token: &[u8], now: u64) -> Option<ConnectionId<'static>> {
+        if token.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = token.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let opened = self.key.open_in_place(nonce, Aad::from(address_bytes(src)), &mut sealed).ok()?;
+        if opened.len() < TIME_LEN {
+            return None;
+        }
+
+        let (time, odcid) = opened.split_at(TIME_LEN);
+        let minted = u64::from_be_bytes(time.try_into().ok()?);
+        // a token from the future was not minted with this clock
+        if minted > now || now - minted > self.lifetime.as_secs() {
+            return None;
+        }
+        Some(ConnectionId::from_vec(odcid.to_vec()))
+    }
+}
+
+/// The address and port of the client, bound to its token
+fn address_bytes(src: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match src.ip() {
+        std::net::IpAddr::V4(a) => a.octets().to_vec(),
+        std::net::IpAddr::V6(a) => a.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&src.port().to_be_bytes());
+    bytes
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let key = TokenKey::generate(TOKEN_LIFETIME).unwrap();
+        let client = SocketAddr::from(([10, 0, 0, 1], 4433));
+        let odcid = [0xab; 16];
+        let now = 1_700_000_000;
+        let token = key.mint_at(&odcid, &client, now).unwrap();
+
+        assert_eq!(key.validate_at(&client, &token, now + 3).unwrap().as_ref(), &odcid);
+        // expired, or minted in the future
+        assert!(key.validate_at(&client, &token, now + TOKEN_LIFETIME.as_secs() + 1).is_none());
+        assert!(key.validate_at(&client, &token, now - 1).is_none());
+        // another port or address
+        assert!(key.validate_at(&SocketAddr::from(([10, 0, 0, 1], 4434)), &token, now).is_none());
+        assert!(key.validate_at(&SocketAddr::from(([10, 0, 0, 2], 4433)), &token, now).is_none());
+        // tampered with, truncated, or minted by another server
+        for i in 0..token.len() {
+            let mut tampered = token.clone();
+            tampered[i] ^= 1;
+            assert!(key.validate_at(&client, &tampered, now).is_none(), "byte {}", i);
+        }
+        assert!(key.validate_at(&client, &token[..NONCE_LEN + 4], now).is_none());
+        let other = TokenKey::generate(TOKEN_LIFETIME).unwrap();
+        assert!(other.validate_at(&client, &token, now).is_none());
+        // the plaintext token of the quiche example
+        assert!(key.validate_at(&client, b"quiche\x0a\x00\x00\x01abababab", now).is_none());
+    }
+}